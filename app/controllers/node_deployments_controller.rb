@@ -124,7 +124,8 @@ class NodeDeploymentsController < ApplicationController
         elsif node_deployment.github_action_runner?
           response = node_api.setup_runner(node_deployment, permitted_params[:github_token])
         end
-        if response && response.code == "200"
+        response = node_api.wait_for_job(response)
+        if node_api.job_succeeded?(response)
           logger.info(response.body)
           node_deployment.update deployment_status: :healthy
         else
@@ -155,7 +156,8 @@ class NodeDeploymentsController < ApplicationController
       elsif node_deployment.github_action_runner?
         return
       end
-      if response && response.code == "200"
+      response = node_api.wait_for_job(response)
+      if node_api.job_succeeded?(response)
         logger.info(response.body)
         node_deployment.update deployment_status: :healthy
       else
@@ -66,6 +66,51 @@ class NodeApiService
     get("runner/status?path=#{CGI.escape(node_deployment.path)}")
   end
 
+  # Keys
+  def rotate_key(name, grace_period_secs = nil)
+    body = {}
+    body[:grace_period_secs] = grace_period_secs if grace_period_secs
+
+    post("admin/keys/#{CGI.escape(name)}/rotate", body)
+  end
+
+  # Jobs, compose deployments, runner setups and container creates answer 202 with a job
+  def job(id)
+    get("jobs/#{id}")
+  end
+
+  # filters: kind, state
+  def jobs(filters = {})
+    get("jobs?#{URI.encode_www_form(filters)}")
+  end
+
+  def cancel_job(id)
+    post("jobs/#{id}/cancel", {})
+  end
+
+  # Poll the job of a 202 response until it finished, returns the final job response
+  def wait_for_job(response, interval: 2, timeout: 1800)
+    return response unless response && response.code == "202"
+
+    id = JSON.parse(response.body)["id"]
+    deadline = Time.now + timeout
+    loop do
+      sleep interval
+      response = job(id)
+      return response unless response && response.code == "200"
+      return response if JSON.parse(response.body)["state"] != "running" || Time.now > deadline
+    end
+  end
+
+  def job_succeeded?(response)
+    response && response.code == "200" && JSON.parse(response.body)["state"] == "succeeded"
+  end
+
+  # Audit log, filters: key, source_ip, method, route, target, outcome, since, until, offset, limit
+  def audit(filters = {})
+    get("audit?#{URI.encode_www_form(filters)}")
+  end
+
 
   private
 
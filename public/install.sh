@@ -56,7 +56,15 @@ echo 'node_agent ALL=(root) NOPASSWD: /home/node_agent/**/svc.sh install, /home/
 KEY=$(openssl rand -hex 25)
 # Use htpasswd (apache2-utils) to create a bcrypt hash without exposing the password on the command line
 HASH=$(printf "%s" "$KEY" | htpasswd -inB -C 10 node_agent_dummy 2>/dev/null | cut -d: -f2)
-printf "%s" "$HASH" > /home/node_agent/.key.hash
+# Independent HMAC secret for signed requests, a captured key alone cannot sign
+SIGNING_KEY=$(openssl rand -hex 32)
+cat >/home/node_agent/.key.hash <<KEYS
+[[keys]]
+name = "manager"
+hash = "$HASH"
+signing_key = "$SIGNING_KEY"
+scopes = ["admin"]
+KEYS
 chown node_agent:node_agent /home/node_agent/.key.hash
 chmod 600 /home/node_agent/.key.hash
 
@@ -68,6 +76,28 @@ unzip -o "$DEST_HOME/agent_bundle.zip" -d "$DEST_HOME"
 rm "$DEST_HOME/agent_bundle.zip"
 chown -R node_agent:node_agent "$DEST_HOME"
 
+# Write agent config (kept on reinstall so local changes survive)
+if [ ! -f /home/node_agent/agent.toml ]; then
+  cat >/home/node_agent/agent.toml <<'TOML'
+listen = "0.0.0.0:8080"
+key_file = "/home/node_agent/.key.hash"
+allowed_base_dirs = ["/home/node_agent"]
+
+[auth]
+# No proxy is trusted by default, so X-Forwarded-For is ignored and the lockout counts per peer
+# address (Traefik's, for requests through management-api). To lock out per client instead,
+# trust exactly the Traefik container's addresses, never a whole docker range, or any container
+# could spoof its client address:
+#   docker inspect -f '{{range .NetworkSettings.Networks}}{{.IPAddress}} {{end}}' <traefik container>
+# trusted_proxies = ["172.18.0.2/32"]
+TOML
+  chown node_agent:node_agent /home/node_agent/agent.toml
+  chmod 600 /home/node_agent/agent.toml
+else
+  # Earlier installs trusted every docker network, drop that default if it is still in place
+  sed -i 's|^trusted_proxies = \["172.16.0.0/12"\]$|# trusted_proxies = []|' /home/node_agent/agent.toml
+fi
+
 # Install systemd service to run the API host
 cat >/etc/systemd/system/node_agent.service <<'EOF'
 [Unit]
@@ -79,9 +109,11 @@ Type=simple
 User=node_agent
 Group=node_agent
 WorkingDirectory=/home/node_agent
-ExecStart=/home/node_agent/api_host/server_agent 0.0.0.0:8080
+ExecStart=/home/node_agent/api_host/server_agent --config /home/node_agent/agent.toml
 Restart=always
 RestartSec=5
+# Leave room for the agent to drain running operations (timeouts.shutdown_secs)
+TimeoutStopSec=90
 
 [Install]
 WantedBy=multi-user.target
@@ -140,7 +172,8 @@ http:
           - url: "http://$DOCKER_BRIDGE_IP:8080"
 YML
 
-# Compose override to mount the dynamic file into Traefik
+# Compose override to mount the dynamic file into Traefik and attach it to the shared network,
+# which deployments join as an external network (the agent's docker.traefik_network)
 cat >"$TRAEFIK_DIR/docker-compose.override.yml" <<'YML'
 services:
   traefik:
@@ -148,11 +181,18 @@ services:
       - ./dynamic.yml:/dynamic.yml:ro
     environment:
       - TRAEFIK_PROVIDERS_FILE_FILENAME=/dynamic.yml
+    networks:
+      - default
+      - traefik
+networks:
+  traefik:
+    external: true
 YML
 
 # Ensure files are owned by node_agent
 chown node_agent:node_agent "$TRAEFIK_DIR/.env" "$TRAEFIK_DIR/dynamic.yml" "$TRAEFIK_DIR/docker-compose.override.yml"
 if command -v docker >/dev/null 2>&1; then
+  docker network inspect traefik >/dev/null 2>&1 || docker network create traefik
   if docker compose version >/dev/null 2>&1; then
     docker compose -f docker-compose.yml -f docker-compose.prod.yml -f docker-compose.override.yml up -d
   elif command -v docker-compose >/dev/null 2>&1; then
@@ -175,5 +215,8 @@ echo
 echo "Generated key (paste to web frontend):"
 echo "$KEY"
 echo
+echo "Generated signing key (for signed requests):"
+echo "$SIGNING_KEY"
+echo
 echo
 echo "ALL OF THESE VALUES CAN NEVER BE SHOWN AGAIN!"
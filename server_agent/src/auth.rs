@@ -0,0 +1,688 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config;
+use crate::signature;
+use crate::util;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// health, list, inspect, logs and status routes
+    Read,
+    /// container and compose deployments, start/stop/rm
+    Deploy,
+    /// GitHub runner setup
+    Runner,
+    /// commands inside containers; not part of deploy, as it reaches into every container
+    Exec,
+    /// everything, including key management
+    Admin,
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Scope::Read => "read",
+            Scope::Deploy => "deploy",
+            Scope::Runner => "runner",
+            Scope::Exec => "exec",
+            Scope::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    pub name: String,
+    /// bcrypt hash of the key
+    pub hash: String,
+    pub scopes: Vec<Scope>,
+    /// If set, the key may only touch deployments below one of these paths.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub path_prefixes: Vec<PathBuf>,
+    /// Hash of the key before the last rotation, accepted until `previous_expires_at` (unix seconds).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_expires_at: Option<u64>,
+    /// HMAC secret for signed requests, see `signature::generate_signing_key`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_signing_key: Option<String>,
+    /// Reject the plain `X-Api-Key` header for this key, only signed requests are accepted.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub require_signature: bool,
+}
+
+impl ApiKey {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    pub fn is_path_restricted(&self) -> bool {
+        !self.path_prefixes.is_empty()
+    }
+
+    pub fn allows_path(&self, path: &str) -> bool {
+        !self.is_path_restricted() || self.path_prefixes.iter().any(|prefix| Path::new(path).starts_with(prefix))
+    }
+
+    fn previous_valid(&self) -> bool {
+        self.previous_expires_at.is_some_and(|expires_at| unix_now() < expires_at)
+    }
+
+    fn previous_hash_valid(&self) -> Option<&str> {
+        self.previous_hash.as_deref().filter(|_| self.previous_valid())
+    }
+
+    fn previous_signing_key_valid(&self) -> Option<&str> {
+        self.previous_signing_key.as_deref().filter(|_| self.previous_valid())
+    }
+
+    /// The hash (current or previous) the token matches.
+    fn matched_hash(&self, token: &str) -> Option<&str> {
+        let hashes = std::iter::once(self.hash.as_str()).chain(self.previous_hash_valid());
+        for hash in hashes {
+            match bcrypt::verify(token, hash) {
+                Ok(true) => return Some(hash),
+                Ok(false) => {}
+                Err(e) => log::warn!("Hash verification error for key {}: {}", self.name, e),
+            }
+        }
+        None
+    }
+
+    fn signature_matches(&self, canonical: &str, signature_hex: &str) -> bool {
+        self.signing_key
+            .as_deref()
+            .into_iter()
+            .chain(self.previous_signing_key_valid())
+            .any(|signing_key| signature::verify(signing_key, canonical, signature_hex))
+    }
+}
+
+/// The cost of a `$2b$10$...` hash.
+fn bcrypt_cost(hash: &str) -> Option<u32> {
+    hash.split('$').nth(2)?.parse().ok()
+}
+
+/// Longest time an old key may stay valid after a rotation, 30 days.
+pub const MAX_ROTATION_GRACE_SECS: u64 = 30 * 24 * 3600;
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Result of a key rotation; the plain key is only ever returned here.
+#[derive(Serialize, JsonSchema)]
+pub struct RotatedKey {
+    pub name: String,
+    pub key: String,
+    /// New HMAC secret for signed requests, the previous one stays valid as long as the old key
+    pub signing_key: String,
+    pub previous_key_valid_until: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyFile {
+    keys: Vec<ApiKey>,
+}
+
+/// The keys from the key file. Reloads itself when the file changes and writes rotations back to it.
+pub struct KeyStore {
+    path: PathBuf,
+    keys: RwLock<Vec<ApiKey>>,
+    modified: Mutex<Option<SystemTime>>,
+    /// SHA-256 of recently verified tokens, the name of their key and the hash they matched.
+    verified: Mutex<HashMap<[u8; 32], Verified>>,
+}
+
+#[derive(Clone)]
+struct Verified {
+    name: String,
+    /// The current or the previous hash of the key, whichever the token matched
+    hash: String,
+    at: Instant,
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Read and validate the key file. Either a TOML list of `[[keys]]` or, as written by older
+/// installs, a single bcrypt hash which becomes an admin key named `default`.
+fn read_keys(path: &Path) -> Result<Vec<ApiKey>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("cannot read key file {}: {}", path.display(), e))?;
+    parse_keys(&content, path)
+}
+
+/// The keys in `content`, `path` only names the file in errors.
+fn parse_keys(content: &str, path: &Path) -> Result<Vec<ApiKey>, String> {
+    let content = content.trim();
+
+    let keys = if content.starts_with("$2") && !content.contains('\n') {
+        vec![ApiKey {
+            name: "default".to_string(),
+            hash: content.to_string(),
+            scopes: vec![Scope::Admin],
+            path_prefixes: Vec::new(),
+            previous_hash: None,
+            previous_expires_at: None,
+            signing_key: None,
+            previous_signing_key: None,
+            require_signature: false,
+        }]
+    } else {
+        let file: KeyFile =
+            toml::from_str(content).map_err(|e| format!("invalid key file {}: {}", path.display(), e))?;
+        file.keys
+    };
+
+    if keys.is_empty() {
+        return Err(format!("key file {} contains no keys", path.display()));
+    }
+    let mut names = HashSet::new();
+    for key in &keys {
+        if !names.insert(key.name.as_str()) {
+            return Err(format!("key file {}: duplicate key name {}", path.display(), key.name));
+        }
+        if !key.hash.starts_with("$2") || key.previous_hash.as_ref().is_some_and(|h| !h.starts_with("$2")) {
+            return Err(format!("key file {}: key {} has no bcrypt hash", path.display(), key.name));
+        }
+        let signing_keys = key.signing_key.iter().chain(&key.previous_signing_key);
+        if signing_keys.into_iter().any(|k| k.len() != 64 || !k.bytes().all(|b| b.is_ascii_hexdigit())) {
+            return Err(format!(
+                "key file {}: key {} has a signing key that is not 64 hex characters",
+                path.display(),
+                key.name
+            ));
+        }
+        if key.require_signature && key.signing_key.is_none() {
+            return Err(format!(
+                "key file {}: key {} requires signatures but has no signing_key",
+                path.display(),
+                key.name
+            ));
+        }
+        if key.scopes.is_empty() {
+            return Err(format!("key file {}: key {} has no scopes", path.display(), key.name));
+        }
+        if let Some(prefix) = key.path_prefixes.iter().find(|p| !p.is_absolute()) {
+            return Err(format!(
+                "key file {}: key {} has relative path prefix {}",
+                path.display(),
+                key.name,
+                prefix.display()
+            ));
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Write the key file back, see `util::write_private`. A TOML key file is edited in place so
+/// comments and layout survive, a legacy bare hash is replaced by a full key file.
+fn write_keys(path: &Path, keys: &[ApiKey]) -> Result<(), String> {
+    let document = fs::read_to_string(path).ok().and_then(|content| content.parse::<toml_edit::DocumentMut>().ok());
+    let content = match document {
+        Some(mut document) if document.get("keys").is_some_and(|k| k.is_array_of_tables()) => {
+            update_document(&mut document, keys)?;
+            document.to_string()
+        }
+        _ => toml::to_string(&KeyFile { keys: keys.to_vec() }).map_err(|e| format!("cannot serialize keys: {}", e))?,
+    };
+    util::write_private(path, content.as_bytes())
+}
+
+/// Set the fields the agent changes (hashes and signing keys) on the `[[keys]]` tables of `document`.
+fn update_document(document: &mut toml_edit::DocumentMut, keys: &[ApiKey]) -> Result<(), String> {
+    let tables = document["keys"].as_array_of_tables_mut().expect("checked by write_keys");
+    for key in keys {
+        let index = tables.iter().position(|t| t.get("name").and_then(|n| n.as_str()) == Some(key.name.as_str()));
+        let table = match index.and_then(|index| tables.get_mut(index)) {
+            Some(table) => table,
+            None => {
+                let serialized = toml::to_string(key).map_err(|e| format!("cannot serialize keys: {}", e))?;
+                let table = serialized.parse::<toml_edit::DocumentMut>().map_err(|e| format!("cannot serialize keys: {}", e))?;
+                tables.push(table.as_table().clone());
+                continue;
+            }
+        };
+        set_field(table, "hash", Some(key.hash.as_str().into()));
+        set_field(table, "previous_hash", key.previous_hash.as_deref().map(Into::into));
+        set_field(table, "previous_expires_at", key.previous_expires_at.map(|at| (at as i64).into()));
+        set_field(table, "signing_key", key.signing_key.as_deref().map(Into::into));
+        set_field(table, "previous_signing_key", key.previous_signing_key.as_deref().map(Into::into));
+    }
+    Ok(())
+}
+
+/// Set or remove `field`, an unchanged value keeps its formatting and a changed one its comments.
+fn set_field(table: &mut toml_edit::Table, field: &str, value: Option<toml_edit::Value>) {
+    let Some(mut value) = value else {
+        table.remove(field);
+        return;
+    };
+    match table.get_mut(field).and_then(|item| item.as_value_mut()) {
+        Some(current) if current.as_str() == value.as_str() && current.as_integer() == value.as_integer() => {}
+        Some(current) => {
+            *value.decor_mut() = current.decor().clone();
+            *current = value;
+        }
+        None => {
+            table.insert(field, toml_edit::Item::Value(value));
+        }
+    }
+}
+
+/// 25 random bytes as hex, the same shape install.sh generates with `openssl rand -hex 25`.
+fn generate_key() -> Result<String, String> {
+    let mut bytes = [0u8; 25];
+    getrandom::getrandom(&mut bytes).map_err(|e| format!("cannot generate key: {}", e))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+impl KeyStore {
+    pub fn load(path: &Path) -> Result<KeyStore, String> {
+        let modified = modified_time(path);
+        let keys = read_keys(path)?;
+        Ok(KeyStore {
+            path: path.to_path_buf(),
+            keys: RwLock::new(keys),
+            modified: Mutex::new(modified),
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The key for a token verified within the last `auth.verified_cache_secs`. The key has to
+    /// still accept the hash the token matched, so a previous key stops at the end of its grace
+    /// period and edits to the key file apply right away.
+    fn cached(&self, token: &str) -> Option<ApiKey> {
+        let ttl = Duration::from_secs(config::get().auth.verified_cache_secs);
+        let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        let verified = {
+            let mut verified = self.verified.lock().unwrap();
+            verified.retain(|_, entry| entry.at.elapsed() < ttl);
+            verified.get(&digest)?.clone()
+        };
+        let keys = self.keys.read().unwrap();
+        let key = keys.iter().find(|key| key.name == verified.name)?;
+        let accepted = key.hash == verified.hash || key.previous_hash_valid() == Some(verified.hash.as_str());
+        if !accepted || key.require_signature {
+            self.verified.lock().unwrap().remove(&digest);
+            return None;
+        }
+        Some(key.clone())
+    }
+
+    /// Find the key matching the presented token. Blocks on bcrypt, see `verify_token`.
+    pub fn verify(&self, token: &str) -> Option<ApiKey> {
+        let (key, matched) = self.keys.read().unwrap().iter().find_map(|key| {
+            if key.require_signature {
+                return None;
+            }
+            key.matched_hash(token).map(|hash| (key.clone(), hash.to_string()))
+        })?;
+        let current_hash = matched == key.hash;
+
+        // Move keys to the configured cost, the plain token is only known right now
+        let cost = config::get().auth.bcrypt_cost;
+        if current_hash && bcrypt_cost(&key.hash) != Some(cost) {
+            if let Err(e) = self.rehash(&key, token, cost) {
+                log::warn!("Cannot rehash key {} with bcrypt cost {}: {}", key.name, cost, e);
+            }
+        }
+
+        if config::get().auth.verified_cache_secs > 0 {
+            let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+            let verified = Verified {
+                name: key.name.clone(),
+                hash: matched,
+                at: Instant::now(),
+            };
+            self.verified.lock().unwrap().insert(digest, verified);
+        }
+        Some(key)
+    }
+
+    fn rehash(&self, key: &ApiKey, token: &str, cost: u32) -> Result<(), String> {
+        let new_hash = bcrypt::hash(token, cost).map_err(|e| format!("cannot hash key: {}", e))?;
+        let updated = self.update_key(&key.name, |stored| {
+            // Skip if the key was rotated or edited in the meantime
+            if stored.hash == key.hash {
+                stored.hash = new_hash;
+            }
+        })?;
+        if updated.is_some() {
+            log::info!("Rehashed key {} with bcrypt cost {}", key.name, cost);
+        }
+        Ok(())
+    }
+
+    /// Change the key `name` and write the key file. `None` if there is no such key.
+    fn update_key<T>(&self, name: &str, change: impl FnOnce(&mut ApiKey) -> T) -> Result<Option<T>, String> {
+        // Hold the modified lock so a concurrent reload does not race the write
+        let mut last_modified = self.modified.lock().unwrap();
+        let mut keys = self.keys.write().unwrap();
+        let mut updated = keys.clone();
+        let key = match updated.iter_mut().find(|k| k.name == name) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let result = change(key);
+
+        write_keys(&self.path, &updated)?;
+        *keys = updated;
+        *last_modified = modified_time(&self.path);
+        self.verified.lock().unwrap().clear();
+        Ok(Some(result))
+    }
+
+    /// Check a request signature made with the signing key of the key `name`.
+    pub fn verify_signature(&self, name: &str, canonical: &str, signature_hex: &str) -> Option<ApiKey> {
+        self.keys
+            .read()
+            .unwrap()
+            .iter()
+            .find(|key| key.name == name && key.signature_matches(canonical, signature_hex))
+            .cloned()
+    }
+
+    /// Reload the key file if it changed on disk. Invalid files are logged and the old keys kept.
+    fn reload_if_changed(&self) {
+        let modified = modified_time(&self.path);
+        let mut last_modified = self.modified.lock().unwrap();
+        if modified == *last_modified {
+            return;
+        }
+        *last_modified = modified;
+        match read_keys(&self.path) {
+            Ok(keys) => {
+                *self.keys.write().unwrap() = keys;
+                self.verified.lock().unwrap().clear();
+                log::info!("Reloaded key file {}", self.path.display());
+            }
+            Err(e) => log::warn!("Keeping previous keys, reload failed: {}", e),
+        }
+    }
+
+    /// Replace the key `name` with a freshly generated one. The old key stays valid for `grace`.
+    pub fn rotate(&self, name: &str, grace: Duration, bcrypt_cost: u32) -> Result<Option<RotatedKey>, String> {
+        let new_key = generate_key()?;
+        let new_hash = bcrypt::hash(&new_key, bcrypt_cost).map_err(|e| format!("cannot hash key: {}", e))?;
+        let expires_at = unix_now().saturating_add(grace.as_secs());
+        let signing_key = signature::generate_signing_key()?;
+
+        let updated = self.update_key(name, |key| {
+            key.previous_hash = Some(std::mem::replace(&mut key.hash, new_hash));
+            key.previous_expires_at = Some(expires_at);
+            key.previous_signing_key = key.signing_key.replace(signing_key.clone());
+        })?;
+        if updated.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(RotatedKey {
+            name: name.to_string(),
+            key: new_key,
+            signing_key,
+            previous_key_valid_until: expires_at,
+        }))
+    }
+}
+
+/// Verify `token` off the async runtime, bcrypt is slow on purpose.
+pub async fn verify_token(keys: Arc<KeyStore>, token: String) -> Option<ApiKey> {
+    if let Some(key) = keys.cached(&token) {
+        return Some(key);
+    }
+    match tokio::task::spawn_blocking(move || keys.verify(&token)).await {
+        Ok(key) => key,
+        Err(e) => {
+            log::error!("Key verification task failed: {}", e);
+            None
+        }
+    }
+}
+
+/// Poll the key file so manual edits are picked up without a restart.
+pub fn watch(keys: Arc<KeyStore>, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            keys.reload_if_changed();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNING_KEY: &str = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+
+    fn parse(content: &str) -> Result<Vec<ApiKey>, String> {
+        parse_keys(content, Path::new("keys.toml"))
+    }
+
+    fn hash(token: &str) -> String {
+        bcrypt::hash(token, 4).unwrap()
+    }
+
+    /// A key file in the temp dir, removed again when the test is done.
+    struct TempKeyFile(PathBuf);
+
+    impl TempKeyFile {
+        fn new(name: &str, content: &str) -> TempKeyFile {
+            let path = std::env::temp_dir().join(format!("server_agent-{}-{}.toml", std::process::id(), name));
+            fs::write(&path, content).unwrap();
+            TempKeyFile(path)
+        }
+    }
+
+    impl Drop for TempKeyFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn legacy_bare_hash_is_an_admin_key() {
+        let hash = hash("secret");
+        let keys = parse(&format!("{}\n", hash)).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].name, "default");
+        assert_eq!(keys[0].hash, hash);
+        assert_eq!(keys[0].scopes, [Scope::Admin]);
+        assert!(!keys[0].is_path_restricted());
+        assert!(keys[0].matched_hash("secret").is_some());
+        assert!(keys[0].matched_hash("wrong").is_none());
+    }
+
+    #[test]
+    fn example_key_file_parses() {
+        let keys = parse(include_str!("../keys.example.toml"));
+        // The example only has placeholder hashes and signing keys
+        assert!(keys.is_err());
+        let example = include_str!("../keys.example.toml").replace("REPLACE_WITH_64_RANDOM_HEX_CHARACTERS", SIGNING_KEY);
+        let keys = parse(&example).unwrap();
+        assert!(keys.iter().any(|key| key.name == "manager" && key.has_scope(Scope::Exec)));
+    }
+
+    #[test]
+    fn named_keys_with_scopes_and_paths() {
+        let content = format!(
+            r#"
+            [[keys]]
+            name = "manager"
+            hash = "{hash}"
+            signing_key = "{SIGNING_KEY}"
+            scopes = ["admin"]
+
+            [[keys]]
+            name = "ci"
+            hash = "{hash}"
+            scopes = ["deploy", "read"]
+            path_prefixes = ["/home/node_agent/apps"]
+            "#,
+            hash = hash("secret")
+        );
+        let keys = parse(&content).unwrap();
+        assert_eq!(keys.len(), 2);
+
+        let manager = &keys[0];
+        assert!(manager.has_scope(Scope::Exec));
+        assert_eq!(manager.signing_key.as_deref(), Some(SIGNING_KEY));
+        assert!(manager.allows_path("/etc"));
+
+        let ci = &keys[1];
+        assert!(ci.has_scope(Scope::Deploy));
+        assert!(!ci.has_scope(Scope::Exec));
+        assert!(!ci.has_scope(Scope::Admin));
+        assert!(ci.allows_path("/home/node_agent/apps/web"));
+        assert!(!ci.allows_path("/home/node_agent/apps2"));
+        assert!(!ci.allows_path("/home/node_agent"));
+    }
+
+    #[test]
+    fn rejects_invalid_key_files() {
+        let hash = hash("secret");
+        let key = |extra: &str| format!("[[keys]]\nname = \"a\"\nhash = \"{}\"\nscopes = [\"read\"]\n{}\n", hash, extra);
+        for content in [
+            String::new(),
+            "keys = []".to_string(),
+            "not a hash".to_string(),
+            format!("{}{}", key(""), key("")),
+            key("previous_hash = \"plain\""),
+            key("signing_key = \"abc\""),
+            key("require_signature = true"),
+            key("path_prefixes = [\"relative\"]"),
+            key("scopes = [\"root\"]"),
+            key("comment = \"unknown field\""),
+            "[[keys]]\nname = \"a\"\nhash = \"plain\"\nscopes = [\"read\"]".to_string(),
+            format!("[[keys]]\nname = \"a\"\nhash = \"{}\"\nscopes = []", hash),
+        ] {
+            assert!(parse(&content).is_err(), "{} should be rejected", content);
+        }
+    }
+
+    #[test]
+    fn rotation_keeps_the_previous_key_for_the_grace_period() {
+        let file = TempKeyFile::new(
+            "rotate",
+            &format!("[[keys]]\nname = \"manager\"\nhash = \"{}\"\nsigning_key = \"{}\"\nscopes = [\"admin\"]\n", hash("old"), SIGNING_KEY),
+        );
+        let store = KeyStore::load(&file.0).unwrap();
+        assert!(store.rotate("missing", Duration::from_secs(60), 4).unwrap().is_none());
+
+        let rotated = store.rotate("manager", Duration::from_secs(60), 4).unwrap().unwrap();
+        assert_ne!(rotated.signing_key, SIGNING_KEY);
+        assert!((unix_now() + 59..=unix_now() + 60).contains(&rotated.previous_key_valid_until));
+
+        // Written back to the file in the same format
+        let keys = read_keys(&file.0).unwrap();
+        let key = &keys[0];
+        assert!(key.matched_hash(&rotated.key).is_some());
+        assert!(key.matched_hash("old").is_some());
+        assert_eq!(key.signing_key.as_deref(), Some(rotated.signing_key.as_str()));
+        assert_eq!(key.previous_signing_key.as_deref(), Some(SIGNING_KEY));
+
+        let canonical = signature::canonical_request("GET", "/health", "", b"", unix_now(), "nonce-0123456789");
+        let sign = |signing_key: &str| {
+            use hmac::{Hmac, Mac};
+            let mut mac = Hmac::<Sha256>::new_from_slice(&hex::decode(signing_key).unwrap()).unwrap();
+            mac.update(canonical.as_bytes());
+            hex::encode(mac.finalize().into_bytes())
+        };
+        assert!(store.verify_signature("manager", &canonical, &sign(&rotated.signing_key)).is_some());
+        assert!(store.verify_signature("manager", &canonical, &sign(SIGNING_KEY)).is_some());
+        assert!(store.verify_signature("other", &canonical, &sign(SIGNING_KEY)).is_none());
+    }
+
+    #[test]
+    fn rotation_keeps_comments_in_the_key_file() {
+        let old_hash = hash("old");
+        let content = format!(
+            "# Keys of this node\n\n[[keys]]\n# The deployment manager\nname = \"manager\"\nhash = \"{}\" # rotated by the manager\nscopes = [\"admin\"]\n\n[[keys]]\nname = \"dashboard\"\nhash = \"{}\"\nscopes = [ \"read\" ]\n",
+            old_hash, old_hash
+        );
+        let file = TempKeyFile::new("comments", &content);
+        let store = KeyStore::load(&file.0).unwrap();
+        let rotated = store.rotate("manager", Duration::from_secs(60), 4).unwrap().unwrap();
+
+        let written = fs::read_to_string(&file.0).unwrap();
+        assert!(written.starts_with("# Keys of this node\n\n[[keys]]\n# The deployment manager\nname = \"manager\"\n"));
+        assert!(written.contains("\" # rotated by the manager\n"));
+        // The other key is untouched
+        assert!(written.ends_with(&format!("[[keys]]\nname = \"dashboard\"\nhash = \"{}\"\nscopes = [ \"read\" ]\n", old_hash)));
+
+        let keys = read_keys(&file.0).unwrap();
+        assert!(keys[0].matched_hash(&rotated.key).is_some());
+        assert_eq!(keys[0].previous_hash.as_deref(), Some(old_hash.as_str()));
+        assert_eq!(keys[0].signing_key.as_deref(), Some(rotated.signing_key.as_str()));
+        assert_eq!(keys[1].hash, old_hash);
+    }
+
+    #[test]
+    fn rotation_rewrites_a_bare_hash_as_a_key_file() {
+        let file = TempKeyFile::new("bare", &hash("old"));
+        let store = KeyStore::load(&file.0).unwrap();
+        store.rotate("default", Duration::from_secs(60), 4).unwrap().unwrap();
+
+        let keys = read_keys(&file.0).unwrap();
+        assert_eq!(keys[0].name, "default");
+        assert!(keys[0].matched_hash("old").is_some());
+    }
+
+    #[test]
+    fn cache_hits_recheck_the_matched_hash() {
+        config::init_for_tests();
+        let file = TempKeyFile::new(
+            "cache",
+            &format!("[[keys]]\nname = \"manager\"\nhash = \"{}\"\nscopes = [\"admin\"]\n", hash("old")),
+        );
+        let store = KeyStore::load(&file.0).unwrap();
+        store.rotate("manager", Duration::from_secs(60), 4).unwrap().unwrap();
+        assert!(store.verify("old").is_some());
+        assert!(store.cached("old").is_some());
+        assert!(store.cached("other").is_none());
+
+        // The grace period of the previous key ends while its token is cached
+        store.keys.write().unwrap()[0].previous_expires_at = Some(unix_now() - 1);
+        assert!(store.cached("old").is_none());
+        assert!(store.verified.lock().unwrap().is_empty());
+        assert!(store.verify("old").is_none());
+
+        // So does a key that now only takes signed requests
+        store.keys.write().unwrap()[0].previous_expires_at = Some(unix_now() + 60);
+        assert!(store.verify("old").is_some());
+        store.keys.write().unwrap()[0].require_signature = true;
+        assert!(store.cached("old").is_none());
+    }
+
+    #[test]
+    fn expired_previous_key_is_rejected() {
+        let mut key = parse(&hash("new")).unwrap().remove(0);
+        key.previous_hash = Some(hash("old"));
+        key.previous_signing_key = Some(SIGNING_KEY.to_string());
+        key.previous_expires_at = Some(unix_now() - 1);
+        assert!(key.matched_hash("new").is_some());
+        assert!(key.matched_hash("old").is_none());
+        assert!(key.previous_signing_key_valid().is_none());
+
+        key.previous_expires_at = Some(unix_now().saturating_add(u64::MAX));
+        assert!(key.matched_hash("old").is_some());
+    }
+}
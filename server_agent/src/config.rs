@@ -0,0 +1,697 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+
+use crate::auth;
+use crate::image_ref::ImageRef;
+
+const DEFAULT_CONFIG_PATH: &str = "/home/node_agent/agent.toml";
+const ENV_PREFIX: &str = "SERVER_AGENT_";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub key_file: PathBuf,
+    pub log_level: String,
+    pub allowed_base_dirs: Vec<PathBuf>,
+    pub docker: DockerConfig,
+    pub timeouts: TimeoutConfig,
+    pub runner: RunnerConfig,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    pub audit: AuditConfig,
+    pub jobs: JobsConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DockerConfig {
+    /// `unix:///path/to/docker.sock` or `tcp://host:port`. Falls back to `DOCKER_HOST` when unset.
+    pub host: Option<String>,
+    pub timeout_secs: u64,
+    /// Private registry credentials, managed through /admin/registries.
+    pub registries_file: PathBuf,
+    /// Image of the short-lived containers that back up and restore volumes.
+    pub helper_image: String,
+    /// Largest tar archive accepted by a volume restore, which streams it into the volume.
+    pub volume_restore_max_bytes: u64,
+    /// Network Traefik routes through, created before deployments if missing. Empty to skip.
+    pub traefik_network: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub command_secs: u64,
+    pub compose_secs: u64,
+    pub runner_setup_secs: u64,
+    /// Grace period before docker kills a container that is being replaced.
+    pub container_stop_secs: u64,
+    /// How long a new container gets to be running, or healthy if it has a health check.
+    pub container_start_secs: u64,
+    /// Default timeout of POST /docker/container/{id}/exec, requests may ask for another one.
+    pub exec_secs: u64,
+    /// A container terminal without input from the client for this long is closed.
+    pub terminal_idle_secs: u64,
+    /// How long a shutdown waits for running requests and commands before aborting them.
+    pub shutdown_secs: u64,
+    /// Reading a request body may take this long; streamed uploads may pause this long between chunks.
+    pub request_body_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunnerConfig {
+    pub latest_release_url: String,
+    pub download_base_url: String,
+    pub platform: String,
+    pub download_timeout_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    /// Reuse the certificate Traefik stored in its ACME file instead of `cert_file`/`key_file`.
+    pub traefik_acme_file: Option<PathBuf>,
+    pub traefik_resolver: String,
+    pub traefik_domain: Option<String>,
+    pub reload_interval_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// bcrypt cost for keys generated by the agent (rotation)
+    pub bcrypt_cost: u32,
+    /// How long the old key keeps working after a rotation unless the request says otherwise.
+    pub rotation_grace_secs: u64,
+    /// How often the key file is checked for changes.
+    pub reload_interval_secs: u64,
+    /// Signed requests are rejected if their timestamp is further than this from the agent clock.
+    pub signature_max_skew_secs: u64,
+    /// Verified keys are cached this long so frequent polling skips bcrypt. 0 disables the cache.
+    pub verified_cache_secs: u64,
+    /// Failed attempts from one address before it gets locked out.
+    pub lockout_threshold: u32,
+    /// First lockout, doubled with every further failure up to `lockout_max_secs`.
+    pub lockout_base_secs: u64,
+    pub lockout_max_secs: u64,
+    /// Proxies (like Traefik) whose X-Forwarded-For header names the real client.
+    #[serde(deserialize_with = "deserialize_ip_nets")]
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// JSONL file every mutating request is appended to.
+    pub file: PathBuf,
+    /// At this size the file is moved to `<file>.1`, replacing the previous one.
+    pub max_file_bytes: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// How long finished jobs stay queryable.
+    pub retention_secs: u64,
+    /// Finished jobs beyond this are dropped oldest first, running jobs are always kept.
+    pub max_jobs: usize,
+    /// Captured output per job, older output is dropped first.
+    pub max_output_bytes: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: "127.0.0.1:8080".parse().unwrap(),
+            key_file: PathBuf::from("/home/node_agent/.key.hash"),
+            log_level: "info".to_string(),
+            allowed_base_dirs: vec![PathBuf::from("/home/node_agent")],
+            docker: DockerConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            runner: RunnerConfig::default(),
+            tls: TlsConfig::default(),
+            auth: AuthConfig::default(),
+            audit: AuditConfig::default(),
+            jobs: JobsConfig::default(),
+        }
+    }
+}
+
+impl Default for DockerConfig {
+    fn default() -> Self {
+        DockerConfig {
+            host: None,
+            timeout_secs: 120,
+            registries_file: PathBuf::from("/home/node_agent/registries.toml"),
+            helper_image: "busybox:stable".to_string(),
+            volume_restore_max_bytes: 1024 * 1024 * 1024,
+            traefik_network: "traefik".to_string(),
+        }
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            command_secs: 30,
+            compose_secs: 900,
+            runner_setup_secs: 300,
+            container_stop_secs: 10,
+            container_start_secs: 60,
+            exec_secs: 60,
+            terminal_idle_secs: 900,
+            shutdown_secs: 60,
+            request_body_secs: 60,
+        }
+    }
+}
+
+impl Default for RunnerConfig {
+    fn default() -> Self {
+        RunnerConfig {
+            latest_release_url: "https://github.com/actions/runner/releases/latest".to_string(),
+            download_base_url: "https://github.com/actions/runner/releases/download".to_string(),
+            platform: "linux-x64".to_string(),
+            download_timeout_secs: 300,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            cert_file: None,
+            key_file: None,
+            traefik_acme_file: None,
+            traefik_resolver: "letsencrypt".to_string(),
+            traefik_domain: None,
+            reload_interval_secs: 30,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            bcrypt_cost: 10,
+            rotation_grace_secs: 86400,
+            reload_interval_secs: 10,
+            signature_max_skew_secs: 300,
+            verified_cache_secs: 60,
+            lockout_threshold: 5,
+            lockout_base_secs: 30,
+            lockout_max_secs: 3600,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            enabled: true,
+            file: PathBuf::from("/home/node_agent/audit.jsonl"),
+            max_file_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            retention_secs: 3600,
+            max_jobs: 200,
+            max_output_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env(String, String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read config file {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config file {}: {}", path.display(), e),
+            ConfigError::Env(name, e) => write!(f, "invalid value for {}: {}", name, e),
+            ConfigError::Invalid(e) => write!(f, "invalid config: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Load the config from the file given by `--config`, `SERVER_AGENT_CONFIG` or the default path,
+/// then apply `SERVER_AGENT_*` environment overrides and validate the result.
+///
+/// The legacy positional arguments (`server_agent ADDR [KEY_FILE]`) used by older systemd units
+/// are still honoured and take precedence over the file.
+pub fn load() -> Result<Config, ConfigError> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut explicit_path = env::var(format!("{}CONFIG", ENV_PREFIX)).ok().map(PathBuf::from);
+    let mut positional = Vec::new();
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--config" || arg == "-c" {
+            match iter.next() {
+                Some(path) => explicit_path = Some(PathBuf::from(path)),
+                None => return Err(ConfigError::Invalid(format!("{} expects a file path", arg))),
+            }
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            explicit_path = Some(PathBuf::from(path));
+        } else {
+            positional.push(arg);
+        }
+    }
+
+    let mut config = match explicit_path {
+        Some(path) => read_file(&path)?,
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => read_file(Path::new(DEFAULT_CONFIG_PATH))?,
+        None => Config::default(),
+    };
+
+    if let Some(addr) = positional.first() {
+        config.listen = addr
+            .parse()
+            .map_err(|e| ConfigError::Invalid(format!("listen address argument {}: {}", addr, e)))?;
+    }
+    if let Some(key_file) = positional.get(1) {
+        config.key_file = PathBuf::from(key_file);
+    }
+
+    apply_env(&mut config)?;
+    config.validate()?;
+    Ok(config)
+}
+
+fn read_file(path: &Path) -> Result<Config, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+    toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+}
+
+fn env_var(name: &str) -> Option<(String, String)> {
+    let full_name = format!("{}{}", ENV_PREFIX, name);
+    env::var(&full_name).ok().map(|v| (full_name, v))
+}
+
+fn env_parse<T>(name: &str, target: &mut T) -> Result<(), ConfigError>
+where
+    T: std::str::FromStr,
+    T::Err: fmt::Display,
+{
+    if let Some((full_name, value)) = env_var(name) {
+        *target = value
+            .parse()
+            .map_err(|e: T::Err| ConfigError::Env(full_name, e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// A network like `172.16.0.0/12` or a single address.
+fn parse_ip_net(value: &str) -> Result<IpNet, String> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{} is not an IP address or network", value))
+}
+
+fn deserialize_ip_nets<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|v| parse_ip_net(v).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn apply_env(config: &mut Config) -> Result<(), ConfigError> {
+    env_parse("LISTEN", &mut config.listen)?;
+    env_parse("KEY_FILE", &mut config.key_file)?;
+    env_parse("LOG_LEVEL", &mut config.log_level)?;
+    if let Some((_, value)) = env_var("ALLOWED_BASE_DIRS") {
+        config.allowed_base_dirs = env::split_paths(&value).collect();
+    }
+    if let Some((_, value)) = env_var("DOCKER_HOST") {
+        config.docker.host = Some(value);
+    }
+    env_parse("DOCKER_TIMEOUT_SECS", &mut config.docker.timeout_secs)?;
+    env_parse("DOCKER_REGISTRIES_FILE", &mut config.docker.registries_file)?;
+    env_parse("DOCKER_HELPER_IMAGE", &mut config.docker.helper_image)?;
+    env_parse("DOCKER_VOLUME_RESTORE_MAX_BYTES", &mut config.docker.volume_restore_max_bytes)?;
+    env_parse("DOCKER_TRAEFIK_NETWORK", &mut config.docker.traefik_network)?;
+    env_parse("COMMAND_TIMEOUT_SECS", &mut config.timeouts.command_secs)?;
+    env_parse("COMPOSE_TIMEOUT_SECS", &mut config.timeouts.compose_secs)?;
+    env_parse("RUNNER_SETUP_TIMEOUT_SECS", &mut config.timeouts.runner_setup_secs)?;
+    env_parse("CONTAINER_STOP_TIMEOUT_SECS", &mut config.timeouts.container_stop_secs)?;
+    env_parse("CONTAINER_START_TIMEOUT_SECS", &mut config.timeouts.container_start_secs)?;
+    env_parse("EXEC_TIMEOUT_SECS", &mut config.timeouts.exec_secs)?;
+    env_parse("TERMINAL_IDLE_TIMEOUT_SECS", &mut config.timeouts.terminal_idle_secs)?;
+    env_parse("SHUTDOWN_TIMEOUT_SECS", &mut config.timeouts.shutdown_secs)?;
+    env_parse("REQUEST_BODY_TIMEOUT_SECS", &mut config.timeouts.request_body_secs)?;
+    env_parse("RUNNER_LATEST_RELEASE_URL", &mut config.runner.latest_release_url)?;
+    env_parse("RUNNER_DOWNLOAD_BASE_URL", &mut config.runner.download_base_url)?;
+    env_parse("RUNNER_PLATFORM", &mut config.runner.platform)?;
+    env_parse("RUNNER_DOWNLOAD_TIMEOUT_SECS", &mut config.runner.download_timeout_secs)?;
+    env_parse("TLS_ENABLED", &mut config.tls.enabled)?;
+    if let Some((_, value)) = env_var("TLS_CERT_FILE") {
+        config.tls.cert_file = Some(PathBuf::from(value));
+    }
+    if let Some((_, value)) = env_var("TLS_KEY_FILE") {
+        config.tls.key_file = Some(PathBuf::from(value));
+    }
+    if let Some((_, value)) = env_var("TLS_TRAEFIK_ACME_FILE") {
+        config.tls.traefik_acme_file = Some(PathBuf::from(value));
+    }
+    env_parse("TLS_TRAEFIK_RESOLVER", &mut config.tls.traefik_resolver)?;
+    if let Some((_, value)) = env_var("TLS_TRAEFIK_DOMAIN") {
+        config.tls.traefik_domain = Some(value);
+    }
+    env_parse("TLS_RELOAD_INTERVAL_SECS", &mut config.tls.reload_interval_secs)?;
+    env_parse("AUTH_BCRYPT_COST", &mut config.auth.bcrypt_cost)?;
+    env_parse("AUTH_ROTATION_GRACE_SECS", &mut config.auth.rotation_grace_secs)?;
+    env_parse("AUTH_RELOAD_INTERVAL_SECS", &mut config.auth.reload_interval_secs)?;
+    env_parse("AUTH_SIGNATURE_MAX_SKEW_SECS", &mut config.auth.signature_max_skew_secs)?;
+    env_parse("AUTH_VERIFIED_CACHE_SECS", &mut config.auth.verified_cache_secs)?;
+    env_parse("AUTH_LOCKOUT_THRESHOLD", &mut config.auth.lockout_threshold)?;
+    env_parse("AUTH_LOCKOUT_BASE_SECS", &mut config.auth.lockout_base_secs)?;
+    env_parse("AUTH_LOCKOUT_MAX_SECS", &mut config.auth.lockout_max_secs)?;
+    if let Some((full_name, value)) = env_var("AUTH_TRUSTED_PROXIES") {
+        config.auth.trusted_proxies = value
+            .split(',')
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(parse_ip_net)
+            .collect::<Result<_, _>>()
+            .map_err(|e| ConfigError::Env(full_name, e))?;
+    }
+    env_parse("AUDIT_ENABLED", &mut config.audit.enabled)?;
+    env_parse("AUDIT_FILE", &mut config.audit.file)?;
+    env_parse("AUDIT_MAX_FILE_BYTES", &mut config.audit.max_file_bytes)?;
+    env_parse("JOBS_RETENTION_SECS", &mut config.jobs.retention_secs)?;
+    env_parse("JOBS_MAX_JOBS", &mut config.jobs.max_jobs)?;
+    env_parse("JOBS_MAX_OUTPUT_BYTES", &mut config.jobs.max_output_bytes)?;
+    Ok(())
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), ConfigError> {
+        match fs::read_to_string(&self.key_file) {
+            Ok(content) if content.trim().is_empty() => {
+                return Err(ConfigError::Invalid(format!(
+                    "key file {} is empty",
+                    self.key_file.display()
+                )))
+            }
+            Ok(_) => {}
+            Err(e) => {
+                return Err(ConfigError::Invalid(format!(
+                    "key file {} cannot be read: {}",
+                    self.key_file.display(),
+                    e
+                )))
+            }
+        }
+
+        if log_level_filter(&self.log_level).is_none() {
+            return Err(ConfigError::Invalid(format!(
+                "log_level must be one of off, error, warn, info, debug, trace (got {})",
+                self.log_level
+            )));
+        }
+
+        if self.allowed_base_dirs.is_empty() {
+            return Err(ConfigError::Invalid("allowed_base_dirs must not be empty".to_string()));
+        }
+        for dir in &self.allowed_base_dirs {
+            if !dir.is_absolute() {
+                return Err(ConfigError::Invalid(format!(
+                    "allowed_base_dirs entry {} is not an absolute path",
+                    dir.display()
+                )));
+            }
+        }
+
+        if let Some(host) = &self.docker.host {
+            if !(host.starts_with("unix://") || host.starts_with("tcp://") || host.starts_with("http://")) {
+                return Err(ConfigError::Invalid(format!(
+                    "docker.host must start with unix://, tcp:// or http:// (got {})",
+                    host
+                )));
+            }
+        }
+
+        if let Err(e) = self.docker.helper_image.parse::<ImageRef>() {
+            return Err(ConfigError::Invalid(format!("docker.helper_image is not a valid image: {}", e)));
+        }
+        if self.docker.volume_restore_max_bytes == 0 {
+            return Err(ConfigError::Invalid("docker.volume_restore_max_bytes must be greater than 0".to_string()));
+        }
+        let network = &self.docker.traefik_network;
+        if !network.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-')) {
+            return Err(ConfigError::Invalid(format!(
+                "docker.traefik_network must only contain letters, digits, _, . and - (got {})",
+                network
+            )));
+        }
+
+        let timeouts = [
+            ("docker.timeout_secs", self.docker.timeout_secs),
+            ("timeouts.command_secs", self.timeouts.command_secs),
+            ("timeouts.compose_secs", self.timeouts.compose_secs),
+            ("timeouts.runner_setup_secs", self.timeouts.runner_setup_secs),
+            ("timeouts.container_stop_secs", self.timeouts.container_stop_secs),
+            ("timeouts.container_start_secs", self.timeouts.container_start_secs),
+            ("timeouts.exec_secs", self.timeouts.exec_secs),
+            ("timeouts.terminal_idle_secs", self.timeouts.terminal_idle_secs),
+            ("timeouts.shutdown_secs", self.timeouts.shutdown_secs),
+            ("timeouts.request_body_secs", self.timeouts.request_body_secs),
+            ("runner.download_timeout_secs", self.runner.download_timeout_secs),
+            ("tls.reload_interval_secs", self.tls.reload_interval_secs),
+            ("auth.reload_interval_secs", self.auth.reload_interval_secs),
+            ("auth.signature_max_skew_secs", self.auth.signature_max_skew_secs),
+            ("auth.lockout_base_secs", self.auth.lockout_base_secs),
+            ("auth.lockout_max_secs", self.auth.lockout_max_secs),
+            ("jobs.retention_secs", self.jobs.retention_secs),
+        ];
+        for (name, value) in timeouts {
+            if value == 0 {
+                return Err(ConfigError::Invalid(format!("{} must be greater than 0", name)));
+            }
+        }
+
+        for (name, value) in [
+            ("runner.latest_release_url", &self.runner.latest_release_url),
+            ("runner.download_base_url", &self.runner.download_base_url),
+        ] {
+            if let Err(e) = url::Url::parse(value) {
+                return Err(ConfigError::Invalid(format!("{} is not a valid URL: {}", name, e)));
+            }
+        }
+        if self.runner.platform.is_empty() {
+            return Err(ConfigError::Invalid("runner.platform must not be empty".to_string()));
+        }
+
+        if !(4..=31).contains(&self.auth.bcrypt_cost) {
+            return Err(ConfigError::Invalid(format!(
+                "auth.bcrypt_cost must be between 4 and 31 (got {})",
+                self.auth.bcrypt_cost
+            )));
+        }
+
+        if self.auth.rotation_grace_secs > auth::MAX_ROTATION_GRACE_SECS {
+            return Err(ConfigError::Invalid(format!(
+                "auth.rotation_grace_secs must be at most {} (got {})",
+                auth::MAX_ROTATION_GRACE_SECS,
+                self.auth.rotation_grace_secs
+            )));
+        }
+
+        if self.auth.lockout_threshold == 0 {
+            return Err(ConfigError::Invalid("auth.lockout_threshold must be greater than 0".to_string()));
+        }
+
+        if self.jobs.max_jobs == 0 || self.jobs.max_output_bytes == 0 {
+            return Err(ConfigError::Invalid(
+                "jobs.max_jobs and jobs.max_output_bytes must be greater than 0".to_string(),
+            ));
+        }
+
+        if !self.docker.registries_file.is_absolute() {
+            return Err(ConfigError::Invalid(format!(
+                "docker.registries_file must be an absolute path (got {})",
+                self.docker.registries_file.display()
+            )));
+        }
+
+        if self.audit.enabled && !self.audit.file.is_absolute() {
+            return Err(ConfigError::Invalid(format!(
+                "audit.file must be an absolute path (got {})",
+                self.audit.file.display()
+            )));
+        }
+        if self.audit.max_file_bytes == 0 {
+            return Err(ConfigError::Invalid("audit.max_file_bytes must be greater than 0".to_string()));
+        }
+
+        if self.tls.enabled {
+            let tls = &self.tls;
+            let files = tls.cert_file.is_some() || tls.key_file.is_some();
+            let traefik = tls.traefik_acme_file.is_some();
+            if files == traefik {
+                return Err(ConfigError::Invalid(
+                    "tls needs either cert_file and key_file or traefik_acme_file".to_string(),
+                ));
+            }
+            if files && (tls.cert_file.is_none() || tls.key_file.is_none()) {
+                return Err(ConfigError::Invalid("tls.cert_file and tls.key_file must be set together".to_string()));
+            }
+            if traefik && tls.traefik_domain.is_none() {
+                return Err(ConfigError::Invalid(
+                    "tls.traefik_domain is required with tls.traefik_acme_file".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn log_level_filter(&self) -> log::LevelFilter {
+        log_level_filter(&self.log_level).unwrap_or(log::LevelFilter::Info)
+    }
+}
+
+fn log_level_filter(level: &str) -> Option<log::LevelFilter> {
+    level.parse().ok()
+}
+
+/// Store the loaded config for the rest of the process. Must be called once at startup.
+pub fn init(config: Config) -> &'static Config {
+    CONFIG.get_or_init(|| config)
+}
+
+/// The default config, for tests of code that reads `get()`.
+#[cfg(test)]
+pub fn init_for_tests() -> &'static Config {
+    init(Config::default())
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("config not initialised")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    /// A parsed config pointing at a key file that exists, so `validate` gets past it.
+    fn valid(content: &str) -> Config {
+        let mut config = parse(content).unwrap();
+        config.key_file = Path::new(env!("CARGO_MANIFEST_DIR")).join("keys.example.toml");
+        config
+    }
+
+    #[test]
+    fn empty_file_uses_defaults() {
+        let config = parse("").unwrap();
+        assert_eq!(config.listen, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(config.allowed_base_dirs, [PathBuf::from("/home/node_agent")]);
+        assert_eq!(config.auth.rotation_grace_secs, 86400);
+        assert!(config.auth.trusted_proxies.is_empty());
+        assert_eq!(config.docker.helper_image, "busybox:stable");
+    }
+
+    #[test]
+    fn example_config_parses_and_validates() {
+        let config = valid(include_str!("../agent.example.toml"));
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn sections_override_defaults() {
+        let config = parse(
+            r#"
+            listen = "0.0.0.0:9000"
+            allowed_base_dirs = ["/srv/apps"]
+
+            [auth]
+            lockout_threshold = 3
+            trusted_proxies = ["172.18.0.2", "10.0.0.0/8"]
+
+            [timeouts]
+            compose_secs = 900
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.listen, "0.0.0.0:9000".parse().unwrap());
+        assert_eq!(config.allowed_base_dirs, [PathBuf::from("/srv/apps")]);
+        assert_eq!(config.auth.lockout_threshold, 3);
+        // Untouched fields of a section keep their defaults
+        assert_eq!(config.auth.lockout_base_secs, 30);
+        assert_eq!(
+            config.auth.trusted_proxies,
+            ["172.18.0.2/32".parse::<IpNet>().unwrap(), "10.0.0.0/8".parse().unwrap()]
+        );
+        assert_eq!(config.timeouts.compose_secs, 900);
+    }
+
+    #[test]
+    fn rejects_unknown_and_malformed_fields() {
+        assert!(parse("listen_addr = \"0.0.0.0:9000\"").is_err());
+        assert!(parse("[auth]\nlockout_treshold = 3").is_err());
+        assert!(parse("listen = \"localhost\"").is_err());
+        assert!(parse("[auth]\ntrusted_proxies = [\"traefik\"]").is_err());
+        assert!(parse("[timeouts]\ncompose_secs = -1").is_err());
+    }
+
+    #[test]
+    fn validation() {
+        valid("").validate().unwrap();
+        for content in [
+            "log_level = \"verbose\"",
+            "allowed_base_dirs = []",
+            "allowed_base_dirs = [\"relative/dir\"]",
+            "[docker]\nhost = \"ssh://server\"",
+            "[docker]\nhelper_image = \"Busybox\"",
+            "[docker]\ntraefik_network = \"traefik net\"",
+            "[timeouts]\ncompose_secs = 0",
+            "[auth]\nbcrypt_cost = 3",
+            "[auth]\nlockout_threshold = 0",
+            "[auth]\nrotation_grace_secs = 2592001",
+            "[audit]\nfile = \"audit.jsonl\"",
+        ] {
+            assert!(valid(content).validate().is_err(), "{} should be rejected", content);
+        }
+        valid("[auth]\nrotation_grace_secs = 2592000").validate().unwrap();
+    }
+
+    #[test]
+    fn missing_key_file_is_rejected() {
+        let mut config = parse("").unwrap();
+        config.key_file = PathBuf::from("/nonexistent/server_agent/.key.hash");
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use std::fmt;
+use std::io;
+use std::process::ExitStatus;
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Response, StatusCode};
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Machine readable error codes, each with a fixed HTTP status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request body is missing or not the expected JSON
+    InvalidBody,
+    /// A query parameter is missing or malformed
+    InvalidQuery,
+    /// The request is well-formed but cannot be carried out as asked
+    InvalidRequest,
+    /// No or an unknown API key, or a rejected signature
+    Unauthorized,
+    /// The key lacks the scope of the route
+    MissingScope,
+    /// The deployment path or container is outside what the key or agent allows
+    PathNotAllowed,
+    NotFound,
+    MethodNotAllowed,
+    /// The target is in a state that conflicts with the request
+    Conflict,
+    PayloadTooLarge,
+    /// The request body did not arrive within `timeouts.request_body_secs`
+    RequestTimeout,
+    /// Locked out after repeated failed authentication
+    TooManyRequests,
+    /// The Docker daemon cannot be reached
+    DockerUnavailable,
+    /// The Docker daemon returned an error
+    DockerError,
+    /// A started container exited, turned unhealthy or was not ready in time
+    ContainerFailed,
+    /// A command exited unsuccessfully or could not be run
+    CommandFailed,
+    /// A command did not finish within its timeout
+    CommandTimedOut,
+    /// A download from an external service such as GitHub failed
+    UpstreamError,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidBody | ErrorCode::InvalidQuery | ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::MissingScope | ErrorCode::PathNotAllowed => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::DockerUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::DockerError | ErrorCode::ContainerFailed | ErrorCode::UpstreamError => StatusCode::BAD_GATEWAY,
+            ErrorCode::CommandTimedOut => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::CommandFailed | ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// The error body of every endpoint: `{"error": {"code": ..., "message": ..., "details": ...}}`.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorBody {
+    pub error: ApiError,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> ApiError {
+        ApiError {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: Value) -> ApiError {
+        self.details = Some(details);
+        self
+    }
+
+    /// Add one entry to the details object, keeping what is already there.
+    pub fn with_detail(mut self, key: &str, value: Value) -> ApiError {
+        match &mut self.details {
+            Some(Value::Object(details)) => {
+                details.insert(key.to_string(), value);
+            }
+            _ => self.details = Some(serde_json::json!({ key: value })),
+        }
+        self
+    }
+
+    pub fn invalid_body(e: impl fmt::Display) -> ApiError {
+        ApiError::new(ErrorCode::InvalidBody, format!("cannot read request body: {}", e))
+    }
+
+    pub fn not_found(message: impl Into<String>) -> ApiError {
+        ApiError::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn path_not_allowed(message: impl Into<String>) -> ApiError {
+        ApiError::new(ErrorCode::PathNotAllowed, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> ApiError {
+        ApiError::new(ErrorCode::Internal, message)
+    }
+
+    pub fn docker_unavailable(e: &bollard::errors::Error) -> ApiError {
+        log::error!("Docker connection failed: {}", e);
+        ApiError::new(ErrorCode::DockerUnavailable, format!("cannot connect to docker: {}", e))
+    }
+
+    /// A failed Docker API call; the daemon's 404 and 409 keep their meaning.
+    pub fn docker(action: &str, e: bollard::errors::Error) -> ApiError {
+        match e {
+            bollard::errors::Error::DockerResponseServerError { status_code, message } => {
+                let code = match status_code {
+                    400 => ErrorCode::InvalidRequest,
+                    404 => ErrorCode::NotFound,
+                    304 | 409 => ErrorCode::Conflict,
+                    _ => ErrorCode::DockerError,
+                };
+                ApiError::new(code, format!("{} failed: {}", action, message))
+                    .with_details(serde_json::json!({ "docker_status": status_code }))
+            }
+            e => {
+                log::error!("{} failed: {}", action, e);
+                ApiError::new(ErrorCode::DockerError, format!("{} failed: {}", action, e))
+            }
+        }
+    }
+
+    pub fn command(command_str: &str, e: &io::Error) -> ApiError {
+        let code = match e.kind() {
+            io::ErrorKind::TimedOut => ErrorCode::CommandTimedOut,
+            _ => ErrorCode::CommandFailed,
+        };
+        ApiError::new(code, format!("{} failed: {}", command_str, e))
+            .with_details(serde_json::json!({ "command": command_str }))
+    }
+
+    /// A command that ran but exited unsuccessfully.
+    pub fn command_exit(command_str: &str, status: ExitStatus) -> ApiError {
+        ApiError::new(ErrorCode::CommandFailed, format!("{} exited with {}", command_str, status))
+            .with_details(serde_json::json!({ "command": command_str, "exit_code": status.code() }))
+    }
+
+    pub fn upstream(message: impl Into<String>) -> ApiError {
+        ApiError::new(ErrorCode::UpstreamError, message)
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    pub fn response(self) -> Response<Full<Bytes>> {
+        let status = self.status();
+        let body = serde_json::to_string(&ErrorBody { error: self }).unwrap();
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<ApiError> for Response<Full<Bytes>> {
+    fn from(e: ApiError) -> Self {
+        e.response()
+    }
+}
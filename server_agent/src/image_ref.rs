@@ -0,0 +1,261 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// Docker limits the repository name including the registry to 255 characters.
+const MAX_NAME_LENGTH: usize = 255;
+
+/// When to pull the image of a deployment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum PullPolicy {
+    /// Pull on every deployment, so a moved tag is picked up
+    Always,
+    /// Pull only when the daemon does not have the image
+    #[default]
+    IfNotPresent,
+    /// Never pull, fail if the image is missing
+    Never,
+}
+
+impl PullPolicy {
+    /// Value of `docker compose up --pull`.
+    pub fn compose_flag(self) -> &'static str {
+        match self {
+            PullPolicy::Always => "always",
+            PullPolicy::IfNotPresent => "missing",
+            PullPolicy::Never => "never",
+        }
+    }
+}
+
+/// An image reference like `registry.local:5000/team/app:1.2@sha256:...`, split the way the
+/// docker CLI does it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    /// Registry host with optional port, None for Docker Hub
+    pub registry: Option<String>,
+    /// Namespace and name, e.g. `library/nginx` or `nginx`
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl ImageRef {
+    /// The image without tag and digest, the `fromImage` of a pull.
+    pub fn name(&self) -> String {
+        match &self.registry {
+            Some(registry) => format!("{}/{}", registry, self.repository),
+            None => self.repository.clone(),
+        }
+    }
+
+    /// What to pull: the digest if pinned, else the tag, else `latest`.
+    pub fn pull_tag(&self) -> &str {
+        self.digest.as_deref().or(self.tag.as_deref()).unwrap_or("latest")
+    }
+}
+
+impl fmt::Display for ImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ImageRef {
+    type Err = String;
+
+    fn from_str(reference: &str) -> Result<ImageRef, String> {
+        if reference.is_empty() {
+            return Err("empty image reference".to_string());
+        }
+        let (rest, digest) = match reference.split_once('@') {
+            Some((rest, digest)) => {
+                if !regexes().digest.is_match(digest) {
+                    return Err(format!("invalid digest '{}'", digest));
+                }
+                (rest, Some(digest))
+            }
+            None => (reference, None),
+        };
+
+        // Only a colon after the last slash starts a tag, earlier ones belong to a registry port
+        let last_component = rest.rfind('/').map_or(0, |i| i + 1);
+        let (name, tag) = match rest[last_component..].rfind(':') {
+            Some(i) => (&rest[..last_component + i], Some(&rest[last_component + i + 1..])),
+            None => (rest, None),
+        };
+        if let Some(tag) = tag {
+            if !regexes().tag.is_match(tag) {
+                return Err(format!("invalid tag '{}'", tag));
+            }
+        }
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(format!("repository name longer than {} characters", MAX_NAME_LENGTH));
+        }
+
+        // Like the docker CLI: the first component is a registry if it looks like a host
+        let (registry, repository) = match name.split_once('/') {
+            Some((first, repository)) if first.contains(['.', ':']) || first == "localhost" => {
+                if !regexes().registry.is_match(first) {
+                    return Err(format!("invalid registry '{}'", first));
+                }
+                (Some(first), repository)
+            }
+            _ => (None, name),
+        };
+        for component in repository.split('/') {
+            if !regexes().component.is_match(component) {
+                return Err(format!(
+                    "invalid repository name component '{}', only lowercase letters, digits and separators are allowed",
+                    component
+                ));
+            }
+        }
+
+        Ok(ImageRef {
+            registry: registry.map(str::to_string),
+            repository: repository.to_string(),
+            tag: tag.map(str::to_string),
+            digest: digest.map(str::to_string),
+        })
+    }
+}
+
+/// Whether `host` is a valid registry host with optional port.
+pub fn is_registry_host(host: &str) -> bool {
+    regexes().registry.is_match(host)
+}
+
+struct Regexes {
+    registry: Regex,
+    component: Regex,
+    tag: Regex,
+    digest: Regex,
+}
+
+/// The grammar of github.com/distribution/reference.
+fn regexes() -> &'static Regexes {
+    static REGEXES: OnceLock<Regexes> = OnceLock::new();
+    REGEXES.get_or_init(|| Regexes {
+        registry: Regex::new(
+            r"^(?:[a-zA-Z0-9](?:[a-zA-Z0-9-]*[a-zA-Z0-9])?(?:\.[a-zA-Z0-9](?:[a-zA-Z0-9-]*[a-zA-Z0-9])?)*|\[[0-9a-fA-F:]+\])(?::[0-9]+)?$",
+        )
+        .unwrap(),
+        component: Regex::new(r"^[a-z0-9]+(?:(?:[._]|__|-+)[a-z0-9]+)*$").unwrap(),
+        tag: Regex::new(r"^[\w][\w.-]{0,127}$").unwrap(),
+        digest: Regex::new(r"^[A-Za-z][A-Za-z0-9]*(?:[-_+.][A-Za-z][A-Za-z0-9]*)*:[0-9a-fA-F]{32,}$").unwrap(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn parse(reference: &str) -> ImageRef {
+        reference.parse().unwrap()
+    }
+
+    #[test]
+    fn docker_hub_name() {
+        let image = parse("nginx");
+        assert_eq!(image.registry, None);
+        assert_eq!(image.repository, "nginx");
+        assert_eq!(image.tag, None);
+        assert_eq!(image.pull_tag(), "latest");
+
+        let image = parse("library/nginx:1.27-alpine");
+        assert_eq!(image.registry, None);
+        assert_eq!(image.repository, "library/nginx");
+        assert_eq!(image.tag.as_deref(), Some("1.27-alpine"));
+    }
+
+    #[test]
+    fn registry_port_is_not_a_tag() {
+        let image = parse("registry.local:5000/team/app");
+        assert_eq!(image.registry.as_deref(), Some("registry.local:5000"));
+        assert_eq!(image.repository, "team/app");
+        assert_eq!(image.tag, None);
+
+        let image = parse("registry.local:5000/team/app:1.2");
+        assert_eq!(image.registry.as_deref(), Some("registry.local:5000"));
+        assert_eq!(image.tag.as_deref(), Some("1.2"));
+        assert_eq!(image.name(), "registry.local:5000/team/app");
+    }
+
+    #[test]
+    fn first_component_is_a_registry_only_if_it_looks_like_a_host() {
+        assert_eq!(parse("localhost/app").registry.as_deref(), Some("localhost"));
+        assert_eq!(parse("localhost:5000/app").registry.as_deref(), Some("localhost:5000"));
+        assert_eq!(parse("ghcr.io/owner/app").registry.as_deref(), Some("ghcr.io"));
+        let image = parse("team/app");
+        assert_eq!(image.registry, None);
+        assert_eq!(image.repository, "team/app");
+    }
+
+    #[test]
+    fn digest() {
+        let image = parse(&format!("ghcr.io/owner/app@{}", DIGEST));
+        assert_eq!(image.tag, None);
+        assert_eq!(image.digest.as_deref(), Some(DIGEST));
+        assert_eq!(image.pull_tag(), DIGEST);
+
+        let image = parse(&format!("registry.local:5000/app:1.2@{}", DIGEST));
+        assert_eq!(image.registry.as_deref(), Some("registry.local:5000"));
+        assert_eq!(image.tag.as_deref(), Some("1.2"));
+        // A pinned digest wins over the tag
+        assert_eq!(image.pull_tag(), DIGEST);
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for reference in [
+            "nginx",
+            "nginx:latest",
+            "registry.local:5000/team/app:1.2",
+            &format!("ghcr.io/owner/app:v1@{}", DIGEST),
+        ] {
+            assert_eq!(parse(reference).to_string(), reference);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_references() {
+        for reference in [
+            "",
+            "Nginx",
+            "nginx:",
+            "nginx:-tag",
+            "nginx@sha256:short",
+            "nginx@md5",
+            "team//app",
+            "bad_host!:5000/app",
+            "app:1.2:3",
+        ] {
+            assert!(reference.parse::<ImageRef>().is_err(), "{} should be rejected", reference);
+        }
+        let long = format!("{}:tag", "a".repeat(MAX_NAME_LENGTH + 1));
+        assert!(long.parse::<ImageRef>().is_err());
+    }
+
+    #[test]
+    fn registry_host() {
+        assert!(is_registry_host("registry.local:5000"));
+        assert!(is_registry_host("[::1]:5000"));
+        assert!(!is_registry_host("registry.local:port"));
+        assert!(!is_registry_host("https://registry.local"));
+    }
+}
@@ -0,0 +1,572 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{broadcast, watch};
+
+use crate::auth::{self, ApiKey, Scope};
+use crate::config::{self, JobsConfig};
+use crate::error::ApiError;
+use crate::shutdown;
+
+/// Events a slow SSE client may fall behind before it misses some.
+const EVENT_BUFFER: usize = 1024;
+
+tokio::task_local! {
+    static CURRENT_JOB: JobHandle;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    /// POST /docker/compose
+    Compose,
+    /// POST /runner
+    RunnerSetup,
+    /// POST /docker/container, including the image pull
+    ContainerCreate,
+    /// POST /docker/images/pull
+    ImagePull,
+}
+
+impl std::fmt::Display for JobKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            JobKind::Compose => "compose",
+            JobKind::RunnerSetup => "runner_setup",
+            JobKind::ContainerCreate => "container_create",
+            JobKind::ImagePull => "image_pull",
+        };
+        f.write_str(name)
+    }
+}
+
+impl JobKind {
+    /// Scope of the route that starts this kind of job, also needed to cancel it.
+    pub fn scope(self) -> Scope {
+        match self {
+            JobKind::Compose | JobKind::ContainerCreate | JobKind::ImagePull => Scope::Deploy,
+            JobKind::RunnerSetup => Scope::Runner,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct JobSummary {
+    pub id: String,
+    pub kind: JobKind,
+    pub state: JobState,
+    /// Deployment path, or the container name for container jobs
+    pub target: String,
+    /// Name of the key that started the job
+    pub key: String,
+    /// Unix seconds
+    pub created_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Job {
+    #[serde(flatten)]
+    pub summary: JobSummary,
+    /// Output of the commands the job ran so far, trimmed to jobs.max_output_bytes
+    pub output: String,
+    /// What the synchronous endpoint used to return, once the job succeeded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ApiError>,
+}
+
+/// Progress of one image layer, as reported by the Docker daemon while pulling.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct PullProgress {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layer: Option<String>,
+    pub status: String,
+    /// Bytes downloaded or extracted so far
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<i64>,
+}
+
+/// What `GET /jobs/{job}/events` streams while a job runs.
+#[derive(Debug, Clone)]
+pub enum JobEvent {
+    /// A line of command output
+    Output(String),
+    /// A step of the job, like "Extracting" during a runner setup
+    Step(String),
+    Progress(PullProgress),
+    /// Always the last event
+    Finished {
+        state: JobState,
+        result: Option<Value>,
+        error: Option<ApiError>,
+    },
+}
+
+impl JobEvent {
+    /// SSE event name and data.
+    pub fn encode(&self) -> (&'static str, Value) {
+        match self {
+            JobEvent::Output(line) => ("output", serde_json::json!({ "line": line })),
+            JobEvent::Step(message) => ("step", serde_json::json!({ "message": message })),
+            JobEvent::Progress(progress) => ("progress", serde_json::to_value(progress).unwrap()),
+            JobEvent::Finished { state, result, error } => (
+                "finished",
+                serde_json::json!({ "state": state, "result": result, "error": error }),
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct JobQuery {
+    pub kind: Option<JobKind>,
+    pub state: Option<JobState>,
+}
+
+struct Entry {
+    job: Job,
+    /// Deployment path the job works on, path restricted keys only see jobs below their prefixes
+    path: Option<String>,
+    cancel: watch::Sender<bool>,
+    /// False while the job runs work that must not be interrupted, see `detach`
+    cancellable: bool,
+    finished: watch::Sender<bool>,
+    events: broadcast::Sender<JobEvent>,
+}
+
+#[derive(Clone)]
+struct JobHandle {
+    store: Arc<JobStore>,
+    id: String,
+}
+
+pub enum CancelError {
+    NotFound,
+    MissingScope(Scope),
+    Finished(JobState),
+    /// The job is in a step that has to run to the end
+    NotCancellable,
+}
+
+/// Background jobs of this agent process; they are not persisted across restarts.
+pub struct JobStore {
+    jobs: Mutex<HashMap<String, Entry>>,
+}
+
+fn generate_id() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("no random source");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn visible(entry: &Entry, key: &ApiKey) -> bool {
+    !key.is_path_restricted() || entry.path.as_deref().is_some_and(|path| key.allows_path(path))
+}
+
+impl JobStore {
+    pub fn new() -> JobStore {
+        JobStore {
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run `work` in the background and return the job right away. The job is tracked like a
+    /// request, so a shutdown waits for it.
+    pub fn spawn<F>(self: &Arc<Self>, kind: JobKind, target: &str, path: Option<&str>, key: &ApiKey, work: F) -> Job
+    where
+        F: Future<Output = Result<Value, ApiError>> + Send + 'static,
+    {
+        let (job, cancelled) = insert(&mut self.jobs.lock().unwrap(), kind, target, path, key);
+        self.run(job, cancelled, work)
+    }
+
+    /// Like `spawn` for a job on the deployment path `path`, unless another job still runs there;
+    /// then the id of that job is returned. Deployments of one path must not overlap, so the check
+    /// and the insert happen under one lock and `work` should do all writes below the path.
+    pub fn spawn_exclusive<F>(self: &Arc<Self>, kind: JobKind, path: &str, key: &ApiKey, work: F) -> Result<Job, String>
+    where
+        F: Future<Output = Result<Value, ApiError>> + Send + 'static,
+    {
+        let (job, cancelled) = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let running = jobs
+                .values()
+                .find(|entry| entry.job.summary.state == JobState::Running && entry.path.as_deref() == Some(path));
+            if let Some(running) = running {
+                return Err(running.job.summary.id.clone());
+            }
+            insert(&mut jobs, kind, path, Some(path), key)
+        };
+        Ok(self.run(job, cancelled, work))
+    }
+
+    fn run<F>(self: &Arc<Self>, job: Job, mut cancelled: watch::Receiver<bool>, work: F) -> Job
+    where
+        F: Future<Output = Result<Value, ApiError>> + Send + 'static,
+    {
+        let summary = &job.summary;
+        log::info!("Started job {} ({} {}) for key {}", summary.id, summary.kind, summary.target, summary.key);
+
+        let id = summary.id.clone();
+        let handle = JobHandle {
+            store: self.clone(),
+            id: id.clone(),
+        };
+        let store = self.clone();
+        let name = format!("job {} ({} {})", id, summary.kind, summary.target);
+        tokio::spawn(shutdown::track(name, CURRENT_JOB.scope(handle, async move {
+            // Dropping `work` on cancel also kills the process group of a running command
+            let outcome = tokio::select! {
+                result = work => Some(result),
+                _ = cancelled.wait_for(|cancelled| *cancelled) => None,
+            };
+            store.finish(&id, outcome);
+        })));
+        job
+    }
+
+    fn set_cancellable(&self, id: &str, cancellable: bool) {
+        if let Some(entry) = self.jobs.lock().unwrap().get_mut(id) {
+            entry.cancellable = cancellable;
+        }
+    }
+
+    fn finish(&self, id: &str, outcome: Option<Result<Value, ApiError>>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(entry) = jobs.get_mut(id) else { return };
+        let job = &mut entry.job;
+        match outcome {
+            Some(Ok(result)) => {
+                job.summary.state = JobState::Succeeded;
+                job.result = Some(result);
+            }
+            Some(Err(e)) => {
+                job.summary.state = JobState::Failed;
+                job.error = Some(e);
+            }
+            None => job.summary.state = JobState::Cancelled,
+        }
+        job.summary.finished_at = Some(auth::unix_now());
+        log::info!(
+            "Job {} ({} {}) finished: {:?}",
+            id,
+            job.summary.kind,
+            job.summary.target,
+            job.summary.state
+        );
+        entry.finished.send_replace(true);
+        let _ = entry.events.send(JobEvent::Finished {
+            state: job.summary.state,
+            result: job.result.clone(),
+            error: job.error.clone(),
+        });
+    }
+
+    pub fn get(&self, id: &str, key: &ApiKey) -> Option<Job> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(id).filter(|entry| visible(entry, key)).map(|entry| entry.job.clone())
+    }
+
+    /// Jobs the key may see, newest first.
+    pub fn list(&self, query: &JobQuery, key: &ApiKey) -> Vec<JobSummary> {
+        let mut jobs = self.jobs.lock().unwrap();
+        prune(&mut jobs, &config::get().jobs, auth::unix_now());
+        let mut summaries: Vec<JobSummary> = jobs
+            .values()
+            .filter(|entry| visible(entry, key))
+            .map(|entry| &entry.job.summary)
+            .filter(|job| query.kind.is_none_or(|kind| job.kind == kind))
+            .filter(|job| query.state.is_none_or(|state| job.state == state))
+            .cloned()
+            .collect();
+        summaries.sort_by(|a, b| b.created_at.cmp(&a.created_at).then_with(|| a.id.cmp(&b.id)));
+        summaries
+    }
+
+    /// Cancel a running job and wait (up to `wait`) until it stopped.
+    pub async fn cancel(&self, id: &str, key: &ApiKey, wait: Duration) -> Result<Job, CancelError> {
+        let mut finished = {
+            let jobs = self.jobs.lock().unwrap();
+            let entry = jobs.get(id).filter(|entry| visible(entry, key)).ok_or(CancelError::NotFound)?;
+            let scope = entry.job.summary.kind.scope();
+            if !key.has_scope(scope) {
+                return Err(CancelError::MissingScope(scope));
+            }
+            if entry.job.summary.state != JobState::Running {
+                return Err(CancelError::Finished(entry.job.summary.state));
+            }
+            if !entry.cancellable {
+                return Err(CancelError::NotCancellable);
+            }
+            log::info!("Cancelling job {} for key {}", id, key.name);
+            entry.cancel.send_replace(true);
+            entry.finished.subscribe()
+        };
+        let _ = tokio::time::timeout(wait, finished.wait_for(|finished| *finished)).await;
+        self.get(id, key).ok_or(CancelError::NotFound)
+    }
+
+    /// The job as it is now and, while it runs, a receiver for everything that happens from then on.
+    pub fn subscribe(&self, id: &str, key: &ApiKey) -> Option<(Job, Option<broadcast::Receiver<JobEvent>>)> {
+        let jobs = self.jobs.lock().unwrap();
+        let entry = jobs.get(id).filter(|entry| visible(entry, key))?;
+        let events = match entry.job.summary.state {
+            JobState::Running => Some(entry.events.subscribe()),
+            _ => None,
+        };
+        Some((entry.job.clone(), events))
+    }
+
+    /// Publish an event, appending `line` to the captured output.
+    fn record(&self, id: &str, line: Option<&str>, event: JobEvent) {
+        let max = config::get().jobs.max_output_bytes;
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(entry) = jobs.get_mut(id) else { return };
+        if let Some(line) = line {
+            append_output(&mut entry.job.output, line, max);
+        }
+        // Nobody listening is fine
+        let _ = entry.events.send(event);
+    }
+}
+
+/// Register a new running job, `JobStore::run` starts its work.
+fn insert(
+    jobs: &mut HashMap<String, Entry>,
+    kind: JobKind,
+    target: &str,
+    path: Option<&str>,
+    key: &ApiKey,
+) -> (Job, watch::Receiver<bool>) {
+    let id = generate_id();
+    let job = Job {
+        summary: JobSummary {
+            id: id.clone(),
+            kind,
+            state: JobState::Running,
+            target: target.to_string(),
+            key: key.name.clone(),
+            created_at: auth::unix_now(),
+            finished_at: None,
+        },
+        output: String::new(),
+        result: None,
+        error: None,
+    };
+    let (cancel, cancelled) = watch::channel(false);
+    prune(jobs, &config::get().jobs, auth::unix_now());
+    jobs.insert(
+        id,
+        Entry {
+            job: job.clone(),
+            path: path.map(|p| p.to_string()),
+            cancel,
+            cancellable: true,
+            finished: watch::channel(false).0,
+            events: broadcast::channel(EVENT_BUFFER).0,
+        },
+    );
+    (job, cancelled)
+}
+
+/// Drop finished jobs past the retention time, then the oldest finished ones above `max_jobs`.
+/// Running jobs are never dropped.
+fn prune(jobs: &mut HashMap<String, Entry>, jobs_config: &JobsConfig, now: u64) {
+    jobs.retain(|_, entry| {
+        entry
+            .job
+            .summary
+            .finished_at
+            .is_none_or(|finished_at| finished_at + jobs_config.retention_secs > now)
+    });
+    if jobs.len() <= jobs_config.max_jobs {
+        return;
+    }
+    let mut finished: Vec<(u64, String)> = jobs
+        .iter()
+        .filter_map(|(id, entry)| entry.job.summary.finished_at.map(|at| (at, id.clone())))
+        .collect();
+    finished.sort();
+    let excess = jobs.len() - jobs_config.max_jobs;
+    for (_, id) in finished.into_iter().take(excess) {
+        jobs.remove(&id);
+    }
+}
+
+/// Append a line to the captured output, dropping whole lines from the front above `max` bytes.
+fn append_output(output: &mut String, line: &str, max: usize) {
+    output.push_str(line);
+    output.push('\n');
+    if output.len() > max {
+        let excess = output.len() - max;
+        let cut = match output[excess..].find('\n') {
+            Some(pos) => excess + pos + 1,
+            None => output.len(),
+        };
+        output.drain(..cut);
+    }
+}
+
+fn record(line: Option<&str>, event: JobEvent) {
+    let _ = CURRENT_JOB.try_with(|handle| handle.store.record(&handle.id, line, event));
+}
+
+/// Run `work` in a task of its own under the current job. Cancelling the job or dropping the
+/// caller cannot stop it halfway, and the job refuses cancels until it is done. For steps that
+/// leave things broken when interrupted, like swapping a container.
+pub async fn detach<F>(work: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let handle = CURRENT_JOB.try_with(|handle| handle.clone()).ok();
+    let task = match handle.clone() {
+        Some(handle) => {
+            handle.store.set_cancellable(&handle.id, false);
+            let name = format!("job {} (uninterruptible step)", handle.id);
+            tokio::spawn(shutdown::track(name, CURRENT_JOB.scope(handle, work)))
+        }
+        None => tokio::spawn(shutdown::track("uninterruptible step".to_string(), work)),
+    };
+    let output = match task.await {
+        Ok(output) => output,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    };
+    if let Some(handle) = handle {
+        handle.store.set_cancellable(&handle.id, true);
+    }
+    output
+}
+
+/// Append a line of command output to the job the current task runs, if any.
+pub fn output(line: &str) {
+    record(Some(line), JobEvent::Output(line.to_string()));
+}
+
+/// Announce the next step of the current job.
+pub fn step(message: &str) {
+    record(Some(message), JobEvent::Step(message.to_string()));
+}
+
+/// Image pull progress of the current job. Only status changes make it into the output, not
+/// every progress update of a layer.
+pub fn progress(progress: PullProgress) {
+    let line = match (&progress.layer, progress.current) {
+        (_, Some(_)) => None,
+        (Some(layer), None) => Some(format!("{}: {}", layer, progress.status)),
+        (None, None) => Some(progress.status.clone()),
+    };
+    record(line.as_deref(), JobEvent::Progress(progress));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(created_at: u64, finished_at: Option<u64>) -> Entry {
+        let state = if finished_at.is_some() { JobState::Succeeded } else { JobState::Running };
+        Entry {
+            job: Job {
+                summary: JobSummary {
+                    id: String::new(),
+                    kind: JobKind::Compose,
+                    state,
+                    target: "/home/node_agent/app".to_string(),
+                    key: "deploy".to_string(),
+                    created_at,
+                    finished_at,
+                },
+                output: String::new(),
+                result: None,
+                error: None,
+            },
+            path: None,
+            cancel: watch::channel(false).0,
+            cancellable: true,
+            finished: watch::channel(false).0,
+            events: broadcast::channel(1).0,
+        }
+    }
+
+    fn jobs(entries: Vec<(&str, Entry)>) -> HashMap<String, Entry> {
+        entries.into_iter().map(|(id, entry)| (id.to_string(), entry)).collect()
+    }
+
+    fn ids(jobs: &HashMap<String, Entry>) -> Vec<&str> {
+        let mut ids: Vec<&str> = jobs.keys().map(String::as_str).collect();
+        ids.sort();
+        ids
+    }
+
+    fn limits(retention_secs: u64, max_jobs: usize) -> JobsConfig {
+        JobsConfig { retention_secs, max_jobs, ..JobsConfig::default() }
+    }
+
+    #[test]
+    fn prune_drops_expired_jobs_first() {
+        let mut store = jobs(vec![
+            ("expired", entry(0, Some(10))),
+            ("recent", entry(0, Some(950))),
+            ("old-running", entry(0, None)),
+        ]);
+        prune(&mut store, &limits(100, 10), 1000);
+        assert_eq!(ids(&store), vec!["old-running", "recent"]);
+    }
+
+    #[test]
+    fn prune_drops_the_oldest_finished_jobs_above_max_jobs() {
+        let mut store = jobs(vec![
+            ("a", entry(0, Some(900))),
+            ("b", entry(0, Some(920))),
+            ("c", entry(0, Some(910))),
+            ("running", entry(0, None)),
+        ]);
+        prune(&mut store, &limits(1000, 2), 1000);
+        assert_eq!(ids(&store), vec!["b", "running"]);
+    }
+
+    #[test]
+    fn prune_never_drops_running_jobs() {
+        let mut store = jobs(vec![
+            ("one", entry(0, None)),
+            ("two", entry(1, None)),
+            ("three", entry(2, None)),
+            ("done", entry(0, Some(999))),
+        ]);
+        prune(&mut store, &limits(1000, 1), 1000);
+        assert_eq!(ids(&store), vec!["one", "three", "two"]);
+    }
+
+    #[test]
+    fn output_drops_whole_lines_from_the_front() {
+        let mut output = String::new();
+        append_output(&mut output, "first", 16);
+        append_output(&mut output, "second", 16);
+        assert_eq!(output, "first\nsecond\n");
+        append_output(&mut output, "third", 16);
+        assert_eq!(output, "second\nthird\n");
+
+        // A single line above the limit leaves nothing rather than half a line
+        append_output(&mut output, &"x".repeat(20), 16);
+        assert_eq!(output, "");
+    }
+}
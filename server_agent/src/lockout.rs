@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+/// Addresses tracked at most; beyond that the one that failed longest ago and is not locked out
+/// makes room.
+const MAX_TRACKED: usize = 10_000;
+
+/// Failed authentication attempts per client address. After `auth.lockout_threshold` failures the
+/// address is locked out, for twice as long with every further failure. IPv6 clients are tracked
+/// per /64, which is what a single host usually gets.
+pub struct Lockout {
+    attempts: Mutex<HashMap<IpAddr, Attempts>>,
+    threshold: u32,
+    base: Duration,
+    max: Duration,
+}
+
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Lockout {
+    pub fn new() -> Lockout {
+        let auth_config = &config::get().auth;
+        Lockout::with_limits(
+            auth_config.lockout_threshold,
+            Duration::from_secs(auth_config.lockout_base_secs),
+            Duration::from_secs(auth_config.lockout_max_secs),
+        )
+    }
+
+    fn with_limits(threshold: u32, base: Duration, max: Duration) -> Lockout {
+        Lockout {
+            attempts: Mutex::new(HashMap::new()),
+            threshold,
+            base,
+            max,
+        }
+    }
+
+    /// Remaining lockout of `ip`, if it is locked out.
+    pub fn locked(&self, ip: IpAddr) -> Option<Duration> {
+        let attempts = self.attempts.lock().unwrap();
+        let locked_until = attempts.get(&client(ip))?.locked_until?;
+        locked_until.checked_duration_since(Instant::now())
+    }
+
+    /// Record a failed attempt. Returns the lockout if `ip` is locked out now.
+    pub fn failure(&self, ip: IpAddr) -> Option<Duration> {
+        let max = self.max;
+        let now = Instant::now();
+
+        let mut attempts = self.attempts.lock().unwrap();
+        // Forget addresses that stayed quiet for a full maximum lockout
+        attempts.retain(|_, a| now.duration_since(a.last_failure) < max || a.locked_until.is_some_and(|t| t > now));
+        let ip = client(ip);
+        if attempts.len() >= MAX_TRACKED && !attempts.contains_key(&ip) {
+            let oldest = attempts
+                .iter()
+                .filter(|(_, a)| a.locked_until.is_none_or(|t| t <= now))
+                .min_by_key(|(_, a)| a.last_failure)
+                .map(|(ip, _)| *ip);
+            match oldest {
+                Some(oldest) => {
+                    attempts.remove(&oldest);
+                }
+                // Everybody tracked is locked out, this address has to wait its turn
+                None => return None,
+            }
+        }
+
+        let entry = attempts.entry(ip).or_insert(Attempts {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        entry.failures += 1;
+        entry.last_failure = now;
+        if entry.failures < self.threshold {
+            return None;
+        }
+
+        let doublings = entry.failures - self.threshold;
+        let lockout = self
+            .base
+            .saturating_mul(1u32.checked_shl(doublings).unwrap_or(u32::MAX))
+            .min(max);
+        entry.locked_until = Some(now + lockout);
+        Some(lockout)
+    }
+
+    pub fn success(&self, ip: IpAddr) {
+        self.attempts.lock().unwrap().remove(&client(ip));
+    }
+}
+
+/// The key an address is tracked under: IPv4 addresses as they are (also when mapped into IPv6),
+/// IPv6 addresses by their /64 network.
+fn client(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(v6.to_bits() & !u128::from(u64::MAX))),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[test]
+    fn locks_out_after_the_threshold() {
+        let lockout = Lockout::with_limits(3, Duration::from_secs(30), Duration::from_secs(3600));
+        assert_eq!(lockout.failure(ip(1)), None);
+        assert_eq!(lockout.failure(ip(1)), None);
+        assert_eq!(lockout.locked(ip(1)), None);
+        assert_eq!(lockout.failure(ip(1)), Some(Duration::from_secs(30)));
+        assert!(lockout.locked(ip(1)).is_some_and(|left| left <= Duration::from_secs(30)));
+        // Other addresses are not affected
+        assert_eq!(lockout.locked(ip(2)), None);
+        assert_eq!(lockout.failure(ip(2)), None);
+    }
+
+    #[test]
+    fn doubles_up_to_the_maximum() {
+        let lockout = Lockout::with_limits(1, Duration::from_secs(30), Duration::from_secs(200));
+        let lockouts: Vec<u64> = (0..5).map(|_| lockout.failure(ip(1)).unwrap().as_secs()).collect();
+        assert_eq!(lockouts, [30, 60, 120, 200, 200]);
+    }
+
+    #[test]
+    fn backoff_does_not_overflow() {
+        let max = Duration::from_secs(3600);
+        let lockout = Lockout::with_limits(1, Duration::from_secs(u64::MAX / 2), max);
+        for _ in 0..70 {
+            assert_eq!(lockout.failure(ip(1)), Some(max));
+        }
+    }
+
+    #[test]
+    fn tracks_ipv6_clients_per_64() {
+        let lockout = Lockout::with_limits(2, Duration::from_secs(30), Duration::from_secs(3600));
+        let first: IpAddr = "2001:db8:1:2::1".parse().unwrap();
+        let same_network: IpAddr = "2001:db8:1:2:ffff:ffff:ffff:ffff".parse().unwrap();
+        let other_network: IpAddr = "2001:db8:1:3::1".parse().unwrap();
+        assert_eq!(lockout.failure(first), None);
+        assert!(lockout.failure(same_network).is_some());
+        assert!(lockout.locked(first).is_some());
+        assert_eq!(lockout.locked(other_network), None);
+
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        assert_eq!(client(mapped), ip(1));
+        assert_eq!(client(ip(1)), ip(1));
+    }
+
+    #[test]
+    fn forgets_quiet_addresses() {
+        let lockout = Lockout::with_limits(5, Duration::from_millis(1), Duration::from_millis(10));
+        for last in 1..=3 {
+            lockout.failure(ip(last));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        lockout.failure(ip(4));
+        let tracked: Vec<IpAddr> = lockout.attempts.lock().unwrap().keys().copied().collect();
+        assert_eq!(tracked, vec![ip(4)]);
+    }
+
+    #[test]
+    fn caps_the_tracked_addresses() {
+        let lockout = Lockout::with_limits(2, Duration::from_secs(30), Duration::from_secs(3600));
+        for n in 0..MAX_TRACKED as u32 {
+            lockout.failure(IpAddr::from((0x0a00_0000 + n).to_be_bytes()));
+        }
+        // One of the others makes room
+        assert_eq!(lockout.failure(ip(1)), None);
+        let attempts = lockout.attempts.lock().unwrap();
+        assert_eq!(attempts.len(), MAX_TRACKED);
+        assert!(attempts.contains_key(&ip(1)));
+    }
+
+    #[test]
+    fn success_resets_the_count() {
+        let lockout = Lockout::with_limits(2, Duration::from_secs(30), Duration::from_secs(3600));
+        assert_eq!(lockout.failure(ip(1)), None);
+        assert!(lockout.failure(ip(1)).is_some());
+        lockout.success(ip(1));
+        assert_eq!(lockout.locked(ip(1)), None);
+        assert_eq!(lockout.failure(ip(1)), None);
+    }
+}
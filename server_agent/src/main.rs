@@ -1,52 +1,165 @@
 use std::net::SocketAddr;
-use std::env;
-use std::fs;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 
-use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
 
+mod audit;
+mod auth;
+mod config;
+mod error;
+mod image_ref;
+mod jobs;
+mod lockout;
+mod openapi;
+mod registries;
 mod router;
+mod routes;
 mod util;
 mod services;
+mod shutdown;
+mod signature;
+mod sse;
+mod state;
+mod tls;
 
+/// Pause before accepting again when the process ran out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// Clients that stall the handshake would hold their connection open forever.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr_string = match env::args().nth(1) {
-        Some(addr) => addr,
-        None => "127.0.0.1:8080".to_string(),
-    };
-    let key_file_path = match env::args().nth(2) {
-        Some(file_path) => file_path,
-        None => "/home/node_agent/.key.hash".to_string(),
+async fn main() -> ExitCode {
+    let config = match config::load() {
+        Ok(config) => config::init(config),
+        Err(e) => {
+            eprintln!("Startup failed: {}", e);
+            return ExitCode::FAILURE;
+        }
     };
-    let key_hash = match fs::read_to_string(key_file_path) {
-        Ok(key_hash) => key_hash,
+    util::init_logger(config.log_level_filter());
+
+    match run(config).await {
+        Ok(()) => ExitCode::SUCCESS,
         Err(e) => {
-            println!("Key could not be read! Setting empty key...");
-            println!("{}", e);
-            String::new()
-        },
+            log::error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(config: &'static config::Config) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let keys = Arc::new(auth::KeyStore::load(&config.key_file)?);
+    auth::watch(keys.clone(), Duration::from_secs(config.auth.reload_interval_secs));
+    let audit = if config.audit.enabled {
+        Some(Arc::new(audit::AuditLog::open(&config.audit.file, config.audit.max_file_bytes)?))
+    } else {
+        None
+    };
+    let state = Arc::new(state::AppState {
+        keys,
+        nonces: signature::NonceCache::new(),
+        lockout: lockout::Lockout::new(),
+        audit,
+        jobs: Arc::new(jobs::JobStore::new()),
+        registries: Arc::new(registries::RegistryStore::load(&config.docker.registries_file)?),
+    });
+    tokio::spawn(services::volumes::remove_orphaned_helpers());
+
+    let tls_acceptor = if config.tls.enabled {
+        Some(tls::acceptor(&config.tls).map_err(|e| format!("TLS setup failed: {}", e))?)
+    } else {
+        None
     };
 
-    let addr = addr_string.parse::<SocketAddr>()?;
-    let listener = TcpListener::bind(addr).await?;
+    let listener = TcpListener::bind(config.listen).await?;
+    log::info!(
+        "Listening on {} ({})",
+        config.listen,
+        if tls_acceptor.is_some() { "https" } else { "http" }
+    );
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let graceful = GracefulShutdown::new();
 
     loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
-        let key_hash = key_hash.clone();
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+        };
+        let (stream, remote_addr) = match accepted {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("Cannot accept connection: {}", e);
+                // Out of file descriptors, give running requests a moment to close theirs
+                if matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) {
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+                continue;
+            }
+        };
+        let state = state.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        let watcher = graceful.watcher();
 
         // Spawn a tokio task to serve multiple connections concurrently
         tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(io, service_fn(move |req| router::router(req, key_hash.clone())))
-                .await
-            {
-                eprintln!("Error serving connection: {:?}", err);
+            match tls_acceptor {
+                Some(acceptor) => match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(tls_stream)) => serve_connection(tls_stream, remote_addr, state, watcher).await,
+                    Ok(Err(err)) => log::warn!("TLS handshake with {} failed: {}", remote_addr, err),
+                    Err(_) => log::warn!("TLS handshake with {} timed out", remote_addr),
+                },
+                None => serve_connection(stream, remote_addr, state, watcher).await,
             }
         });
     }
+
+    // Stop accepting, let idle keep-alive connections close and wait for running requests
+    drop(listener);
+    let timeout = Duration::from_secs(config.timeouts.shutdown_secs);
+    log::info!("Shutting down, waiting up to {}s for running operations", timeout.as_secs());
+    shutdown::begin();
+    let drained = tokio::time::timeout(timeout, async {
+        graceful.shutdown().await;
+        shutdown::idle().await;
+    })
+    .await
+    .is_ok();
+
+    if !drained {
+        shutdown::abort_all();
+        // Give aborted handlers a moment to kill their child processes and respond
+        let _ = tokio::time::timeout(Duration::from_secs(5), shutdown::idle()).await;
+    }
+    log::info!("Shutdown complete");
+    Ok(())
+}
+
+async fn serve_connection<I>(stream: I, remote_addr: SocketAddr, state: Arc<state::AppState>, watcher: Watcher)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = TokioIo::new(stream);
+    // HTTP/1 only as before; the upgradeable connection lets the container terminal switch to a WebSocket
+    let builder = auto::Builder::new(TokioExecutor::new()).http1_only();
+    let connection = builder.serve_connection_with_upgrades(
+        io,
+        service_fn(move |req| {
+            let name = format!("{} {}", req.method(), req.uri().path());
+            shutdown::track(name, router::router(req, remote_addr, state.clone()))
+        }),
+    );
+    if let Err(err) = watcher.watch(connection).await {
+        log::warn!("Error serving connection: {:?}", err);
+    }
 }
@@ -0,0 +1,207 @@
+use std::sync::OnceLock;
+
+use schemars::generate::SchemaSettings;
+use schemars::SchemaGenerator;
+use serde_json::{json, Map, Value};
+
+use crate::error::ErrorBody;
+use crate::routes::{self, RequestBody, ResponseBody, Route, PATH_PARAMS};
+
+/// The OpenAPI document describing `routes::ROUTES`, built on first use.
+pub fn document() -> &'static str {
+    static DOCUMENT: OnceLock<String> = OnceLock::new();
+    DOCUMENT.get_or_init(|| build().to_string())
+}
+
+fn settings() -> SchemaSettings {
+    SchemaSettings::draft2020_12().with(|s| {
+        s.definitions_path = "/components/schemas".into();
+        s.meta_schema = None;
+    })
+}
+
+fn path_parameters(route: &Route) -> Vec<Value> {
+    route
+        .path
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| {
+            let pattern = PATH_PARAMS.iter().find(|(n, _)| *n == name).map(|(_, p)| *p);
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string", "pattern": pattern },
+            })
+        })
+        .collect()
+}
+
+/// One parameter per property of the (inlined) query struct schema.
+fn query_parameters(route: &Route, generator: &mut SchemaGenerator) -> Vec<Value> {
+    let Some(query) = route.query else {
+        return Vec::new();
+    };
+    let schema = query(generator).to_value();
+    let required: Vec<&str> = schema["required"]
+        .as_array()
+        .map(|r| r.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default();
+    let Some(properties) = schema["properties"].as_object() else {
+        return Vec::new();
+    };
+    properties
+        .iter()
+        .map(|(name, property)| {
+            let mut property = property.clone();
+            let description = property.as_object_mut().and_then(|p| p.remove("description"));
+            let mut parameter = json!({
+                "name": name,
+                "in": "query",
+                "required": required.contains(&name.as_str()),
+                "schema": property,
+            });
+            if let Some(description) = description {
+                parameter["description"] = description;
+            }
+            parameter
+        })
+        .collect()
+}
+
+fn operation(route: &Route, generator: &mut SchemaGenerator, query_generator: &mut SchemaGenerator) -> Value {
+    let mut parameters = path_parameters(route);
+    parameters.extend(query_parameters(route, query_generator));
+
+    let (status, success) = match &route.response {
+        ResponseBody::Json(schema) => ("200", json!({
+            "description": "OK",
+            "content": { "application/json": { "schema": schema(generator) } },
+        })),
+        ResponseBody::Accepted(schema) => ("202", json!({
+            "description": "Accepted, poll the job in the Location header",
+            "content": { "application/json": { "schema": schema(generator) } },
+        })),
+        ResponseBody::Text => ("200", json!({
+            "description": "OK",
+            "content": { "text/plain": { "schema": { "type": "string" } } },
+        })),
+        ResponseBody::Tar => ("200", json!({
+            "description": "OK",
+            "content": { "application/x-tar": { "schema": { "type": "string", "format": "binary" } } },
+        })),
+        ResponseBody::EventStream(schema) => ("200", json!({
+            "description": "Server-Sent Events",
+            "content": { "text/event-stream": { "schema": schema(generator) } },
+        })),
+        ResponseBody::JsonOrEventStream(schema, events) => ("200", json!({
+            "description": "OK, Server-Sent Events if the query asks for a stream",
+            "content": {
+                "application/json": { "schema": schema(generator) },
+                "text/event-stream": { "schema": events(generator) },
+            },
+        })),
+        ResponseBody::WebSocket(schema) => ("101", json!({
+            "description": "Switching Protocols to a WebSocket",
+            "x-websocket-messages": schema(generator),
+        })),
+    };
+
+    let mut operation = json!({
+        "operationId": format!("{:?}", route.endpoint),
+        "summary": route.summary,
+        "x-required-scope": route.scope,
+        "parameters": parameters,
+        "responses": {
+            status: success,
+            "default": { "$ref": "#/components/responses/Error" },
+        },
+    });
+    let body = match &route.body {
+        RequestBody::None => None,
+        RequestBody::Json(schema) => Some(("application/json", schema(generator).to_value(), true)),
+        RequestBody::OptionalJson(schema) => Some(("application/json", schema(generator).to_value(), false)),
+        RequestBody::Tar => Some(("application/x-tar", json!({ "type": "string", "format": "binary" }), true)),
+    };
+    if let Some((content_type, schema, required)) = body {
+        operation["requestBody"] = json!({
+            "required": required,
+            "content": { content_type: { "schema": schema } },
+        });
+    }
+    operation
+}
+
+fn build() -> Value {
+    let mut generator = settings().into_generator();
+    let mut query_generator = settings().with(|s| s.inline_subschemas = true).into_generator();
+
+    let mut paths = Map::new();
+    for route in routes::ROUTES {
+        let operation = operation(route, &mut generator, &mut query_generator);
+        let item = paths.entry(route.path).or_insert_with(|| json!({}));
+        item[route.method.as_str().to_lowercase()] = operation;
+    }
+
+    for job_result in routes::JOB_RESULTS {
+        job_result(&mut generator);
+    }
+    let error_schema = generator.subschema_for::<ErrorBody>();
+    let schemas = generator.take_definitions(true);
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "server_agent",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Node agent of the deployment manager",
+        },
+        "security": [{ "apiKey": [] }, { "signature": [] }],
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "responses": {
+                "Error": {
+                    "description": "Error",
+                    "content": { "application/json": { "schema": error_schema } },
+                },
+            },
+            "securitySchemes": {
+                "apiKey": { "type": "apiKey", "in": "header", "name": "X-Api-Key" },
+                "signature": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "X-Signature",
+                    "description": "HMAC-SHA256 request signature, sent with X-Key-Name, X-Timestamp and X-Nonce",
+                },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_every_route() {
+        let document: Value = serde_json::from_str(document()).unwrap();
+        assert_eq!(document["openapi"], "3.1.0");
+        for route in routes::ROUTES {
+            let operation = &document["paths"][route.path][route.method.as_str().to_lowercase()];
+            assert!(operation.is_object(), "{} {} is missing", route.method, route.path);
+        }
+        let operations: usize = document["paths"].as_object().unwrap().values().map(|item| item.as_object().unwrap().len()).sum();
+        assert_eq!(operations, routes::ROUTES.len());
+    }
+
+    #[test]
+    fn documents_path_params() {
+        let document: Value = serde_json::from_str(document()).unwrap();
+        let parameters = document["paths"]["/docker/container/{id}/inspect"]["get"]["parameters"].as_array().unwrap();
+        let id = parameters.iter().find(|p| p["name"] == "id").unwrap();
+        assert_eq!(id["in"], "path");
+        assert_eq!(id["required"], true);
+        assert_eq!(id["schema"]["pattern"], PATH_PARAMS[0].1);
+    }
+}
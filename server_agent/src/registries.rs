@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use base64::Engine;
+use bollard::auth::DockerCredentials;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::auth;
+use crate::image_ref::{self, ImageRef};
+use crate::util;
+
+/// Docker Hub, as images without a registry host resolve to.
+const DOCKER_HUB: &str = "docker.io";
+/// The server address the docker CLI uses for Docker Hub credentials.
+const DOCKER_HUB_ADDRESS: &str = "https://index.docker.io/v1/";
+const MASKED_PASSWORD: &str = "********";
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Registry {
+    host: String,
+    username: String,
+    password: String,
+    added_at: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistryFile {
+    #[serde(default)]
+    registries: Vec<Registry>,
+}
+
+/// Stored credentials as the API shows them, never with the password.
+#[derive(Serialize, JsonSchema)]
+pub struct RegistryInfo {
+    /// Registry host with optional port, `docker.io` for Docker Hub
+    pub host: String,
+    pub username: String,
+    /// Always masked
+    pub password: String,
+    pub added_at: u64,
+}
+
+impl From<&Registry> for RegistryInfo {
+    fn from(registry: &Registry) -> Self {
+        RegistryInfo {
+            host: registry.host.clone(),
+            username: registry.username.clone(),
+            password: MASKED_PASSWORD.to_string(),
+            added_at: registry.added_at,
+        }
+    }
+}
+
+/// Turn what users paste as a registry (`https://ghcr.io`, `registry.local:5000/`) into the host
+/// images refer to. The Docker Hub aliases all become `docker.io`.
+pub fn normalize_host(host: &str) -> Result<String, String> {
+    let host = host.trim();
+    let host = host
+        .strip_prefix("https://")
+        .or_else(|| host.strip_prefix("http://"))
+        .unwrap_or(host);
+    let host = host.split('/').next().unwrap_or_default().to_ascii_lowercase();
+    match host.as_str() {
+        "index.docker.io" | "registry-1.docker.io" | "registry.hub.docker.com" => return Ok(DOCKER_HUB.to_string()),
+        _ => {}
+    }
+    if !image_ref::is_registry_host(&host) {
+        return Err(format!("invalid registry host '{}'", host));
+    }
+    Ok(host)
+}
+
+fn server_address(host: &str) -> &str {
+    if host == DOCKER_HUB {
+        DOCKER_HUB_ADDRESS
+    } else {
+        host
+    }
+}
+
+fn read_registries(path: &Path) -> Result<Vec<Registry>, String> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        // Nothing stored yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("cannot read registry file {}: {}", path.display(), e)),
+    };
+    let file: RegistryFile =
+        toml::from_str(&content).map_err(|e| format!("invalid registry file {}: {}", path.display(), e))?;
+    for registry in &file.registries {
+        if normalize_host(&registry.host).as_deref() != Ok(registry.host.as_str()) {
+            return Err(format!("registry file {}: invalid host {}", path.display(), registry.host));
+        }
+    }
+    Ok(file.registries)
+}
+
+/// Registry credentials by host, kept in the registry file with owner-only permissions.
+pub struct RegistryStore {
+    path: PathBuf,
+    registries: RwLock<BTreeMap<String, Registry>>,
+}
+
+impl RegistryStore {
+    pub fn load(path: &Path) -> Result<RegistryStore, String> {
+        let registries = read_registries(path)?
+            .into_iter()
+            .map(|registry| (registry.host.clone(), registry))
+            .collect();
+        Ok(RegistryStore {
+            path: path.to_path_buf(),
+            registries: RwLock::new(registries),
+        })
+    }
+
+    pub fn list(&self) -> Vec<RegistryInfo> {
+        self.registries.read().unwrap().values().map(RegistryInfo::from).collect()
+    }
+
+    /// Add or replace the credentials of a registry. `host` has to be normalized already.
+    pub fn add(&self, host: &str, username: &str, password: &str) -> Result<RegistryInfo, String> {
+        let registry = Registry {
+            host: host.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            added_at: auth::unix_now(),
+        };
+        let info = RegistryInfo::from(&registry);
+        let mut registries = self.registries.write().unwrap();
+        let mut updated = registries.clone();
+        updated.insert(host.to_string(), registry);
+        self.write(&updated)?;
+        *registries = updated;
+        Ok(info)
+    }
+
+    /// Remove the credentials of a registry, None if there were none.
+    pub fn remove(&self, host: &str) -> Result<Option<RegistryInfo>, String> {
+        let mut registries = self.registries.write().unwrap();
+        let mut updated = registries.clone();
+        let Some(removed) = updated.remove(host) else {
+            return Ok(None);
+        };
+        self.write(&updated)?;
+        *registries = updated;
+        Ok(Some(RegistryInfo::from(&removed)))
+    }
+
+    fn write(&self, registries: &BTreeMap<String, Registry>) -> Result<(), String> {
+        let file = RegistryFile {
+            registries: registries.values().cloned().collect(),
+        };
+        let content = toml::to_string(&file).map_err(|e| format!("cannot serialize registries: {}", e))?;
+        util::write_private(&self.path, content.as_bytes())
+    }
+
+    /// Credentials for pulling `image` through the Docker API.
+    pub fn credentials(&self, image: &ImageRef) -> Option<DockerCredentials> {
+        let host = match &image.registry {
+            Some(registry) => normalize_host(registry).ok()?,
+            None => DOCKER_HUB.to_string(),
+        };
+        let registries = self.registries.read().unwrap();
+        let registry = registries.get(&host)?;
+        Some(DockerCredentials {
+            username: Some(registry.username.clone()),
+            password: Some(registry.password.clone()),
+            serveraddress: Some(server_address(&registry.host).to_string()),
+            ..Default::default()
+        })
+    }
+
+    /// A throwaway `DOCKER_CONFIG` directory with all stored credentials, for `docker compose`.
+    /// None when there are no credentials, so compose keeps using the user's config.
+    pub fn docker_config_dir(&self) -> Result<Option<DockerConfigDir>, String> {
+        let user_dir = user_docker_config();
+        let user_config = user_dir.as_ref().and_then(|dir| read_docker_config(&dir.join("config.json")));
+        let config = {
+            let registries = self.registries.read().unwrap();
+            if registries.is_empty() {
+                return Ok(None);
+            }
+            docker_config(user_config.as_ref(), &registries)
+        };
+
+        let mut suffix = [0u8; 8];
+        getrandom::getrandom(&mut suffix).map_err(|e| format!("cannot create docker config dir: {}", e))?;
+        let path = env::temp_dir().join(format!("server_agent-docker-{}", hex::encode(suffix)));
+        fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&path)
+            .map_err(|e| format!("cannot create {}: {}", path.display(), e))?;
+        let dir = DockerConfigDir(path);
+
+        // `docker compose` is a CLI plugin, which may be installed in the user's config dir
+        if let Some(plugins) = user_dir.map(|d| d.join("cli-plugins")).filter(|p| p.is_dir()) {
+            std::os::unix::fs::symlink(&plugins, dir.path().join("cli-plugins"))
+                .map_err(|e| format!("cannot link {}: {}", plugins.display(), e))?;
+        }
+        util::write_private(&dir.path().join("config.json"), config.to_string().as_bytes())?;
+        Ok(Some(dir))
+    }
+}
+
+/// The user's docker CLI config, if there is a readable one.
+fn read_docker_config(path: &Path) -> Option<Value> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            log::warn!("Cannot read {}, using the stored registry credentials only: {}", path.display(), e);
+            return None;
+        }
+    };
+    match serde_json::from_str(&content) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            log::warn!("Invalid {}, using the stored registry credentials only: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// The config compose runs with: the user's `auths`, `credsStore` and `credHelpers`, with the
+/// stored credentials replacing theirs for the same host.
+fn docker_config(user: Option<&Value>, registries: &BTreeMap<String, Registry>) -> Value {
+    let section = |name: &str| user.and_then(|u| u.get(name)).and_then(Value::as_object).cloned().unwrap_or_default();
+    let stored = |address: &str| normalize_host(address).is_ok_and(|host| registries.contains_key(&host));
+    let mut auths = section("auths");
+    auths.retain(|address, _| !stored(address));
+    let mut helpers = section("credHelpers");
+    helpers.retain(|address, _| !stored(address));
+    let creds_store = user.and_then(|u| u.get("credsStore")).and_then(Value::as_str).filter(|s| !s.is_empty());
+
+    for registry in registries.values() {
+        let address = server_address(&registry.host).to_string();
+        let basic = format!("{}:{}", registry.username, registry.password);
+        let auth = base64::engine::general_purpose::STANDARD.encode(basic);
+        // An empty helper makes the CLI take this host from `auths` despite the `credsStore`
+        if creds_store.is_some() {
+            helpers.insert(address.clone(), json!(""));
+        }
+        auths.insert(address, json!({ "auth": auth }));
+    }
+
+    let mut config = json!({ "auths": auths });
+    if let Some(store) = creds_store {
+        config["credsStore"] = json!(store);
+    }
+    if !helpers.is_empty() {
+        config["credHelpers"] = Value::Object(helpers);
+    }
+    config
+}
+
+fn user_docker_config() -> Option<PathBuf> {
+    env::var_os("DOCKER_CONFIG")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".docker")))
+}
+
+/// A temporary docker config directory, removed when dropped.
+pub struct DockerConfigDir(PathBuf);
+
+impl DockerConfigDir {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for DockerConfigDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            log::warn!("Cannot remove {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(hosts: &[&str]) -> BTreeMap<String, Registry> {
+        hosts
+            .iter()
+            .map(|host| {
+                let registry = Registry {
+                    host: host.to_string(),
+                    username: "bot".to_string(),
+                    password: "secret".to_string(),
+                    added_at: 0,
+                };
+                (host.to_string(), registry)
+            })
+            .collect()
+    }
+
+    // base64 of "bot:secret"
+    const BOT_AUTH: &str = "Ym90OnNlY3JldA==";
+
+    #[test]
+    fn normalizes_hosts() {
+        for alias in ["docker.io", "index.docker.io", "https://index.docker.io/v1/", "registry-1.docker.io", "Registry.Hub.Docker.com"] {
+            assert_eq!(normalize_host(alias), Ok("docker.io".to_string()), "{}", alias);
+        }
+        assert_eq!(normalize_host("https://ghcr.io"), Ok("ghcr.io".to_string()));
+        assert_eq!(normalize_host("ghcr.io/"), Ok("ghcr.io".to_string()));
+        assert_eq!(normalize_host(" http://registry.local:5000/v2/ "), Ok("registry.local:5000".to_string()));
+        assert!(normalize_host("").is_err());
+        assert!(normalize_host("https://").is_err());
+        assert!(normalize_host("not a host").is_err());
+    }
+
+    #[test]
+    fn server_addresses() {
+        assert_eq!(server_address("docker.io"), DOCKER_HUB_ADDRESS);
+        assert_eq!(server_address("ghcr.io"), "ghcr.io");
+        assert_eq!(server_address("registry.local:5000"), "registry.local:5000");
+    }
+
+    #[test]
+    fn generates_auths() {
+        let config = docker_config(None, &stored(&["docker.io", "ghcr.io"]));
+        assert_eq!(
+            config,
+            json!({
+                "auths": {
+                    "https://index.docker.io/v1/": { "auth": BOT_AUTH },
+                    "ghcr.io": { "auth": BOT_AUTH },
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn merges_the_user_config() {
+        let user = json!({
+            "auths": {
+                "https://index.docker.io/v1/": { "auth": "dXNlcjpodWI=" },
+                "registry.local:5000": { "auth": "dXNlcjpsb2NhbA==" },
+            },
+            "credHelpers": { "ghcr.io": "gh", "123.dkr.ecr.eu-west-1.amazonaws.com": "ecr-login" },
+            "proxies": { "default": { "httpProxy": "http://proxy:3128" } },
+        });
+        let config = docker_config(Some(&user), &stored(&["docker.io", "ghcr.io"]));
+        assert_eq!(
+            config,
+            json!({
+                "auths": {
+                    "https://index.docker.io/v1/": { "auth": BOT_AUTH },
+                    "registry.local:5000": { "auth": "dXNlcjpsb2NhbA==" },
+                    "ghcr.io": { "auth": BOT_AUTH },
+                },
+                "credHelpers": { "123.dkr.ecr.eu-west-1.amazonaws.com": "ecr-login" },
+            })
+        );
+    }
+
+    #[test]
+    fn stored_hosts_bypass_the_creds_store() {
+        let user = json!({ "auths": { "quay.io": {} }, "credsStore": "desktop" });
+        let config = docker_config(Some(&user), &stored(&["ghcr.io"]));
+        assert_eq!(
+            config,
+            json!({
+                "auths": { "quay.io": {}, "ghcr.io": { "auth": BOT_AUTH } },
+                "credsStore": "desktop",
+                "credHelpers": { "ghcr.io": "" },
+            })
+        );
+    }
+}
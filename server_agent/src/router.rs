@@ -1,103 +1,469 @@
 use std::collections::HashMap;
 use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
 
-use http_body_util::Full;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::header::HeaderValue;
 use hyper::{Method, Request, Response};
-use hyper::body::Bytes;
-use bcrypt::{verify};
+use hyper::body::{Bytes, Incoming};
+use hyper::http::request::Parts;
 
-use regex::Regex;
+use serde::de::DeserializeOwned;
 
+use crate::audit::{self, AuditEntry, Outcome};
+use crate::auth::{self, ApiKey, Scope};
+use crate::config;
+use crate::error::{ApiError, ErrorCode};
+use crate::openapi;
+use crate::routes::{self, Endpoint, PathParams, PathQuery, RouteMatch};
 use crate::services::{self};
+use crate::signature::{self, SignatureError};
+use crate::state::AppState;
+use crate::util;
 
+type Body = BoxBody<Bytes, Infallible>;
+
+/// The request body: read before authentication for signed requests, after it otherwise.
+enum Payload {
+    Read(Bytes),
+    Unread(Incoming),
+}
+
+// Compose files, container configs and signed bodies are buffered, they are small
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
 
 pub async fn router(
-    request: Request<hyper::body::Incoming>,
-    key_hash: String
-) -> Result<Response<Full<Bytes>>, Infallible> {
-    // Auth check
-    let token_result = match request.headers().get("X-Api-Key") {
-        Some(t) => t,
-        None => return forbidden()
+    request: Request<Incoming>,
+    remote_addr: SocketAddr,
+    state: Arc<AppState>
+) -> Result<Response<Body>, Infallible> {
+    let started = Instant::now();
+    let (parts, body) = request.into_parts();
+    let client_ip = util::client_ip(remote_addr.ip(), &parts.headers);
+    if let Some(remaining) = state.lockout.locked(client_ip) {
+        return locked_out(remaining).map(boxed);
+    }
+
+    // A signature covers the body, so a signed request is read up front, with the small limit.
+    // Everything else authenticates before the agent reads a byte of the body.
+    let payload = if parts.headers.contains_key(signature::SIGNATURE_HEADER) {
+        match read_body(body, MAX_BODY_BYTES).await {
+            Ok(body) => Payload::Read(body),
+            Err(e) => return Ok(boxed(e.response())),
+        }
+    } else {
+        Payload::Unread(body)
     };
-    let token = match token_result.to_str() {
-        Ok(t) => t,
-        Err(_) => return forbidden()
+
+    // Auth check
+    let authenticated = if let Payload::Read(body) = &payload {
+        verify_signed(&parts, body, &state).map_err(|e| {
+            log::warn!("{} {} from {}: signature rejected: {}", parts.method, parts.uri.path(), client_ip, e);
+            signature_rejected(e)
+        })
+    } else {
+        match parts.headers.get("X-Api-Key").and_then(|t| t.to_str().ok()) {
+            Some(token) => auth::verify_token(state.keys.clone(), token.to_string()).await.ok_or_else(forbidden),
+            None => Err(forbidden())
+        }
     };
-    let valid = match verify(token, &key_hash) {
-        Ok(res) => res,
-        Err(e) => {
-            println!("Hash verification error!");
-            println!("{}", e);
-            return forbidden()
+    let key = match authenticated {
+        Ok(key) => {
+            state.lockout.success(client_ip);
+            key
+        }
+        Err(res) => {
+            if let Some(lockout) = state.lockout.failure(client_ip) {
+                log::warn!("Locking out {} for {}s after repeated failed authentication", client_ip, lockout.as_secs());
+            }
+            return res.map(boxed);
         }
     };
-    if !valid {
-        return forbidden();
-    }
 
     // Deconstuct request path and params
-    let path = request.uri().path();
-    let params: HashMap<String, String> = request
-        .uri()
+    let path = parts.uri.path().to_string();
+    let params: HashMap<String, String> = parts
+        .uri
         .query()
         .map(|v| {
             url::form_urlencoded::parse(v.as_bytes())
                 .into_owned()
                 .collect()
         })
-        .unwrap_or_else(HashMap::new);
-    println!("{}", path);
-
-    // Setup regex routes
-    let containers_re = Regex::new(r"\/docker\/container\/(?P<id>[a-z0-9]{64})\/(?P<action>\w+)").unwrap();
-
-    // Do routing
-    if path == "/health" && request.method() == Method::GET {
-        services::health::health(request)
-    } else if path == "/docker/containers/list" && request.method() == Method::GET {
-        services::docker::list_containers(request).await
-    } else if path == "/docker/container" && request.method() == Method::POST {
-        services::docker::create_or_update_container(request).await
-    } else if let Some(caps) = containers_re.captures(path) {
-        let id = &caps["id"];
-        let action = &caps["action"];
-
-        match action {
-            "inspect" => services::docker::container_inspect(id).await,
-            "start" => services::docker::container_start(id).await,
-            "stop" => services::docker::container_stop(id).await,
-            "rm" => services::docker::container_rm(id).await,
-            "logs" => services::docker::container_logs(id).await,
-            _ => not_found()
-        }
-    } else if path == "/runner/status" && params.contains_key("path") && request.method() == Method::GET {
-        let svc_path = params.get("path").unwrap();
-        services::github_runners::get_status(svc_path)
-    } else if path == "/runner" && request.method() == Method::POST {
-        services::github_runners::setup_new(request).await
-    } else if path == "/docker/compose" && request.method() == Method::POST {
-        services::docker_compose::create_or_update_compose(request).await
-    } else if path == "/docker/compose/status" && params.contains_key("path") && request.method() == Method::GET {
-        let compose_path = params.get("path").unwrap();
-        services::docker_compose::logs(compose_path).await
+        .unwrap_or_default();
+    log::info!("{} {} (key {})", parts.method, path, key.name);
+
+    let (route, path_params) = match routes::find(&parts.method, &path) {
+        RouteMatch::Found(route, path_params) => (route, path_params),
+        RouteMatch::MethodNotAllowed(allowed) => return method_not_allowed(&allowed).map(boxed),
+        RouteMatch::NotFound => return not_found().map(boxed),
+    };
+
+    // The scope is checked before the body is read, a key without it cannot upload anything.
+    // Volume restores stream their archive into the volume, every other body is read first.
+    let method = parts.method.to_string();
+    let (body_summary, response) = if !key.has_scope(route.scope) {
+        (None, missing_scope(route.scope).map(boxed))
+    } else if route.endpoint == Endpoint::RestoreVolume {
+        let response = match payload {
+            // Signed archives were already read for the signature check
+            Payload::Read(body) => {
+                restore_volume(Request::from_parts(parts, Full::new(body)), &path_params, &key, &state).await
+            }
+            Payload::Unread(body) => restore_volume(Request::from_parts(parts, body), &path_params, &key, &state).await,
+        };
+        (None, response)
     } else {
-        not_found()
+        let body = match payload {
+            Payload::Read(body) => body,
+            Payload::Unread(body) => match read_body(body, MAX_BODY_BYTES).await {
+                Ok(body) => body,
+                Err(e) => return Ok(boxed(e.response())),
+            },
+        };
+        let body_summary = match &state.audit {
+            Some(_) if route.mutating => audit::summarize_body(&body),
+            _ => None,
+        };
+        let request = Request::from_parts(parts, Full::new(body));
+        (body_summary, dispatch(request, route.endpoint, &path_params, &key, &state).await)
+    };
+    let Ok(response) = response;
+
+    // Mutating requests end up in the audit log, together with their outcome
+    if let Some(audit_log) = state.audit.as_ref().filter(|_| route.mutating) {
+        let status = response.status();
+        audit_log.record(&AuditEntry {
+            timestamp: auth::unix_now(),
+            key: key.name.clone(),
+            source_ip: client_ip,
+            method,
+            route: path,
+            target: audit::target(&path_params, &params, body_summary.as_ref()),
+            body: body_summary,
+            status: status.as_u16(),
+            // 101 is a terminal session that was opened
+            outcome: if status.is_success() || status.is_informational() { Outcome::Success } else { Outcome::Failure },
+            duration_ms: started.elapsed().as_millis() as u64,
+        });
     }
+    Ok(response)
+}
+
+async fn dispatch(
+    request: Request<Full<Bytes>>,
+    endpoint: Endpoint,
+    path_params: &PathParams,
+    key: &ApiKey,
+    state: &Arc<AppState>
+) -> Result<Response<Body>, Infallible> {
+    // Every {id} route acts on a container the key has to be allowed to touch
+    let id = match path_params.get("id") {
+        Some(reference) => match services::docker::resolve_container(key, reference).await {
+            Ok(id) => id,
+            Err(e) => return Ok(boxed(e.response())),
+        },
+        None => String::new(),
+    };
+    if !id.is_empty() && !services::docker::container_allowed(key, &id).await {
+        return path_restricted().map(boxed);
+    }
+    let id = id.as_str();
+    let job = path_params.get("job").unwrap_or_default();
+
+    let response = match endpoint {
+        Endpoint::Health => services::health::health(request).await,
+        Endpoint::OpenApi => Ok(Response::new(Full::new(Bytes::from(openapi::document())))),
+        Endpoint::Audit => match query(&request) {
+            Ok(query) => services::audit::list(query, state.audit.clone()).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::RotateKey => {
+            let name = path_params.get("name").unwrap_or_default().to_string();
+            services::keys::rotate(request, &name, state.keys.clone()).await
+        }
+        Endpoint::Registries => services::registries::list(state.registries.clone()).await,
+        Endpoint::AddRegistry => services::registries::add(request, state.registries.clone()).await,
+        Endpoint::RemoveRegistry => {
+            let host = path_params.get("host").unwrap_or_default();
+            services::registries::remove(host, state.registries.clone()).await
+        }
+        Endpoint::ListContainers => match query(&request) {
+            Ok(query) => services::docker::list_containers(query, key).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::AllContainerStats => services::container_stats::all_stats(key).await,
+        Endpoint::Images => services::images::list(key).await,
+        Endpoint::ImageInspect => match query(&request) {
+            Ok(query) => services::images::inspect(query).await,
+            Err(e) => bad_request(&e),
+        },
+        // Images are shared by all deployments on the node
+        Endpoint::PullImage | Endpoint::TagImage | Endpoint::RemoveImage | Endpoint::PruneImages
+            if key.is_path_restricted() =>
+        {
+            return path_restricted().map(boxed);
+        }
+        Endpoint::PullImage => services::images::pull(request, key, state.jobs.clone(), &state.registries).await,
+        Endpoint::TagImage => services::images::tag(request).await,
+        Endpoint::RemoveImage => match query(&request) {
+            Ok(query) => services::images::remove(query).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::PruneImages => services::images::prune(request).await,
+        Endpoint::Volumes => services::volumes::list(key).await,
+        // Volumes are not tied to a deployment path either
+        Endpoint::CreateVolume
+        | Endpoint::RemoveVolume
+        | Endpoint::PruneVolumes
+        | Endpoint::BackupVolume
+            if key.is_path_restricted() =>
+        {
+            return path_restricted().map(boxed);
+        }
+        Endpoint::CreateVolume => services::volumes::create(request).await,
+        Endpoint::RemoveVolume => {
+            let name = path_params.get("name").unwrap_or_default();
+            services::volumes::remove(name).await
+        }
+        Endpoint::PruneVolumes => services::volumes::prune(request).await,
+        // Streamed by the router before the body is read
+        Endpoint::RestoreVolume => unreachable!("volume restores are not dispatched"),
+        Endpoint::Networks => services::networks::list(key).await,
+        Endpoint::NetworkInspect => {
+            let name = path_params.get("name").unwrap_or_default();
+            services::networks::inspect(name, key).await
+        }
+        // Joining another project's network would reach its containers
+        Endpoint::CreateNetwork
+        | Endpoint::RemoveNetwork
+        | Endpoint::ConnectNetwork
+        | Endpoint::DisconnectNetwork
+            if key.is_path_restricted() =>
+        {
+            return path_restricted().map(boxed);
+        }
+        Endpoint::CreateNetwork => services::networks::create(request, key).await,
+        Endpoint::RemoveNetwork => {
+            let name = path_params.get("name").unwrap_or_default();
+            services::networks::remove(name, key).await
+        }
+        Endpoint::ConnectNetwork => {
+            let name = path_params.get("name").unwrap_or_default().to_string();
+            services::networks::connect(request, &name, key).await
+        }
+        Endpoint::DisconnectNetwork => {
+            let name = path_params.get("name").unwrap_or_default().to_string();
+            services::networks::disconnect(request, &name, key).await
+        }
+        Endpoint::CreateContainer => {
+            if key.is_path_restricted() {
+                // standalone containers have no deployment path to check against
+                return path_restricted().map(boxed);
+            }
+            services::docker::create_or_update_container(request, key, state.jobs.clone(), &state.registries).await
+        }
+        Endpoint::ContainerInspect => services::docker::container_inspect(id).await,
+        Endpoint::ContainerStart => services::docker::container_start(id).await,
+        Endpoint::ContainerStop => match query(&request) {
+            Ok(query) => services::docker::container_stop(id, query).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::ContainerRestart => match query(&request) {
+            Ok(query) => services::docker::container_restart(id, query).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::ContainerPause => services::docker::container_pause(id).await,
+        Endpoint::ContainerUnpause => services::docker::container_unpause(id).await,
+        Endpoint::ContainerKill => match query(&request) {
+            Ok(query) => services::docker::container_kill(id, query).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::ContainerRename => match query(&request) {
+            Ok(query) => services::docker::container_rename(id, query).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::ContainerUpdate => services::docker::container_update(request, id).await,
+        Endpoint::ContainerRm => match query(&request) {
+            Ok(query) => services::docker::container_rm(id, query).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::ContainerExec => services::container_exec::container_exec(request, id).await,
+        Endpoint::ContainerTerminal => match services::container_terminal::TerminalQuery::parse(request.uri().query().unwrap_or("")) {
+            Ok(query) => services::container_terminal::terminal(request, id, query).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::RunnerStatus => match query::<PathQuery, _>(&request) {
+            Ok(query) => services::github_runners::get_status(&query.path, key).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::RunnerSetup => services::github_runners::setup_new(request, key, state.jobs.clone()).await,
+        Endpoint::ComposeUp => {
+            services::docker_compose::create_or_update_compose(request, key, state.jobs.clone(), state.registries.clone())
+                .await
+        }
+        Endpoint::ComposeStatus => match query::<PathQuery, _>(&request) {
+            Ok(query) => services::docker_compose::logs(&query.path, key).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::Jobs => match query(&request) {
+            Ok(query) => services::jobs::list(query, key, state.jobs.clone()).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::Job => services::jobs::get(job, key, state.jobs.clone()).await,
+        Endpoint::CancelJob => services::jobs::cancel(job, key, state.jobs.clone()).await,
+        // Streaming responses
+        Endpoint::JobEvents => return services::jobs::events(job, key, state.jobs.clone()).await,
+        Endpoint::ContainerLogs => match query(&request) {
+            Ok(query) => return services::docker::container_logs(id, query).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::ContainerStats => match query(&request) {
+            Ok(query) => return services::container_stats::container_stats(id, query).await,
+            Err(e) => bad_request(&e),
+        },
+        Endpoint::BackupVolume => {
+            let name = path_params.get("name").unwrap_or_default();
+            return services::volumes::backup(name, &state.registries).await;
+        }
+    };
+    response.map(boxed)
+}
+
+fn boxed(response: Response<Full<Bytes>>) -> Response<Body> {
+    response.map(BodyExt::boxed)
+}
+
+/// Stream an uploaded archive into a volume. The body is still unread for keys with an X-Api-Key
+/// header, signed requests had to fit into the regular limit.
+async fn restore_volume<B>(
+    request: Request<B>,
+    path_params: &PathParams,
+    key: &ApiKey,
+    state: &AppState,
+) -> Result<Response<Body>, Infallible>
+where
+    B: hyper::body::Body<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: std::fmt::Display,
+{
+    // Like the other volume routes, not tied to a deployment path
+    if key.is_path_restricted() {
+        return path_restricted().map(boxed);
+    }
+    match query(&request) {
+        Ok(query) => {
+            let name = path_params.get("name").unwrap_or_default().to_string();
+            services::volumes::restore(request, &name, query, &state.registries).await.map(boxed)
+        }
+        Err(e) => bad_request(&e).map(boxed),
+    }
+}
+
+/// Read the whole request body, at most `limit` bytes within `timeouts.request_body_secs`.
+async fn read_body(body: Incoming, limit: usize) -> Result<Bytes, ApiError> {
+    let timeout = Duration::from_secs(config::get().timeouts.request_body_secs);
+    match tokio::time::timeout(timeout, Limited::new(body, limit).collect()).await {
+        Ok(Ok(body)) => Ok(body.to_bytes()),
+        Ok(Err(e)) if e.is::<LengthLimitError>() => Err(ApiError::new(
+            ErrorCode::PayloadTooLarge,
+            format!("the request body is larger than {} bytes", limit),
+        )),
+        Ok(Err(e)) => Err(ApiError::invalid_body(e)),
+        Err(_) => Err(ApiError::new(
+            ErrorCode::RequestTimeout,
+            format!("the request body did not arrive within {}s", timeout.as_secs()),
+        )),
+    }
+}
+
+/// Deserialize the query string into the typed query of a route.
+fn query<T: DeserializeOwned, B>(request: &Request<B>) -> Result<T, String> {
+    serde_urlencoded::from_str(request.uri().query().unwrap_or("")).map_err(|e| format!("invalid query: {}", e))
+}
+
+/// Check the HMAC headers of a signed request, see `signature::canonical_request`.
+fn verify_signed(parts: &Parts, body: &Bytes, state: &AppState) -> Result<ApiKey, SignatureError> {
+    let header = |name: &'static str| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .ok_or(SignatureError::MissingHeader(name))
+    };
+    let key_name = header(signature::KEY_NAME_HEADER)?;
+    let max_skew = config::get().auth.signature_max_skew_secs;
+    let timestamp = signature::check_timestamp(header(signature::TIMESTAMP_HEADER)?, max_skew)?;
+    let nonce = header(signature::NONCE_HEADER)?;
+    signature::check_nonce(nonce)?;
+
+    let canonical = signature::canonical_request(
+        parts.method.as_str(),
+        parts.uri.path(),
+        parts.uri.query().unwrap_or(""),
+        body,
+        timestamp,
+        nonce,
+    );
+    let key = state
+        .keys
+        .verify_signature(key_name, &canonical, header(signature::SIGNATURE_HEADER)?)
+        .ok_or(SignatureError::Invalid)?;
+
+    // Only remember nonces of valid signatures, so nobody can burn them for the real client
+    if !state.nonces.insert(&key.name, nonce, timestamp + max_skew) {
+        return Err(SignatureError::Replayed);
+    }
+    Ok(key)
 }
 
 fn not_found() -> Result<Response<Full<Bytes>>, Infallible> {
-    let resp = Response::builder()
-        .status(hyper::StatusCode::NOT_FOUND)
-        .body(Full::new(Bytes::from("NOT FOUND")))
-        .unwrap();
+    Ok(ApiError::not_found("no such route").response())
+}
+
+fn bad_request(message: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+    Ok(ApiError::new(ErrorCode::InvalidQuery, message).response())
+}
+
+fn method_not_allowed(allowed: &[&Method]) -> Result<Response<Full<Bytes>>, Infallible> {
+    let allowed: Vec<&str> = allowed.iter().map(|m| m.as_str()).collect();
+    let allow = allowed.join(", ");
+    let mut resp = ApiError::new(ErrorCode::MethodNotAllowed, format!("method not allowed, use {}", allow))
+        .with_details(serde_json::json!({ "allowed": allowed }))
+        .response();
+    resp.headers_mut().insert("Allow", HeaderValue::from_str(&allow).unwrap());
     Ok(resp)
 }
 
 fn forbidden() -> Result<Response<Full<Bytes>>, Infallible> {
-    let resp = Response::builder()
-        .status(hyper::StatusCode::FORBIDDEN)
-        .body(Full::new(Bytes::from("FORBIDDEN")))
-        .unwrap();
+    Ok(ApiError::new(ErrorCode::Unauthorized, "missing or invalid API key").response())
+}
+
+fn signature_rejected(e: SignatureError) -> Result<Response<Full<Bytes>>, Infallible> {
+    Ok(ApiError::new(ErrorCode::Unauthorized, format!("signature rejected: {}", e)).response())
+}
+
+fn locked_out(remaining: Duration) -> Result<Response<Full<Bytes>>, Infallible> {
+    // Round up so clients do not retry a moment too early
+    let secs = remaining.as_secs() + 1;
+    let mut resp = ApiError::new(
+        ErrorCode::TooManyRequests,
+        format!("too many failed attempts, retry in {}s", secs),
+    )
+    .with_details(serde_json::json!({ "retry_after_secs": secs }))
+    .response();
+    resp.headers_mut().insert("Retry-After", HeaderValue::from(secs));
     Ok(resp)
 }
+
+fn missing_scope(scope: Scope) -> Result<Response<Full<Bytes>>, Infallible> {
+    Ok(ApiError::new(ErrorCode::MissingScope, format!("key lacks the {} scope", scope))
+        .with_details(serde_json::json!({ "required_scope": scope }))
+        .response())
+}
+
+fn path_restricted() -> Result<Response<Full<Bytes>>, Infallible> {
+    Ok(ApiError::path_not_allowed("key is restricted to other deployment paths").response())
+}
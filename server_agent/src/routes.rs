@@ -0,0 +1,954 @@
+use std::sync::OnceLock;
+
+use hyper::Method;
+use regex::Regex;
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::Deserialize;
+
+use crate::audit::{AuditPage, AuditQuery};
+use crate::auth::{RotatedKey, Scope};
+use crate::error::ApiError;
+use crate::jobs::{Job, JobQuery, JobSummary, PullProgress};
+use crate::registries::RegistryInfo;
+use crate::services::container_exec::{ExecRequest, ExecResult};
+use crate::services::container_stats::{ContainerStats, StatsQuery};
+use crate::services::container_terminal::{ClientMessage, ServerMessage, TerminalQuery};
+use crate::services::container_update::UpdateResult;
+use crate::services::docker::{
+    ActionResult, ContainerListItem, DockerRequest, KillQuery, ListQuery, LogLine, LogsQuery, RenameQuery, RmQuery, StopQuery, UpdateRequest,
+};
+use crate::services::docker_compose::{ComposeResult, DockerComposeRequest};
+use crate::services::images::{
+    ImageInfo, ImageQuery, PruneRequest, PullRequest, PullResult, RemovalResult, RemoveQuery, TagRequest, TagResult,
+};
+use crate::services::volumes::{CreateVolumeRequest, RestoreQuery, RestoreResult, VolumeInfo, VolumePruneRequest, VolumeRemoval};
+use crate::services::networks::{ConnectRequest, CreateNetworkRequest, DisconnectRequest, NetworkInfo};
+use crate::services::github_runners::{SetupRequest, SetupResult};
+use crate::services::health::SystemStats;
+use crate::services::keys::RotateRequest;
+use crate::services::registries::RegistryRequest;
+
+pub type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+/// What a route is dispatched to in `router::dispatch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Health,
+    OpenApi,
+    Audit,
+    RotateKey,
+    Registries,
+    AddRegistry,
+    RemoveRegistry,
+    ListContainers,
+    CreateContainer,
+    ContainerInspect,
+    ContainerStart,
+    ContainerStop,
+    ContainerRestart,
+    ContainerPause,
+    ContainerUnpause,
+    ContainerKill,
+    ContainerRename,
+    ContainerUpdate,
+    ContainerRm,
+    ContainerLogs,
+    ContainerStats,
+    ContainerExec,
+    ContainerTerminal,
+    AllContainerStats,
+    Images,
+    ImageInspect,
+    PullImage,
+    TagImage,
+    RemoveImage,
+    PruneImages,
+    Volumes,
+    CreateVolume,
+    RemoveVolume,
+    PruneVolumes,
+    BackupVolume,
+    RestoreVolume,
+    Networks,
+    NetworkInspect,
+    CreateNetwork,
+    RemoveNetwork,
+    ConnectNetwork,
+    DisconnectNetwork,
+    RunnerStatus,
+    RunnerSetup,
+    ComposeUp,
+    ComposeStatus,
+    Jobs,
+    Job,
+    CancelJob,
+    JobEvents,
+}
+
+pub enum RequestBody {
+    None,
+    Json(SchemaFn),
+    OptionalJson(SchemaFn),
+    /// `application/x-tar` archive
+    Tar,
+}
+
+pub enum ResponseBody {
+    Json(SchemaFn),
+    /// 202, the work continues as a job
+    Accepted(SchemaFn),
+    Text,
+    /// `application/x-tar` archive, streamed
+    Tar,
+    /// `text/event-stream`, the schema lists the event names
+    EventStream(SchemaFn),
+    /// JSON, or `text/event-stream` (second schema) when the query asks for a stream
+    JsonOrEventStream(SchemaFn, SchemaFn),
+    /// 101, the connection continues as a WebSocket; the schema describes its text messages
+    WebSocket(SchemaFn),
+}
+
+pub struct Route {
+    pub method: Method,
+    /// Template like `/docker/container/{id}/inspect`, see `PATH_PARAMS` for the placeholders.
+    pub path: &'static str,
+    pub endpoint: Endpoint,
+    pub scope: Scope,
+    /// Changes something on the node, recorded in the audit log
+    pub mutating: bool,
+    pub summary: &'static str,
+    pub query: Option<SchemaFn>,
+    pub body: RequestBody,
+    pub response: ResponseBody,
+}
+
+/// Path placeholders and the pattern a segment has to match.
+pub const PATH_PARAMS: &[(&str, &str)] = &[
+    // Full ID, unique ID prefix or container name
+    ("id", "^[a-zA-Z0-9][a-zA-Z0-9_.-]*$"),
+    ("name", r"^[\w.-]+$"),
+    ("job", "^[a-f0-9]{32}$"),
+    // Registry host with optional port
+    ("host", r"^[a-zA-Z0-9][a-zA-Z0-9.-]*(:[0-9]+)?$"),
+];
+
+/// Query of the routes that act on a deployment directory.
+#[derive(Deserialize, JsonSchema)]
+pub struct PathQuery {
+    /// Absolute deployment path below one of the allowed base directories
+    pub path: String,
+}
+
+fn schema<T: JsonSchema>(generator: &mut SchemaGenerator) -> Schema {
+    generator.subschema_for::<T>()
+}
+
+fn docker_object(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({ "type": "object", "description": "Passed through from the Docker Engine API" })
+}
+
+fn openapi_document(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({ "type": "object", "description": "OpenAPI 3.1 document" })
+}
+
+fn job_events(generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "description": "`job` (JobSummary), `output` ({line}), `step` ({message}), `progress` (PullProgress), \
+            `lagged` ({skipped}) and finally `finished` ({state, result, error}), or `end` ({reason}) on shutdown",
+        "oneOf": [
+            generator.subschema_for::<JobSummary>(),
+            generator.subschema_for::<PullProgress>(),
+            { "type": "object" },
+        ],
+    })
+}
+
+fn log_events(generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "description": "`log` (LogLine) per line, `error` (ApiError) and finally `end` ({}, or {reason} on shutdown)",
+        "oneOf": [
+            generator.subschema_for::<LogLine>(),
+            generator.subschema_for::<ApiError>(),
+            { "type": "object" },
+        ],
+    })
+}
+
+fn stats_events(generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "description": "`stats` (ContainerStats) about every second, `error` (ApiError) and finally `end` ({}, or {reason} on shutdown)",
+        "oneOf": [
+            generator.subschema_for::<ContainerStats>(),
+            generator.subschema_for::<ApiError>(),
+            { "type": "object" },
+        ],
+    })
+}
+
+fn terminal_messages(generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "description": "Binary messages carry stdin from the client and terminal output from the agent. \
+            Text messages are JSON: `stdin` ({data}) and `resize` ({cols, rows}) from the client, \
+            `exit` ({exit_code}), `timeout` ({idle_secs}) and `error` ({error}) from the agent",
+        "oneOf": [
+            generator.subschema_for::<ClientMessage>(),
+            generator.subschema_for::<ServerMessage>(),
+        ],
+    })
+}
+
+fn container_list(generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "description": "Docker Engine API container summaries, or ContainerListItem with summary=true",
+        "oneOf": [
+            docker_objects(generator),
+            generator.subschema_for::<Vec<ContainerListItem>>(),
+        ],
+    })
+}
+
+fn docker_objects(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "type": "array",
+        "items": { "type": "object", "description": "Passed through from the Docker Engine API" }
+    })
+}
+
+pub static ROUTES: &[Route] = &[
+    Route {
+        method: Method::GET,
+        path: "/health",
+        endpoint: Endpoint::Health,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "Memory, IO and CPU usage of the node",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<SystemStats>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/openapi.json",
+        endpoint: Endpoint::OpenApi,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "This document",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(openapi_document),
+    },
+    Route {
+        method: Method::GET,
+        path: "/audit",
+        endpoint: Endpoint::Audit,
+        scope: Scope::Admin,
+        mutating: false,
+        summary: "Audit log entries, newest first",
+        query: Some(schema::<AuditQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<AuditPage>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/admin/keys/{name}/rotate",
+        endpoint: Endpoint::RotateKey,
+        scope: Scope::Admin,
+        mutating: true,
+        summary: "Replace a key, the old one stays valid for the grace period",
+        query: None,
+        body: RequestBody::OptionalJson(schema::<RotateRequest>),
+        response: ResponseBody::Json(schema::<RotatedKey>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/admin/registries",
+        endpoint: Endpoint::Registries,
+        scope: Scope::Admin,
+        mutating: false,
+        summary: "Registries with stored credentials, passwords masked",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<Vec<RegistryInfo>>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/admin/registries",
+        endpoint: Endpoint::AddRegistry,
+        scope: Scope::Admin,
+        mutating: true,
+        summary: "Store or replace the credentials of a registry, used for container and compose pulls",
+        query: None,
+        body: RequestBody::Json(schema::<RegistryRequest>),
+        response: ResponseBody::Json(schema::<RegistryInfo>),
+    },
+    Route {
+        method: Method::DELETE,
+        path: "/admin/registries/{host}",
+        endpoint: Endpoint::RemoveRegistry,
+        scope: Scope::Admin,
+        mutating: true,
+        summary: "Remove the credentials of a registry",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<RegistryInfo>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/containers/list",
+        endpoint: Endpoint::ListContainers,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "All containers, including stopped ones, optionally filtered; summary=true for the short form",
+        query: Some(schema::<ListQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::Json(container_list),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/containers/stats",
+        endpoint: Endpoint::AllContainerStats,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "Resource usage of all running containers",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<Vec<ContainerStats>>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/images",
+        endpoint: Endpoint::Images,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "Local images with their size and the containers using them",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<Vec<ImageInfo>>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/images/inspect",
+        endpoint: Endpoint::ImageInspect,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "Inspect an image",
+        query: Some(schema::<ImageQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::Json(docker_object),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/images/pull",
+        endpoint: Endpoint::PullImage,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Pull an image as a job, with stored registry credentials; the job events report the layer progress",
+        query: None,
+        body: RequestBody::Json(schema::<PullRequest>),
+        response: ResponseBody::Accepted(schema::<Job>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/images/tag",
+        endpoint: Endpoint::TagImage,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Add a tag to an image",
+        query: None,
+        body: RequestBody::Json(schema::<TagRequest>),
+        response: ResponseBody::Json(schema::<TagResult>),
+    },
+    Route {
+        method: Method::DELETE,
+        path: "/docker/images",
+        endpoint: Endpoint::RemoveImage,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Remove an image and report the space it freed",
+        query: Some(schema::<RemoveQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<RemovalResult>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/images/prune",
+        endpoint: Endpoint::PruneImages,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Remove dangling images, or all unused ones, optionally only those older than a given age",
+        query: None,
+        body: RequestBody::OptionalJson(schema::<PruneRequest>),
+        response: ResponseBody::Json(schema::<RemovalResult>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/volumes",
+        endpoint: Endpoint::Volumes,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "Volumes with their size and the containers using them",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<Vec<VolumeInfo>>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/volumes",
+        endpoint: Endpoint::CreateVolume,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Create a named volume",
+        query: None,
+        body: RequestBody::Json(schema::<CreateVolumeRequest>),
+        response: ResponseBody::Json(docker_object),
+    },
+    Route {
+        method: Method::DELETE,
+        path: "/docker/volumes/{name}",
+        endpoint: Endpoint::RemoveVolume,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Remove a volume no container uses",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<VolumeRemoval>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/volumes/prune",
+        endpoint: Endpoint::PruneVolumes,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Remove unused anonymous volumes, or all unused ones",
+        query: None,
+        body: RequestBody::OptionalJson(schema::<VolumePruneRequest>),
+        response: ResponseBody::Json(schema::<VolumeRemoval>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/volumes/{name}/backup",
+        endpoint: Endpoint::BackupVolume,
+        scope: Scope::Deploy,
+        // Changes nothing, but who copied data off the node belongs in the audit log
+        mutating: true,
+        summary: "Download the contents of a volume as a tar archive, with the files under volume/",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Tar,
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/volumes/{name}/restore",
+        endpoint: Endpoint::RestoreVolume,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Extract a tar archive with the files under volume/ into a volume, as made by the backup",
+        query: Some(schema::<RestoreQuery>),
+        body: RequestBody::Tar,
+        response: ResponseBody::Json(schema::<RestoreResult>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/networks",
+        endpoint: Endpoint::Networks,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "Networks with their subnets and the containers attached to them",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<Vec<NetworkInfo>>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/networks/{name}",
+        endpoint: Endpoint::NetworkInspect,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "Docker's view of a network, by name or ID",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(docker_object),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/networks",
+        endpoint: Endpoint::CreateNetwork,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Create a network",
+        query: None,
+        body: RequestBody::Json(schema::<CreateNetworkRequest>),
+        response: ResponseBody::Json(schema::<NetworkInfo>),
+    },
+    Route {
+        method: Method::DELETE,
+        path: "/docker/networks/{name}",
+        endpoint: Endpoint::RemoveNetwork,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Remove a network no container is attached to",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<NetworkInfo>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/networks/{name}/connect",
+        endpoint: Endpoint::ConnectNetwork,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Attach a container to a network",
+        query: None,
+        body: RequestBody::Json(schema::<ConnectRequest>),
+        response: ResponseBody::Json(schema::<NetworkInfo>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/networks/{name}/disconnect",
+        endpoint: Endpoint::DisconnectNetwork,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Detach a container from a network",
+        query: None,
+        body: RequestBody::Json(schema::<DisconnectRequest>),
+        response: ResponseBody::Json(schema::<NetworkInfo>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/container",
+        endpoint: Endpoint::CreateContainer,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Create a container, or replace it if its configuration changed, rolling back on failure. Runs as a job",
+        query: None,
+        body: RequestBody::Json(schema::<DockerRequest>),
+        response: ResponseBody::Accepted(schema::<Job>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/container/{id}/inspect",
+        endpoint: Endpoint::ContainerInspect,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "Inspect a container",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(docker_object),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/container/{id}/start",
+        endpoint: Endpoint::ContainerStart,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Start a container",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<ActionResult>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/container/{id}/stop",
+        endpoint: Endpoint::ContainerStop,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Stop a container",
+        query: Some(schema::<StopQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<ActionResult>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/container/{id}/restart",
+        endpoint: Endpoint::ContainerRestart,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Restart a container",
+        query: Some(schema::<StopQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<ActionResult>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/container/{id}/pause",
+        endpoint: Endpoint::ContainerPause,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Freeze all processes of a container",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<ActionResult>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/container/{id}/unpause",
+        endpoint: Endpoint::ContainerUnpause,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Resume a paused container",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<ActionResult>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/container/{id}/kill",
+        endpoint: Endpoint::ContainerKill,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Send a signal to the main process of a container",
+        query: Some(schema::<KillQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<ActionResult>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/container/{id}/rename",
+        endpoint: Endpoint::ContainerRename,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Rename a container",
+        query: Some(schema::<RenameQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<ActionResult>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/container/{id}/update",
+        endpoint: Endpoint::ContainerUpdate,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Change memory and CPU limits or the restart policy of a container without recreating it",
+        query: None,
+        body: RequestBody::Json(schema::<UpdateRequest>),
+        response: ResponseBody::Json(schema::<ActionResult>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/container/{id}/rm",
+        endpoint: Endpoint::ContainerRm,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Remove a container",
+        query: Some(schema::<RmQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<ActionResult>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/container/{id}/logs",
+        endpoint: Endpoint::ContainerLogs,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "Log lines of a container; with follow=true new lines are streamed as Server-Sent Events",
+        query: Some(schema::<LogsQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::JsonOrEventStream(schema::<Vec<String>>, log_events),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/container/{id}/stats",
+        endpoint: Endpoint::ContainerStats,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "CPU, memory, network and block I/O usage of a container; with stream=true a sample about every second as Server-Sent Events",
+        query: Some(schema::<StatsQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::JsonOrEventStream(schema::<ContainerStats>, stats_events),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/container/{id}/exec",
+        endpoint: Endpoint::ContainerExec,
+        scope: Scope::Exec,
+        mutating: true,
+        summary: "Run a command inside a running container and return its output and exit code",
+        query: None,
+        body: RequestBody::Json(schema::<ExecRequest>),
+        response: ResponseBody::Json(schema::<ExecResult>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/container/{id}/terminal",
+        endpoint: Endpoint::ContainerTerminal,
+        scope: Scope::Exec,
+        mutating: true,
+        summary: "Interactive terminal in a running container over a WebSocket, closed after timeouts.terminal_idle_secs without input",
+        query: Some(schema::<TerminalQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::WebSocket(terminal_messages),
+    },
+    Route {
+        method: Method::GET,
+        path: "/runner/status",
+        endpoint: Endpoint::RunnerStatus,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "Output of svc.sh status for a runner",
+        query: Some(schema::<PathQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::Text,
+    },
+    Route {
+        method: Method::POST,
+        path: "/runner",
+        endpoint: Endpoint::RunnerSetup,
+        scope: Scope::Runner,
+        mutating: true,
+        summary: "Download, configure and start a GitHub Actions runner. Runs as a job, see SetupResult",
+        query: None,
+        body: RequestBody::Json(schema::<SetupRequest>),
+        response: ResponseBody::Accepted(schema::<Job>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/docker/compose",
+        endpoint: Endpoint::ComposeUp,
+        scope: Scope::Deploy,
+        mutating: true,
+        summary: "Write docker-compose.yml and run docker compose up. Runs as a job, see ComposeResult",
+        query: None,
+        body: RequestBody::Json(schema::<DockerComposeRequest>),
+        response: ResponseBody::Accepted(schema::<Job>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/docker/compose/status",
+        endpoint: Endpoint::ComposeStatus,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "Last 100 lines of docker compose logs",
+        query: Some(schema::<PathQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<Vec<String>>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/jobs",
+        endpoint: Endpoint::Jobs,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "Jobs of the last jobs.retention_secs, newest first",
+        query: Some(schema::<JobQuery>),
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<Vec<JobSummary>>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/jobs/{job}",
+        endpoint: Endpoint::Job,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "State, captured output and result of a job",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<Job>),
+    },
+    Route {
+        method: Method::POST,
+        path: "/jobs/{job}/cancel",
+        endpoint: Endpoint::CancelJob,
+        scope: Scope::Read,
+        mutating: true,
+        summary: "Cancel a running job and kill its command, needs the scope that started the job",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::Json(schema::<Job>),
+    },
+    Route {
+        method: Method::GET,
+        path: "/jobs/{job}/events",
+        endpoint: Endpoint::JobEvents,
+        scope: Scope::Read,
+        mutating: false,
+        summary: "Server-Sent Events with the output, steps and pull progress of a job as they happen",
+        query: None,
+        body: RequestBody::None,
+        response: ResponseBody::EventStream(job_events),
+    },
+];
+
+/// Job results, only named in route summaries, so they end up in the document too.
+pub static JOB_RESULTS: &[SchemaFn] = &[
+    schema::<ComposeResult>,
+    schema::<SetupResult>,
+    schema::<UpdateResult>,
+    schema::<PullResult>,
+];
+
+/// Values of the `{placeholders}` of a matched route.
+pub struct PathParams(Vec<(&'static str, String)>);
+
+impl PathParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(n, _)| *n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+pub enum RouteMatch {
+    Found(&'static Route, PathParams),
+    /// The path exists, but not for this method
+    MethodNotAllowed(Vec<&'static Method>),
+    NotFound,
+}
+
+fn param_regex(name: &str) -> Option<&'static Regex> {
+    static REGEXES: OnceLock<Vec<(&str, Regex)>> = OnceLock::new();
+    let regexes = REGEXES.get_or_init(|| {
+        PATH_PARAMS
+            .iter()
+            .map(|(name, pattern)| (*name, Regex::new(pattern).unwrap()))
+            .collect()
+    });
+    regexes.iter().find(|(n, _)| *n == name).map(|(_, re)| re)
+}
+
+fn match_path(template: &'static str, path: &str) -> Option<PathParams> {
+    let mut template_segments = template.split('/');
+    let mut path_segments = path.split('/');
+    let mut params = Vec::new();
+    loop {
+        match (template_segments.next(), path_segments.next()) {
+            (None, None) => return Some(PathParams(params)),
+            (Some(t), Some(p)) => match t.strip_prefix('{').and_then(|t| t.strip_suffix('}')) {
+                Some(name) => {
+                    if !param_regex(name)?.is_match(p) {
+                        return None;
+                    }
+                    params.push((name, p.to_string()));
+                }
+                None if t == p => {}
+                None => return None,
+            },
+            _ => return None,
+        }
+    }
+}
+
+pub fn find(method: &Method, path: &str) -> RouteMatch {
+    let matching: Vec<(&'static Route, PathParams)> = ROUTES
+        .iter()
+        .filter_map(|route| match_path(route.path, path).map(|params| (route, params)))
+        .collect();
+    // Literal segments beat placeholders: `/docker/volumes/prune` is not the volume `prune`
+    let Some(most_literal) = matching.iter().map(|(route, _)| literal_segments(route.path)).max() else {
+        return RouteMatch::NotFound;
+    };
+    let mut allowed = Vec::new();
+    for (route, params) in matching {
+        if literal_segments(route.path) < most_literal {
+            continue;
+        }
+        if route.method == method {
+            return RouteMatch::Found(route, params);
+        }
+        allowed.push(&route.method);
+    }
+    RouteMatch::MethodNotAllowed(allowed)
+}
+
+fn literal_segments(template: &str) -> usize {
+    template.split('/').filter(|segment| !segment.starts_with('{')).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(method: Method, path: &str) -> Option<(Endpoint, PathParams)> {
+        match find(&method, path) {
+            RouteMatch::Found(route, params) => Some((route.endpoint, params)),
+            _ => None,
+        }
+    }
+
+    fn allowed(method: Method, path: &str) -> Option<Vec<Method>> {
+        match find(&method, path) {
+            RouteMatch::MethodNotAllowed(allowed) => Some(allowed.into_iter().cloned().collect()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn finds_routes_and_params() {
+        let (endpoint, params) = found(Method::GET, "/docker/container/web-1/inspect").unwrap();
+        assert_eq!(endpoint, Endpoint::ContainerInspect);
+        assert_eq!(params.get("id"), Some("web-1"));
+
+        let (endpoint, params) = found(Method::DELETE, "/admin/registries/registry.local:5000").unwrap();
+        assert_eq!(endpoint, Endpoint::RemoveRegistry);
+        assert_eq!(params.get("host"), Some("registry.local:5000"));
+        assert_eq!(params.get("id"), None);
+    }
+
+    #[test]
+    fn wrong_method_lists_the_allowed_ones() {
+        assert_eq!(allowed(Method::PUT, "/docker/volumes"), Some(vec![Method::GET, Method::POST]));
+        assert_eq!(allowed(Method::POST, "/health"), Some(vec![Method::GET]));
+        assert_eq!(allowed(Method::DELETE, "/jobs/0123456789abcdef0123456789abcdef/cancel"), Some(vec![Method::POST]));
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        for path in ["/", "/nope", "/health/", "/docker/container/web", "/docker/container/web/inspect/more"] {
+            assert!(matches!(find(&Method::GET, path), RouteMatch::NotFound), "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_param_characters() {
+        for path in [
+            "/docker/container/-web/inspect",
+            "/docker/container/.hidden/inspect",
+            "/docker/container/web%20app/inspect",
+            "/docker/container//inspect",
+            "/jobs/not-a-job-id",
+            "/jobs/0123456789ABCDEF0123456789ABCDEF",
+            "/admin/registries/ghcr.io:port",
+        ] {
+            let method = if path.starts_with("/admin") { Method::DELETE } else { Method::GET };
+            assert!(matches!(find(&method, path), RouteMatch::NotFound), "{}", path);
+        }
+    }
+
+    #[test]
+    fn literal_segments_take_precedence() {
+        let (endpoint, params) = found(Method::POST, "/docker/volumes/prune").unwrap();
+        assert_eq!(endpoint, Endpoint::PruneVolumes);
+        assert_eq!(params.get("name"), None);
+        // Not the removal of a volume called prune
+        assert_eq!(allowed(Method::DELETE, "/docker/volumes/prune"), Some(vec![Method::POST]));
+
+        let (endpoint, params) = found(Method::DELETE, "/docker/volumes/data").unwrap();
+        assert_eq!(endpoint, Endpoint::RemoveVolume);
+        assert_eq!(params.get("name"), Some("data"));
+    }
+
+    #[test]
+    fn mutating_routes_are_not_get() {
+        // start, stop and rm kept the method clients used before the route table. A backup only
+        // reads and is audited because it exports data, WebSocket upgrades have to be GETs.
+        let exceptions = [
+            Endpoint::ContainerStart,
+            Endpoint::ContainerStop,
+            Endpoint::ContainerRm,
+            Endpoint::BackupVolume,
+            Endpoint::ContainerTerminal,
+        ];
+        for route in ROUTES.iter().filter(|route| route.mutating && !exceptions.contains(&route.endpoint)) {
+            assert_ne!(route.method, Method::GET, "{} changes things but is a GET", route.path);
+        }
+        assert_eq!(allowed(Method::GET, "/docker/container/web/restart"), Some(vec![Method::POST]));
+    }
+
+    #[test]
+    fn path_params_are_known() {
+        for route in ROUTES {
+            for segment in route.path.split('/') {
+                if let Some(name) = segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    assert!(param_regex(name).is_some(), "{} uses unknown param {}", route.path, name);
+                }
+            }
+        }
+    }
+}
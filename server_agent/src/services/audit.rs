@@ -0,0 +1,47 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use serde_json;
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::Response;
+
+use crate::audit::{AuditLog, AuditPage, AuditQuery};
+use crate::error::ApiError;
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+pub async fn list(query: AuditQuery, audit: Option<Arc<AuditLog>>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let audit = match audit {
+        Some(audit) => audit,
+        None => return Ok(ApiError::not_found("audit log is disabled").response()),
+    };
+
+    let offset = query.offset;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+    // Reading the log files is blocking
+    let result = tokio::task::spawn_blocking(move || audit.query(&query, offset, limit)).await;
+    match result {
+        Ok(Ok((entries, total))) => {
+            let page = AuditPage {
+                entries,
+                total,
+                offset,
+                limit,
+            };
+            let serialized = serde_json::to_string(&page).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Ok(Err(e)) => {
+            log::error!("Cannot read audit log: {}", e);
+            Ok(ApiError::internal("cannot read audit log").response())
+        }
+        Err(e) => {
+            log::error!("Audit query task failed: {}", e);
+            Ok(ApiError::internal("cannot read audit log").response())
+        }
+    }
+}
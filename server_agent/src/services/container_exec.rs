@@ -0,0 +1,165 @@
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+
+use bollard::container::LogOutput;
+use bollard::exec::{StartExecOptions, StartExecResults};
+use bollard::models::ExecConfig;
+
+use futures_util::TryStreamExt;
+use http_body_util::BodyExt;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, Response};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json;
+
+use crate::config;
+use crate::error::{ApiError, ErrorCode};
+use crate::util;
+
+/// Upper limit for the `timeout_secs` of a request.
+const MAX_TIMEOUT_SECS: u64 = 3600;
+/// stdout and stderr are each cut off after this many bytes.
+const MAX_OUTPUT_BYTES: usize = 1024 * 1024;
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ExecRequest {
+    /// Command and arguments, run without a shell, e.g. `["bin/rails", "db:migrate"]`
+    cmd: Vec<String>,
+    /// Extra environment variables as `NAME=value`
+    #[serde(default)]
+    env: Vec<String>,
+    /// Working directory inside the container, defaults to the image's
+    workdir: Option<String>,
+    /// User or `user:group` to run as, defaults to the container's
+    user: Option<String>,
+    /// Defaults to timeouts.exec_secs, at most 3600
+    timeout_secs: Option<u64>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ExecResult {
+    exit_code: i64,
+    stdout: String,
+    stderr: String,
+    /// Output beyond 1 MiB per stream was dropped
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    duration_ms: u64,
+}
+
+#[derive(Default)]
+struct Output {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    truncated: bool,
+}
+
+impl Output {
+    fn push(&mut self, log: LogOutput) {
+        let (buffer, message) = match log {
+            LogOutput::StdErr { message } => (&mut self.stderr, message),
+            LogOutput::StdOut { message } | LogOutput::Console { message } => (&mut self.stdout, message),
+            LogOutput::StdIn { .. } => return,
+        };
+        let room = MAX_OUTPUT_BYTES.saturating_sub(buffer.len());
+        if message.len() > room {
+            self.truncated = true;
+        }
+        buffer.extend_from_slice(&message[..message.len().min(room)]);
+    }
+}
+
+/// Run a command inside a running container and wait for it to exit. A non-zero exit code is a
+/// successful request, the caller decides what it means.
+pub async fn container_exec(request: Request<Full<Bytes>>, id: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match request.into_body().collect().await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    let body_bytes = body.to_bytes();
+    let exec: ExecRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    if exec.cmd.is_empty() || exec.cmd[0].is_empty() {
+        return Ok(ApiError::new(ErrorCode::InvalidBody, "cmd must not be empty").response());
+    }
+    if let Some(entry) = exec.env.iter().find(|e| e.split_once('=').is_none_or(|(name, _)| name.is_empty())) {
+        let message = format!("env entries must look like NAME=value (got {})", entry);
+        return Ok(ApiError::new(ErrorCode::InvalidBody, message).response());
+    }
+    let timeout_secs = exec.timeout_secs.unwrap_or(config::get().timeouts.exec_secs);
+    if timeout_secs == 0 || timeout_secs > MAX_TIMEOUT_SECS {
+        let message = format!("timeout_secs must be between 1 and {}", MAX_TIMEOUT_SECS);
+        return Ok(ApiError::new(ErrorCode::InvalidBody, message).response());
+    }
+
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let config = ExecConfig {
+        attach_stdout: Some(true),
+        attach_stderr: Some(true),
+        tty: Some(false),
+        env: Some(exec.env),
+        cmd: Some(exec.cmd),
+        user: exec.user,
+        working_dir: exec.workdir,
+        ..Default::default()
+    };
+    let exec_id = match docker.create_exec(id, config).await {
+        Ok(created) => created.id,
+        Err(e) => return Ok(ApiError::docker("exec create", e).response()),
+    };
+
+    let started = Instant::now();
+    let mut output_stream = match docker.start_exec(&exec_id, None::<StartExecOptions>).await {
+        Ok(StartExecResults::Attached { output, .. }) => output,
+        Ok(StartExecResults::Detached) => return Ok(ApiError::internal("exec started detached").response()),
+        Err(e) => return Ok(ApiError::docker("exec start", e).response()),
+    };
+    let mut output = Output::default();
+    let collect = async {
+        while let Some(log) = output_stream.try_next().await? {
+            output.push(log);
+        }
+        Ok::<(), bollard::errors::Error>(())
+    };
+    match tokio::time::timeout(Duration::from_secs(timeout_secs), collect).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => return Ok(ApiError::docker("exec output", e).response()),
+        Err(_) => {
+            // Docker cannot kill an exec, the process keeps running inside the container
+            let pid = docker.inspect_exec(&exec_id).await.ok().and_then(|i| i.pid);
+            log::warn!("Exec in {} did not finish within {}s (pid {:?})", id, timeout_secs, pid);
+            let message = format!(
+                "command did not finish within {}s and is still running in the container",
+                timeout_secs
+            );
+            return Ok(ApiError::new(ErrorCode::CommandTimedOut, message)
+                .with_details(serde_json::json!({ "timeout_secs": timeout_secs, "pid": pid }))
+                .response());
+        }
+    }
+
+    let exit_code = match docker.inspect_exec(&exec_id).await {
+        Ok(inspect) => inspect.exit_code.unwrap_or(-1),
+        Err(e) => return Ok(ApiError::docker("exec inspect", e).response()),
+    };
+    let result = ExecResult {
+        exit_code,
+        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        truncated: output.truncated,
+        duration_ms: started.elapsed().as_millis() as u64,
+    };
+    let serialized = serde_json::to_string(&result).unwrap();
+    Ok(Response::new(Full::new(Bytes::from(serialized))))
+}
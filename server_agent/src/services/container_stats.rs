@@ -0,0 +1,224 @@
+use std::convert::Infallible;
+
+use bollard::errors::Error as DockerError;
+use bollard::models::{ContainerBlkioStatEntry, ContainerStatsResponse};
+use bollard::query_parameters::InspectContainerOptions;
+use bollard::query_parameters::ListContainersOptionsBuilder;
+use bollard::query_parameters::StatsOptionsBuilder;
+use bollard::Docker;
+
+use futures_util::future;
+use futures_util::TryStreamExt;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::Response;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json;
+
+use crate::auth::ApiKey;
+use crate::error::ApiError;
+use crate::services::docker;
+use crate::sse;
+use crate::util;
+
+/// Query of `GET /docker/container/{id}/stats`.
+#[derive(Deserialize, JsonSchema)]
+pub struct StatsQuery {
+    /// Keep the connection open and send a sample about every second as Server-Sent Events
+    /// (`stats` events)
+    #[serde(default)]
+    stream: bool,
+}
+
+/// Resource usage of a container, computed the way `docker stats` does.
+#[derive(Serialize, JsonSchema)]
+pub struct ContainerStats {
+    id: String,
+    name: String,
+    /// Time of the sample (RFC 3339)
+    read: Option<String>,
+    /// Percent of one CPU, so up to 100 × online_cpus
+    cpu_percent: f64,
+    online_cpus: u32,
+    /// Memory in use without the inactive page cache, in bytes
+    memory_usage: u64,
+    /// Memory limit of the container, or of the host if it has none
+    memory_limit: u64,
+    memory_percent: f64,
+    /// Received bytes over all networks
+    net_rx_bytes: u64,
+    /// Sent bytes over all networks
+    net_tx_bytes: u64,
+    block_read_bytes: u64,
+    block_write_bytes: u64,
+    pids: u64,
+}
+
+impl ContainerStats {
+    fn from_response(stats: ContainerStatsResponse) -> ContainerStats {
+        let cpu = stats.cpu_stats.unwrap_or_default();
+        let precpu = stats.precpu_stats.unwrap_or_default();
+        let cpu_total = |s: &bollard::models::ContainerCpuStats| {
+            s.cpu_usage.as_ref().and_then(|u| u.total_usage).unwrap_or(0)
+        };
+        let online_cpus = cpu.online_cpus.unwrap_or_else(|| {
+            let percpu = cpu.cpu_usage.as_ref().and_then(|u| u.percpu_usage.as_ref());
+            percpu.map_or(0, |p| p.len() as u32)
+        });
+        let cpu_delta = cpu_total(&cpu).saturating_sub(cpu_total(&precpu));
+        let system_delta = cpu
+            .system_cpu_usage
+            .unwrap_or(0)
+            .saturating_sub(precpu.system_cpu_usage.unwrap_or(0));
+        // Without a previous sample the deltas are the totals since boot, which means nothing
+        let cpu_percent = if precpu.system_cpu_usage.unwrap_or(0) > 0 && system_delta > 0 {
+            cpu_delta as f64 / system_delta as f64 * online_cpus as f64 * 100.0
+        } else {
+            0.0
+        };
+
+        let memory = stats.memory_stats.unwrap_or_default();
+        // cgroup v1 reports total_inactive_file, v2 inactive_file
+        let inactive_file = memory.stats.as_ref().and_then(|s| {
+            s.get("total_inactive_file").or_else(|| s.get("inactive_file")).copied()
+        });
+        let memory_usage = memory.usage.unwrap_or(0).saturating_sub(inactive_file.unwrap_or(0));
+        let memory_limit = memory.limit.unwrap_or(0);
+        let memory_percent = if memory_limit > 0 {
+            memory_usage as f64 / memory_limit as f64 * 100.0
+        } else {
+            0.0
+        };
+
+        let networks = stats.networks.unwrap_or_default();
+        let blkio = stats
+            .blkio_stats
+            .and_then(|b| b.io_service_bytes_recursive)
+            .unwrap_or_default();
+
+        ContainerStats {
+            id: stats.id.unwrap_or_default(),
+            name: stats.name.unwrap_or_default().trim_start_matches('/').to_string(),
+            read: stats.read,
+            cpu_percent,
+            online_cpus,
+            memory_usage,
+            memory_limit,
+            memory_percent,
+            net_rx_bytes: networks.values().filter_map(|n| n.rx_bytes).sum(),
+            net_tx_bytes: networks.values().filter_map(|n| n.tx_bytes).sum(),
+            block_read_bytes: block_bytes(&blkio, "read"),
+            block_write_bytes: block_bytes(&blkio, "write"),
+            pids: stats.pids_stats.and_then(|p| p.current).unwrap_or(0),
+        }
+    }
+}
+
+/// Sum of the block I/O entries of one operation; cgroup v1 writes `Read`, v2 `read`.
+fn block_bytes(entries: &[ContainerBlkioStatEntry], op: &str) -> u64 {
+    entries
+        .iter()
+        .filter(|e| e.op.as_deref().is_some_and(|o| o.eq_ignore_ascii_case(op)))
+        .filter_map(|e| e.value)
+        .sum()
+}
+
+/// One sample with a CPU reading; the daemon waits for a second sample to compute it.
+async fn sample(docker: &Docker, id: &str) -> Result<ContainerStats, DockerError> {
+    let options = StatsOptionsBuilder::default().stream(false).one_shot(false).build();
+    let mut stats_stream = docker.stats(id, Some(options));
+    match stats_stream.try_next().await? {
+        Some(stats) => Ok(ContainerStats::from_response(stats)),
+        None => Err(DockerError::DockerResponseServerError {
+            status_code: 404,
+            message: format!("no stats for container {}", id),
+        }),
+    }
+}
+
+pub async fn container_stats(id: &str, query: StatsQuery) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response().map(BodyExt::boxed)),
+    };
+
+    if !query.stream {
+        return match sample(&docker, id).await {
+            Ok(stats) => {
+                let serialized = serde_json::to_string(&stats).unwrap();
+                Ok(Response::new(Full::new(Bytes::from(serialized)).boxed()))
+            }
+            Err(e) => Ok(ApiError::docker("container stats", e).response().map(BodyExt::boxed)),
+        };
+    }
+
+    // Fail before the stream starts if the container does not exist
+    if let Err(e) = docker.inspect_container(id, None::<InspectContainerOptions>).await {
+        return Ok(ApiError::docker("container inspect", e).response().map(BodyExt::boxed));
+    }
+    let id = id.to_string();
+    Ok(sse::response(move |sender| async move {
+        let options = StatsOptionsBuilder::default().stream(true).one_shot(false).build();
+        let mut stats_stream = docker.stats(&id, Some(options));
+        let mut first = true;
+        loop {
+            match stats_stream.try_next().await {
+                Ok(Some(stats)) => {
+                    // The first sample of a running container has no previous one for the CPU usage
+                    let without_cpu = stats.precpu_stats.as_ref().and_then(|p| p.system_cpu_usage).is_none();
+                    if first && without_cpu && stats.cpu_stats.as_ref().and_then(|c| c.system_cpu_usage).is_some() {
+                        first = false;
+                        continue;
+                    }
+                    first = false;
+                    if !sender.send("stats", &ContainerStats::from_response(stats)).await {
+                        return;
+                    }
+                }
+                // The container is gone
+                Ok(None) => {
+                    sender.send("end", &serde_json::json!({})).await;
+                    return;
+                }
+                Err(e) => {
+                    sender.send("error", &ApiError::docker("container stats", e)).await;
+                    return;
+                }
+            }
+        }
+    }))
+}
+
+/// Stats of all running containers the key may see, sampled in parallel.
+pub async fn all_stats(key: &ApiKey) -> Result<Response<Full<Bytes>>, Infallible> {
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let options = ListContainersOptionsBuilder::default().all(false).build();
+    let mut containers = match docker.list_containers(Some(options)).await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker("container list", e).response()),
+    };
+    docker::retain_allowed(&mut containers, key);
+
+    let ids: Vec<String> = containers.into_iter().filter_map(|c| c.id).collect();
+    let samples = future::join_all(ids.iter().map(|id| sample(&docker, id))).await;
+    let mut stats = Vec::with_capacity(samples.len());
+    for sample in samples {
+        match sample {
+            Ok(sample) => stats.push(sample),
+            // Stopped or removed since the listing
+            Err(DockerError::DockerResponseServerError { status_code: 404 | 409, .. }) => {}
+            Err(e) => return Ok(ApiError::docker("container stats", e).response()),
+        }
+    }
+
+    let serialized = serde_json::to_string(&stats).unwrap();
+    Ok(Response::new(Full::new(Bytes::from(serialized))))
+}
@@ -0,0 +1,304 @@
+use std::convert::Infallible;
+use std::time::Duration;
+
+use bollard::exec::{StartExecOptions, StartExecResults};
+use bollard::models::ExecConfig;
+use bollard::query_parameters::ResizeExecOptionsBuilder;
+use bollard::Docker;
+
+use futures_util::{SinkExt, StreamExt, TryStreamExt};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::upgrade::Upgraded;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use url::form_urlencoded;
+
+use crate::config;
+use crate::error::{ApiError, ErrorCode};
+use crate::shutdown;
+use crate::util;
+
+/// Keeps proxies from dropping a terminal the user is just looking at.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Largest terminal size accepted for `cols` and `rows`.
+const MAX_TERMINAL_SIZE: u16 = 1000;
+
+type Socket = WebSocketStream<TokioIo<Upgraded>>;
+
+/// Query of `GET /docker/container/{id}/terminal`.
+#[derive(Deserialize, JsonSchema)]
+pub struct TerminalQuery {
+    /// Command and its arguments, one `cmd` parameter each (`cmd=bash&cmd=-lc&cmd=...`), defaults to `/bin/sh`
+    #[serde(default)]
+    cmd: Vec<String>,
+    /// User or `user:group` to run as, defaults to the container's
+    user: Option<String>,
+    /// Working directory inside the container, defaults to the image's
+    workdir: Option<String>,
+    /// Initial terminal width, defaults to 80
+    #[serde(default = "default_cols")]
+    cols: u16,
+    /// Initial terminal height, defaults to 24
+    #[serde(default = "default_rows")]
+    rows: u16,
+}
+
+impl TerminalQuery {
+    /// Parse the raw query string. Unlike the other queries `cmd` may repeat, which the plain
+    /// deserializer rejects as a duplicate field, so its values are collected separately.
+    pub fn parse(raw: &str) -> Result<TerminalQuery, String> {
+        let mut cmd = Vec::new();
+        let mut rest = form_urlencoded::Serializer::new(String::new());
+        for (name, value) in form_urlencoded::parse(raw.as_bytes()) {
+            if name == "cmd" {
+                cmd.push(value.into_owned());
+            } else {
+                rest.append_pair(&name, &value);
+            }
+        }
+        let mut query: TerminalQuery =
+            serde_urlencoded::from_str(&rest.finish()).map_err(|e| format!("invalid query: {}", e))?;
+        query.cmd = cmd;
+        Ok(query)
+    }
+}
+
+fn default_cols() -> u16 {
+    80
+}
+
+fn default_rows() -> u16 {
+    24
+}
+
+/// Text messages from the client; binary messages are written to stdin as they are.
+#[derive(Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientMessage {
+    Stdin { data: String },
+    Resize { cols: u16, rows: u16 },
+}
+
+/// Text messages to the client; the terminal output arrives as binary messages.
+#[derive(Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerMessage {
+    /// The command exited, the agent closes the socket next
+    Exit { exit_code: i64 },
+    /// No input for `idle_secs`, the agent closes the socket next
+    Timeout { idle_secs: u64 },
+    Error { error: ApiError },
+}
+
+fn valid_size(cols: u16, rows: u16) -> bool {
+    (1..=MAX_TERMINAL_SIZE).contains(&cols) && (1..=MAX_TERMINAL_SIZE).contains(&rows)
+}
+
+/// The `Sec-WebSocket-Key` of a WebSocket handshake, or what is wrong with the request.
+fn websocket_key(headers: &HeaderMap) -> Result<&HeaderValue, &'static str> {
+    let contains_token = |name: header::HeaderName, token: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|v| v.trim().eq_ignore_ascii_case(token))
+    };
+    if !contains_token(header::CONNECTION, "upgrade") || !contains_token(header::UPGRADE, "websocket") {
+        return Err("this endpoint only speaks WebSocket (Connection: Upgrade, Upgrade: websocket)");
+    }
+    if headers.get(header::SEC_WEBSOCKET_VERSION).is_none_or(|v| v != "13") {
+        return Err("only WebSocket version 13 is supported");
+    }
+    headers.get(header::SEC_WEBSOCKET_KEY).ok_or("Sec-WebSocket-Key is missing")
+}
+
+/// Open an interactive TTY exec in a running container and hand it over to a WebSocket. Errors
+/// before the upgrade, like a stopped container, are regular JSON responses.
+pub async fn terminal(
+    mut request: Request<Full<Bytes>>,
+    id: &str,
+    query: TerminalQuery,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let accept_key = match websocket_key(request.headers()) {
+        Ok(key) => derive_accept_key(key.as_bytes()),
+        Err(e) => return Ok(ApiError::new(ErrorCode::InvalidRequest, e).response()),
+    };
+    let cmd = match query.cmd {
+        cmd if cmd.is_empty() => vec!["/bin/sh".to_string()],
+        cmd if cmd[0].is_empty() => {
+            return Ok(ApiError::new(ErrorCode::InvalidQuery, "cmd must not be empty").response());
+        }
+        cmd => cmd,
+    };
+    if !valid_size(query.cols, query.rows) {
+        let message = format!("cols and rows must be between 1 and {}", MAX_TERMINAL_SIZE);
+        return Ok(ApiError::new(ErrorCode::InvalidQuery, message).response());
+    }
+
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let config = ExecConfig {
+        attach_stdin: Some(true),
+        attach_stdout: Some(true),
+        attach_stderr: Some(true),
+        tty: Some(true),
+        console_size: Some(vec![query.rows as usize, query.cols as usize]),
+        env: Some(vec!["TERM=xterm-256color".to_string()]),
+        cmd: Some(cmd),
+        user: query.user,
+        working_dir: query.workdir,
+        ..Default::default()
+    };
+    let exec_id = match docker.create_exec(id, config).await {
+        Ok(created) => created.id,
+        Err(e) => return Ok(ApiError::docker("exec create", e).response()),
+    };
+    let options = StartExecOptions {
+        tty: true,
+        ..Default::default()
+    };
+    let (output, input) = match docker.start_exec(&exec_id, Some(options)).await {
+        Ok(StartExecResults::Attached { output, input }) => (output, input),
+        Ok(StartExecResults::Detached) => return Ok(ApiError::internal("exec started detached").response()),
+        Err(e) => return Ok(ApiError::docker("exec start", e).response()),
+    };
+
+    // Resolves once the 101 below went out
+    let upgrade = hyper::upgrade::on(&mut request);
+    let name = format!("terminal {}", id);
+    let id = id.to_string();
+    tokio::spawn(shutdown::track(name, async move {
+        let upgraded = match upgrade.await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                log::warn!("Terminal for {}: WebSocket upgrade failed: {}", id, e);
+                return;
+            }
+        };
+        let socket = WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await;
+        log::info!("Terminal for {} opened (exec {})", id, exec_id);
+        session(socket, &docker, &exec_id, output, input).await;
+        log::info!("Terminal for {} closed", id);
+    }));
+
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(header::SEC_WEBSOCKET_ACCEPT, HeaderValue::from_str(&accept_key).unwrap());
+    Ok(response)
+}
+
+/// Shuttle between the socket and the exec until the command exits, the client goes away, the
+/// client is idle for too long or the agent aborts on shutdown.
+async fn session(
+    socket: Socket,
+    docker: &Docker,
+    exec_id: &str,
+    mut output: impl futures_util::Stream<Item = Result<bollard::container::LogOutput, bollard::errors::Error>> + Unpin,
+    mut input: impl tokio::io::AsyncWrite + Unpin,
+) {
+    let (mut sink, mut source) = socket.split();
+    let idle_secs = config::get().timeouts.terminal_idle_secs;
+    let idle_timeout = Duration::from_secs(idle_secs);
+    let idle = tokio::time::sleep(idle_timeout);
+    tokio::pin!(idle);
+    let aborted = shutdown::aborted();
+    tokio::pin!(aborted);
+    let mut ping = tokio::time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+
+    let close = loop {
+        tokio::select! {
+            _ = &mut aborted => break CloseFrame { code: CloseCode::Away, reason: "agent shutting down".into() },
+            _ = &mut idle => {
+                let _ = sink.send(text(&ServerMessage::Timeout { idle_secs })).await;
+                break CloseFrame { code: CloseCode::Normal, reason: "idle timeout".into() };
+            }
+            _ = ping.tick() => {
+                if sink.send(Message::Ping(Bytes::new())).await.is_err() {
+                    return;
+                }
+            }
+            log = output.try_next() => match log {
+                Ok(Some(log)) => {
+                    if sink.send(Message::Binary(log.into_bytes())).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => {
+                    let exit_code = match docker.inspect_exec(exec_id).await {
+                        Ok(inspect) => inspect.exit_code.unwrap_or(-1),
+                        Err(e) => {
+                            log::warn!("Terminal exec {}: inspect failed: {}", exec_id, e);
+                            -1
+                        }
+                    };
+                    let _ = sink.send(text(&ServerMessage::Exit { exit_code })).await;
+                    break CloseFrame { code: CloseCode::Normal, reason: "exited".into() };
+                }
+                Err(e) => {
+                    let error = ApiError::docker("exec output", e);
+                    let _ = sink.send(text(&ServerMessage::Error { error })).await;
+                    break CloseFrame { code: CloseCode::Error, reason: "exec failed".into() };
+                }
+            },
+            message = source.next() => {
+                let stdin = match message {
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(Message::Text(data))) => match serde_json::from_str::<ClientMessage>(&data) {
+                        Ok(ClientMessage::Stdin { data }) => Bytes::from(data),
+                        Ok(ClientMessage::Resize { cols, rows }) => {
+                            idle.as_mut().reset(Instant::now() + idle_timeout);
+                            if valid_size(cols, rows) {
+                                let options = ResizeExecOptionsBuilder::new().w(cols as i32).h(rows as i32).build();
+                                if let Err(e) = docker.resize_exec(exec_id, options).await {
+                                    log::warn!("Terminal exec {}: resize failed: {}", exec_id, e);
+                                }
+                            }
+                            continue;
+                        }
+                        Err(e) => {
+                            let error = ApiError::new(ErrorCode::InvalidBody, format!("invalid message: {}", e));
+                            if sink.send(text(&ServerMessage::Error { error })).await.is_err() {
+                                return;
+                            }
+                            continue;
+                        }
+                    },
+                    // Pings are answered by tungstenite
+                    Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                    // The client left; dropping stdin ends an interactive shell
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                };
+                idle.as_mut().reset(Instant::now() + idle_timeout);
+                if let Err(e) = input.write_all(&stdin).await {
+                    log::warn!("Terminal exec {}: writing stdin failed: {}", exec_id, e);
+                    break CloseFrame { code: CloseCode::Error, reason: "stdin closed".into() };
+                }
+            }
+        }
+    };
+    let _ = sink.send(Message::Close(Some(close))).await;
+}
+
+fn text(message: &ServerMessage) -> Message {
+    Message::text(serde_json::to_string(message).unwrap())
+}
@@ -0,0 +1,570 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use bollard::auth::DockerCredentials;
+use bollard::errors::Error as DockerError;
+use bollard::models::{
+    ContainerCreateBody, ContainerCreateResponse, ContainerInspectResponse, ContainerStateStatusEnum,
+    HealthStatusEnum,
+};
+use bollard::query_parameters::CreateContainerOptionsBuilder;
+use bollard::query_parameters::InspectContainerOptions;
+use bollard::query_parameters::RemoveContainerOptionsBuilder;
+use bollard::query_parameters::RenameContainerOptionsBuilder;
+use bollard::query_parameters::StartContainerOptions;
+use bollard::query_parameters::StopContainerOptionsBuilder;
+use bollard::Docker;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{self, Value};
+
+use crate::config;
+use crate::error::{ApiError, ErrorCode};
+use crate::image_ref::{ImageRef, PullPolicy};
+use crate::jobs;
+use crate::services::{docker, networks};
+
+/// The requested create body as JSON, so the next deploy can tell exactly what changed.
+const CONFIG_LABEL: &str = "server_agent.config";
+/// Name the container was deployed under, so a `-previous` leftover can be told apart from an
+/// unrelated container that happens to have that name.
+const SERVICE_LABEL: &str = "server_agent.service";
+/// The old container is kept under its name with this suffix until the new one runs.
+const PREVIOUS_SUFFIX: &str = "-previous";
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateAction {
+    Created,
+    /// Replaced, the old container was removed
+    Updated,
+    /// Same configuration, the container was only started if it was stopped
+    Unchanged,
+}
+
+/// Result of the POST /docker/container job.
+#[derive(Serialize, JsonSchema)]
+pub struct UpdateResult {
+    action: UpdateAction,
+    /// ID of the container now running under the requested name
+    id: String,
+    /// Top level fields of the create body that differ from the old container
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    changes: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+}
+
+fn result(action: UpdateAction, id: String, changes: Vec<String>, warnings: Vec<String>) -> Value {
+    serde_json::to_value(UpdateResult {
+        action,
+        id,
+        changes,
+        warnings,
+    })
+    .unwrap()
+}
+
+/// Create the container `name`, or replace it when its configuration differs from `config` or
+/// its image now resolves to a different ID: stop and rename the old one, create and start the
+/// new one and wait until it runs (or is healthy). The old container is removed on success and
+/// restored on failure.
+pub async fn create_or_update(
+    docker: Docker,
+    name: String,
+    config: ContainerCreateBody,
+    credentials: Option<DockerCredentials>,
+    image: ImageRef,
+    pull_policy: PullPolicy,
+) -> Result<Value, ApiError> {
+    let requested = serde_json::to_value(&config).unwrap();
+    let config = with_config_label(config, &requested, &name);
+
+    // Before the old container stops, to keep the downtime short
+    let image_id = docker::ensure_image(&docker, &image, pull_policy, credentials).await?;
+    networks::ensure_traefik_network(&docker).await?;
+
+    let existing = match docker.inspect_container(&name, None::<InspectContainerOptions>).await {
+        Ok(existing) => existing,
+        Err(DockerError::DockerResponseServerError { status_code: 404, .. }) => {
+            let created = create(&docker, &name, config).await?;
+            start_and_wait(&docker, &created.id).await?;
+            return Ok(result(UpdateAction::Created, created.id, Vec::new(), created.warnings));
+        }
+        Err(e) => return Err(ApiError::docker("container inspect", e)),
+    };
+    let old_id = existing.id.clone().unwrap_or_default();
+    let was_running = is_running(&existing);
+
+    let mut changes = changes(&requested, &existing);
+    if existing.image.as_deref() != Some(image_id.as_str()) && !changes.iter().any(|c| c == "Image") {
+        // Same reference, but the tag moved to another image
+        changes.push("Image".to_string());
+    }
+    if changes.is_empty() {
+        jobs::step("Configuration unchanged");
+        if !was_running {
+            start_and_wait(&docker, &old_id).await?;
+        }
+        return Ok(result(UpdateAction::Unchanged, old_id, changes, Vec::new()));
+    }
+    jobs::step(&format!("Changed: {}", changes.join(", ")));
+
+    let previous_name = format!("{}{}", name, PREVIOUS_SUFFIX);
+    remove_leftover(&docker, &previous_name, &name).await?;
+    // From the stop on, the old container is only back after a rollback, so a cancel or
+    // shutdown must not drop the swap halfway
+    jobs::detach(swap(docker, name, config, old_id, was_running, changes)).await
+}
+
+/// Stop the old container and move it aside, then create and start the new one. The old one is
+/// removed on success and restored on failure.
+async fn swap(
+    docker: Docker,
+    name: String,
+    config: ContainerCreateBody,
+    old_id: String,
+    was_running: bool,
+    changes: Vec<String>,
+) -> Result<Value, ApiError> {
+    let previous_name = format!("{}{}", name, PREVIOUS_SUFFIX);
+    if was_running {
+        jobs::step("Stopping the old container");
+        let options = StopContainerOptionsBuilder::default()
+            .t(config::get().timeouts.container_stop_secs as i32)
+            .build();
+        docker
+            .stop_container(&old_id, Some(options))
+            .await
+            .map_err(|e| ApiError::docker("container stop", e))?;
+    }
+
+    // From here on every failure restarts the old container, see `rollback`
+    let outcome = match rename(&docker, &old_id, &previous_name).await {
+        Ok(()) => match create(&docker, &name, config).await {
+            Ok(created) => match start_and_wait(&docker, &created.id).await {
+                Ok(()) => Ok(created),
+                Err(e) => Err(Failed::after_create(e, created.id)),
+            },
+            Err(e) => Err(Failed::after_rename(e)),
+        },
+        Err(e) => Err(Failed::before_rename(e)),
+    };
+    match outcome {
+        Ok(created) => {
+            jobs::step("Removing the old container");
+            let mut warnings = created.warnings;
+            let options = RemoveContainerOptionsBuilder::default().force(true).build();
+            if let Err(e) = docker.remove_container(&old_id, Some(options)).await {
+                // The new container runs, the leftover is cleaned up by the next update
+                log::warn!("Cannot remove old container {}: {}", previous_name, e);
+                warnings.push(format!("old container {} was not removed: {}", previous_name, e));
+            }
+            Ok(result(UpdateAction::Updated, created.id, changes, warnings))
+        }
+        Err(failed) => {
+            jobs::step("Update failed, rolling back to the old container");
+            let rollback = rollback(&docker, &name, &old_id, was_running, &failed).await;
+            let e = match rollback {
+                Ok(()) => failed.error.with_detail("rolled_back", Value::Bool(true)),
+                Err(rollback_error) => {
+                    log::error!("Rolling back {} failed: {}", name, rollback_error);
+                    failed
+                        .error
+                        .with_detail("rolled_back", Value::Bool(false))
+                        .with_detail("rollback_error", Value::String(rollback_error.to_string()))
+                }
+            };
+            Err(e)
+        }
+    }
+}
+
+/// How far a swap got before it failed, so the rollback only undoes what happened.
+struct Failed {
+    error: ApiError,
+    /// The old container was renamed to its `-previous` name
+    renamed: bool,
+    /// The new container was created
+    new_id: Option<String>,
+}
+
+impl Failed {
+    fn before_rename(error: ApiError) -> Failed {
+        Failed {
+            error,
+            renamed: false,
+            new_id: None,
+        }
+    }
+
+    fn after_rename(error: ApiError) -> Failed {
+        Failed {
+            error,
+            renamed: true,
+            new_id: None,
+        }
+    }
+
+    fn after_create(error: ApiError, new_id: String) -> Failed {
+        Failed {
+            error,
+            renamed: true,
+            new_id: Some(new_id),
+        }
+    }
+}
+
+fn with_config_label(mut config: ContainerCreateBody, requested: &Value, name: &str) -> ContainerCreateBody {
+    let labels = config.labels.get_or_insert_with(HashMap::new);
+    labels.insert(CONFIG_LABEL.to_string(), requested.to_string());
+    labels.insert(SERVICE_LABEL.to_string(), name.to_string());
+    config
+}
+
+fn is_running(container: &ContainerInspectResponse) -> bool {
+    container.state.as_ref().and_then(|s| s.running).unwrap_or(false)
+}
+
+/// Top level fields of the create body that differ from the existing container. Containers the
+/// agent created carry the body they were created from in a label; for other containers the
+/// requested values are looked up in the inspect data.
+fn changes(requested: &Value, existing: &ContainerInspectResponse) -> Vec<String> {
+    let empty = serde_json::Map::new();
+    let requested = requested.as_object().unwrap_or(&empty);
+    let previous = existing
+        .config
+        .as_ref()
+        .and_then(|c| c.labels.as_ref())
+        .and_then(|labels| labels.get(CONFIG_LABEL))
+        .and_then(|label| serde_json::from_str::<Value>(label).ok());
+    if let Some(Value::Object(previous)) = previous {
+        let keys: BTreeSet<&String> = requested.keys().chain(previous.keys()).collect();
+        return keys
+            .into_iter()
+            .filter(|key| requested.get(*key) != previous.get(*key))
+            .cloned()
+            .collect();
+    }
+
+    let inspect = serde_json::to_value(existing).unwrap();
+    requested
+        .iter()
+        .filter(|(key, wanted)| {
+            let actual = match key.as_str() {
+                "HostConfig" => &inspect["HostConfig"],
+                "NetworkingConfig" => &inspect["NetworkSettings"]["Networks"],
+                _ => &inspect["Config"][key.as_str()],
+            };
+            let wanted = match key.as_str() {
+                // Only the network names are comparable, docker fills in the endpoint details
+                "NetworkingConfig" => &wanted["EndpointsConfig"],
+                _ => *wanted,
+            };
+            !contains(actual, wanted, key.as_str() == "NetworkingConfig")
+        })
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+/// Whether `actual` has everything `wanted` sets. Docker adds image defaults to lists and maps,
+/// so extra entries in `actual` are fine. With `keys_only`, only the keys of maps are compared.
+fn contains(actual: &Value, wanted: &Value, keys_only: bool) -> bool {
+    match (actual, wanted) {
+        (_, Value::Null) => true,
+        (Value::Object(actual), Value::Object(wanted)) => wanted.iter().all(|(key, value)| match actual.get(key) {
+            Some(_) if keys_only => true,
+            Some(actual) => contains(actual, value, false),
+            None => value.is_null(),
+        }),
+        (Value::Array(actual), Value::Array(wanted)) => {
+            wanted.iter().all(|value| actual.iter().any(|a| contains(a, value, false)))
+        }
+        (actual, wanted) => actual == wanted,
+    }
+}
+
+async fn create(docker: &Docker, name: &str, config: ContainerCreateBody) -> Result<ContainerCreateResponse, ApiError> {
+    jobs::step(&format!("Creating container {}", name));
+    let options = CreateContainerOptionsBuilder::default().name(name).build();
+    docker
+        .create_container(Some(options), config)
+        .await
+        .map_err(|e| ApiError::docker("container create", e))
+}
+
+/// Start a container and wait until it runs, or until it is healthy if it has a health check.
+async fn start_and_wait(docker: &Docker, id: &str) -> Result<(), ApiError> {
+    jobs::step("Starting container");
+    docker
+        .start_container(id, None::<StartContainerOptions>)
+        .await
+        .map_err(|e| ApiError::docker("container start", e))?;
+
+    let timeout = Duration::from_secs(config::get().timeouts.container_start_secs);
+    let deadline = Instant::now() + timeout;
+    loop {
+        // Give a crashing container a moment to exit before it counts as running
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let inspect = docker
+            .inspect_container(id, None::<InspectContainerOptions>)
+            .await
+            .map_err(|e| ApiError::docker("container inspect", e))?;
+        let state = inspect.state.unwrap_or_default();
+        let health = state.health.as_ref().and_then(|h| h.status);
+        match (state.status, health) {
+            (Some(ContainerStateStatusEnum::RUNNING), Some(HealthStatusEnum::HEALTHY))
+            | (
+                Some(ContainerStateStatusEnum::RUNNING),
+                None | Some(HealthStatusEnum::NONE) | Some(HealthStatusEnum::EMPTY),
+            ) => return Ok(()),
+            (_, Some(HealthStatusEnum::UNHEALTHY)) => {
+                return Err(ApiError::new(ErrorCode::ContainerFailed, "the new container is unhealthy"))
+            }
+            (Some(ContainerStateStatusEnum::EXITED | ContainerStateStatusEnum::DEAD), _) => {
+                let exit_code = state.exit_code.unwrap_or_default();
+                return Err(ApiError::new(
+                    ErrorCode::ContainerFailed,
+                    format!("the new container exited with code {}", exit_code),
+                )
+                .with_detail("exit_code", Value::from(exit_code)));
+            }
+            _ => {}
+        }
+        if Instant::now() >= deadline {
+            return Err(ApiError::new(
+                ErrorCode::ContainerFailed,
+                format!("the new container was not ready within {}s", timeout.as_secs()),
+            ));
+        }
+    }
+}
+
+async fn rename(docker: &Docker, id: &str, name: &str) -> Result<(), ApiError> {
+    let options = RenameContainerOptionsBuilder::default().name(name).build();
+    docker
+        .rename_container(id, options)
+        .await
+        .map_err(|e| ApiError::docker("container rename", e))
+}
+
+/// A `-previous` container left behind by an interrupted update of `service` would block the
+/// rename. Only a container the agent deployed as `service` is removed, anything else is a 409.
+async fn remove_leftover(docker: &Docker, name: &str, service: &str) -> Result<(), ApiError> {
+    let leftover = match docker.inspect_container(name, None::<InspectContainerOptions>).await {
+        Ok(leftover) => leftover,
+        Err(DockerError::DockerResponseServerError { status_code: 404, .. }) => return Ok(()),
+        Err(e) => return Err(ApiError::docker("container inspect", e)),
+    };
+    let labels = leftover.config.as_ref().and_then(|c| c.labels.as_ref());
+    let ours = labels.is_some_and(|labels| {
+        labels.contains_key(CONFIG_LABEL) && labels.get(SERVICE_LABEL).map(String::as_str) == Some(service)
+    });
+    if !ours {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            format!("container {} is in the way and was not left behind by an update of {}", name, service),
+        ));
+    }
+
+    let options = RemoveContainerOptionsBuilder::default().force(true).build();
+    match docker.remove_container(name, Some(options)).await {
+        Ok(()) => {
+            jobs::step(&format!("Removed leftover container {}", name));
+            Ok(())
+        }
+        Err(DockerError::DockerResponseServerError { status_code: 404, .. }) => Ok(()),
+        Err(e) => Err(ApiError::docker("container rm", e)),
+    }
+}
+
+/// Remove the new container, put the old one back under its name and start it again if it ran.
+async fn rollback(docker: &Docker, name: &str, old_id: &str, was_running: bool, failed: &Failed) -> Result<(), ApiError> {
+    if let Some(new_id) = &failed.new_id {
+        let options = RemoveContainerOptionsBuilder::default().force(true).build();
+        docker
+            .remove_container(new_id, Some(options))
+            .await
+            .map_err(|e| ApiError::docker("container rm", e))?;
+    }
+    if failed.renamed {
+        rename(docker, old_id, name).await?;
+    }
+    if was_running {
+        docker
+            .start_container(old_id, None::<StartContainerOptions>)
+            .await
+            .map_err(|e| ApiError::docker("container start", e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    use bollard::API_DEFAULT_VERSION;
+    use http_body_util::Full;
+    use hyper::body::Bytes;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use serde_json::json;
+
+    use super::*;
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    /// A Docker API on a local port that answers with `respond(call)` and records every call as
+    /// `METHOD /path?query` without the API version.
+    async fn fake_docker(respond: fn(&str) -> (StatusCode, Value)) -> (Docker, Calls) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let calls = Calls::default();
+        let recorded = calls.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let recorded = recorded.clone();
+                let service = service_fn(move |request: Request<hyper::body::Incoming>| {
+                    let path = request.uri().path();
+                    let path = &path[path.find("/containers").unwrap_or(0)..];
+                    let call = match request.uri().query() {
+                        Some(query) => format!("{} {}?{}", request.method(), path, query),
+                        None => format!("{} {}", request.method(), path),
+                    };
+                    let (status, body) = respond(&call);
+                    recorded.lock().unwrap().push(call);
+                    let body = if status == StatusCode::NO_CONTENT { String::new() } else { body.to_string() };
+                    let response = Response::builder()
+                        .status(status)
+                        .header("Content-Type", "application/json")
+                        .body(Full::new(Bytes::from(body)))
+                        .unwrap();
+                    async move { Ok::<_, Infallible>(response) }
+                });
+                tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        let docker = Docker::connect_with_http(&format!("tcp://{}", addr), 10, API_DEFAULT_VERSION).unwrap();
+        (docker, calls)
+    }
+
+    fn failure(message: &str) -> (StatusCode, Value) {
+        (StatusCode::INTERNAL_SERVER_ERROR, json!({ "message": message }))
+    }
+
+    async fn swap_web(docker: Docker) -> Result<Value, ApiError> {
+        config::init_for_tests();
+        let changes = vec!["Image".to_string()];
+        swap(docker, "web".to_string(), ContainerCreateBody::default(), "old".to_string(), true, changes).await
+    }
+
+    #[tokio::test]
+    async fn failed_rename_restarts_the_old_container() {
+        let (docker, calls) = fake_docker(|call| match call {
+            c if c.contains("/rename") => failure("name in use"),
+            _ => (StatusCode::NO_CONTENT, Value::Null),
+        })
+        .await;
+
+        let error = swap_web(docker).await.unwrap_err();
+        assert_eq!(error.details.as_ref().and_then(|d| d.get("rolled_back")), Some(&Value::Bool(true)));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "POST /containers/old/stop?t=10",
+                "POST /containers/old/rename?name=web-previous",
+                "POST /containers/old/start",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_create_renames_back_and_restarts() {
+        let (docker, calls) = fake_docker(|call| match call {
+            c if c.starts_with("POST /containers/create") => failure("no such image"),
+            _ => (StatusCode::NO_CONTENT, Value::Null),
+        })
+        .await;
+
+        let error = swap_web(docker).await.unwrap_err();
+        assert_eq!(error.details.as_ref().and_then(|d| d.get("rolled_back")), Some(&Value::Bool(true)));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "POST /containers/old/stop?t=10",
+                "POST /containers/old/rename?name=web-previous",
+                "POST /containers/create?name=web&platform=",
+                "POST /containers/old/rename?name=web",
+                "POST /containers/old/start",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_stop_leaves_the_old_container_alone() {
+        let (docker, calls) = fake_docker(|_| failure("cannot stop")).await;
+
+        let error = swap_web(docker).await.unwrap_err();
+        assert!(error.details.as_ref().and_then(|d| d.get("rolled_back")).is_none());
+        assert_eq!(*calls.lock().unwrap(), ["POST /containers/old/stop?t=10"]);
+    }
+
+    fn inspect(value: Value) -> ContainerInspectResponse {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn changes_against_the_config_label() {
+        let previous = json!({ "Image": "nginx:1", "Env": ["A=1"], "Labels": { "x": "1" } });
+        let existing = inspect(json!({ "Config": { "Labels": { CONFIG_LABEL: previous.to_string() } } }));
+
+        assert!(changes(&previous, &existing).is_empty());
+        let requested = json!({ "Image": "nginx:2", "Env": ["A=1"], "Cmd": ["run"] });
+        // Fields dropped from the request count as changed too
+        assert_eq!(changes(&requested, &existing), ["Cmd", "Image", "Labels"]);
+    }
+
+    #[test]
+    fn changes_against_inspect_data() {
+        let existing = inspect(json!({
+            "Config": { "Image": "nginx:1", "Env": ["PATH=/usr/bin", "A=1"], "Labels": { "x": "1", "y": "2" } },
+            "HostConfig": { "Memory": 0, "PortBindings": { "80/tcp": [{ "HostIp": "", "HostPort": "8080" }] } },
+            "NetworkSettings": { "Networks": { "traefik": { "IPAddress": "172.18.0.5" } } },
+        }));
+
+        let same = json!({
+            "Image": "nginx:1",
+            "Env": ["A=1"],
+            "Labels": { "x": "1" },
+            "HostConfig": { "PortBindings": { "80/tcp": [{ "HostPort": "8080" }] } },
+            "NetworkingConfig": { "EndpointsConfig": { "traefik": { "Aliases": ["web"] } } },
+        });
+        assert!(changes(&same, &existing).is_empty());
+
+        let different = json!({
+            "Image": "nginx:2",
+            "Env": ["A=2"],
+            "HostConfig": { "Memory": 512 },
+            "NetworkingConfig": { "EndpointsConfig": { "backend": {} } },
+        });
+        assert_eq!(changes(&different, &existing), ["Env", "HostConfig", "Image", "NetworkingConfig"]);
+    }
+
+    #[test]
+    fn contains_rules() {
+        // Extra entries in lists and maps are image defaults
+        assert!(contains(&json!({ "a": 1, "b": 2 }), &json!({ "a": 1 }), false));
+        assert!(contains(&json!(["x", "y"]), &json!(["y"]), false));
+        assert!(!contains(&json!(["x"]), &json!(["x", "z"]), false));
+        // Unset values match anything, a missing key only matches null
+        assert!(contains(&json!({ "a": 1 }), &json!({ "a": null, "b": null }), false));
+        assert!(!contains(&json!({ "a": 1 }), &json!({ "b": 0 }), false));
+        assert!(!contains(&json!({ "a": 1 }), &json!({ "a": 2 }), false));
+        // Only keys are compared with keys_only
+        assert!(contains(&json!({ "a": { "x": 1 } }), &json!({ "a": { "y": 2 } }), true));
+        assert!(!contains(&json!({ "a": {} }), &json!({ "b": {} }), true));
+    }
+}
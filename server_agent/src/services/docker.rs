@@ -1,211 +1,471 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::sync::Arc;
 
+use bollard::auth::DockerCredentials;
 use bollard::container::LogOutput;
-use bollard::models::ContainerCreateBody;
-use bollard::query_parameters::CreateContainerOptionsBuilder;
+use bollard::Docker;
+use bollard::models::{
+    ContainerCreateBody, ContainerInspectResponse, ContainerSummary, ContainerUpdateBody, CreateImageInfo,
+    RestartPolicy, RestartPolicyNameEnum,
+};
 use bollard::query_parameters::CreateImageOptionsBuilder;
+use bollard::query_parameters::InspectContainerOptions;
 use bollard::query_parameters::InspectContainerOptionsBuilder;
 use bollard::query_parameters::ListContainersOptionsBuilder;
 use bollard::query_parameters::LogsOptionsBuilder;
 use bollard::query_parameters::StartContainerOptionsBuilder;
 use bollard::query_parameters::StopContainerOptionsBuilder;
 use bollard::query_parameters::RemoveContainerOptionsBuilder;
-use bollard::Docker;
+use bollard::query_parameters::RestartContainerOptionsBuilder;
+use bollard::query_parameters::KillContainerOptionsBuilder;
+use bollard::query_parameters::RenameContainerOptionsBuilder;
 
-use serde::Deserialize;
-use serde_json;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 use futures_util::TryStreamExt;
+use http_body_util::combinators::BoxBody;
 use http_body_util::BodyExt;
 use http_body_util::Full;
 use hyper::body::Bytes;
 use hyper::{Request, Response};
 
-#[derive(Deserialize)]
-struct DockerRequest {
+use crate::auth::ApiKey;
+use crate::error::{ApiError, ErrorCode};
+use crate::image_ref::{ImageRef, PullPolicy};
+use crate::jobs::{self, JobKind, JobStore, PullProgress};
+use crate::registries::RegistryStore;
+use crate::services::{self, container_update};
+use crate::sse;
+use crate::util;
+
+/// Label docker compose sets to the project directory; used to match path restricted keys.
+const COMPOSE_WORKING_DIR_LABEL: &str = "com.docker.compose.project.working_dir";
+/// Label docker compose sets to the project name.
+const COMPOSE_PROJECT_LABEL: &str = "com.docker.compose.project";
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DockerRequest {
     container_name: String,
+    /// Docker Engine API container create body
+    #[schemars(with = "serde_json::Map<String, serde_json::Value>")]
     container_config: ContainerCreateBody,
+    #[serde(default)]
+    pull_policy: PullPolicy,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ActionResult {
+    ok: String,
+    /// The container after the action, missing once it is removed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    container: Option<ContainerState>,
+}
+
+/// State and live settings of a container, as an action left it.
+#[derive(Serialize, JsonSchema)]
+pub struct ContainerState {
+    id: String,
+    name: String,
+    /// created, running, paused, restarting, removing, exited or dead
+    status: String,
+    running: bool,
+    paused: bool,
+    restarting: bool,
+    exit_code: i64,
+    /// starting, healthy or unhealthy; missing without a health check
+    health: Option<String>,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+    restart_policy: String,
+    /// Memory limit in bytes, 0 for none
+    memory: i64,
+    /// CPU limit in units of 10^-9 CPUs, 0 for none
+    nano_cpus: i64,
+}
+
+impl ContainerState {
+    fn from_inspect(inspect: ContainerInspectResponse) -> ContainerState {
+        let state = inspect.state.unwrap_or_default();
+        let host_config = inspect.host_config.unwrap_or_default();
+        ContainerState {
+            id: inspect.id.unwrap_or_default(),
+            name: inspect.name.unwrap_or_default().trim_start_matches('/').to_string(),
+            status: state.status.map(|s| s.to_string()).unwrap_or_default(),
+            running: state.running.unwrap_or(false),
+            paused: state.paused.unwrap_or(false),
+            restarting: state.restarting.unwrap_or(false),
+            exit_code: state.exit_code.unwrap_or(0),
+            health: state.health.and_then(|h| h.status).map(|s| s.to_string()),
+            started_at: state.started_at,
+            finished_at: state.finished_at,
+            restart_policy: host_config
+                .restart_policy
+                .and_then(|p| p.name)
+                .map(|n| n.to_string())
+                .filter(|n| !n.is_empty())
+                .unwrap_or_else(|| "no".to_string()),
+            memory: host_config.memory.unwrap_or(0),
+            nano_cpus: host_config.nano_cpus.unwrap_or(0),
+        }
+    }
+}
+
+/// Respond with `message` and the state the action left the container in.
+async fn action_result(docker: &Docker, id: &str, message: &str) -> Response<Full<Bytes>> {
+    let options = InspectContainerOptionsBuilder::default().build();
+    let container = match docker.inspect_container(id, Some(options)).await {
+        Ok(inspect) => Some(ContainerState::from_inspect(inspect)),
+        // The action went through, e.g. a killed container with auto remove is gone already
+        Err(e) => {
+            log::warn!("Inspecting {} after \"{}\" failed: {}", id, message, e);
+            None
+        }
+    };
+    let result = ActionResult {
+        ok: message.to_string(),
+        container,
+    };
+    let serialized = serde_json::to_string(&result).unwrap();
+    Response::new(Full::new(Bytes::from(serialized)))
 }
 
 pub async fn create_or_update_container(
-    request: Request<hyper::body::Incoming>,
+    request: Request<Full<Bytes>>,
+    key: &ApiKey,
+    jobs: Arc<JobStore>,
+    registries: &RegistryStore,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
     let body = match request.into_body().collect().await {
         Ok(v) => v,
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::BAD_REQUEST)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Cant read request body\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
     };
 
     let body_bytes = body.to_bytes();
     // Parse JSON
     let setup: DockerRequest = match serde_json::from_slice(&body_bytes) {
         Ok(v) => v,
-        Err(e) => {
-            println!("{}", e);
-            let res = Response::builder()
-                .status(hyper::StatusCode::BAD_REQUEST)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Cant read request body. JSON parse error\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
     };
 
-    let docker = match Docker::connect_with_defaults() {
+    let image: ImageRef = match setup.container_config.image.as_deref().map(str::parse) {
+        Some(Ok(image)) => image,
+        Some(Err(e)) => return Ok(ApiError::new(ErrorCode::InvalidBody, format!("invalid image: {}", e)).response()),
+        None => return Ok(ApiError::new(ErrorCode::InvalidBody, "container_config.Image is required").response()),
+    };
+
+    let docker = match util::docker() {
         Ok(v) => v,
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Docker init failed\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
     };
 
-    let options = CreateContainerOptionsBuilder::default()
-        .name(&setup.container_name)
+    let name = setup.container_name.clone();
+    let work = container_update::create_or_update(
+        docker,
+        setup.container_name,
+        setup.container_config,
+        registries.credentials(&image),
+        image,
+        setup.pull_policy,
+    );
+    let job = jobs.spawn(JobKind::ContainerCreate, &name, None, key, work);
+    Ok(services::jobs::accepted(&job))
+}
+
+/// Make sure the daemon has the image as `policy` asks and return its ID.
+pub async fn ensure_image(
+    docker: &Docker,
+    image: &ImageRef,
+    policy: PullPolicy,
+    credentials: Option<DockerCredentials>,
+) -> Result<String, ApiError> {
+    if policy != PullPolicy::Always {
+        if let Some(id) = local_image_id(docker, image).await? {
+            return Ok(id);
+        }
+        if policy == PullPolicy::Never {
+            return Err(ApiError::new(
+                ErrorCode::InvalidRequest,
+                format!("image {} is not present and the pull policy is never", image),
+            ));
+        }
+    }
+    pull_image(docker, image, credentials).await?;
+    match local_image_id(docker, image).await? {
+        Some(id) => Ok(id),
+        None => Err(ApiError::internal(format!("image {} is missing after the pull", image))),
+    }
+}
+
+async fn local_image_id(docker: &Docker, image: &ImageRef) -> Result<Option<String>, ApiError> {
+    match docker.inspect_image(&image.to_string()).await {
+        Ok(inspect) => Ok(Some(inspect.id.unwrap_or_default())),
+        Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => Ok(None),
+        Err(e) => Err(ApiError::docker("image inspect", e)),
+    }
+}
+
+/// Pull an image, reporting the layers as job progress.
+pub async fn pull_image(
+    docker: &Docker,
+    image: &ImageRef,
+    credentials: Option<DockerCredentials>,
+) -> Result<(), ApiError> {
+    jobs::step(&format!("Pulling {}", image));
+    let name = image.name();
+    let create_image_opts = CreateImageOptionsBuilder::default()
+        .from_image(&name)
+        .tag(image.pull_tag())
         .build();
-    // clone config because we may try create twice (ContainerCreateBody derives Clone)
-    let cfg = setup.container_config.clone();
-    match docker
-        .create_container(Some(options.clone()), cfg.clone())
-        .await
-    {
-        Ok(result) => {
-            let serialized = serde_json::to_string(&result).unwrap();
-            Ok(Response::new(Full::new(Bytes::from(serialized))))
+    let mut pull_stream = docker.create_image(Some(create_image_opts), None, credentials);
+    loop {
+        match pull_stream.try_next().await {
+            Ok(Some(info)) => jobs::progress(pull_progress(info)),
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(ApiError::docker("image pull", e)),
         }
-        Err(e) => {
-            let err_str = e.to_string();
-            // if missing image, try to pull it then retry create
-            if err_str.contains("No such image") || err_str.contains("404") {
-                let image = cfg.image.clone().unwrap_or_default();
-                if image.is_empty() {
-                    let res = Response::builder()
-                        .status(hyper::StatusCode::BAD_REQUEST)
-                        .body(Full::new(Bytes::from("{\"error\":\"No image specified\"}")))
-                        .unwrap();
-                    return Ok(res);
-                }
-                // naive split into repo:tag (falls back to "latest")
-                let (repo, tag) = match image.rsplit_once(':') {
-                    Some((r, t)) => (r.to_string(), t.to_string()),
-                    None => (image.clone(), "latest".to_string()),
-                };
-                let create_image_opts = CreateImageOptionsBuilder::default()
-                    .from_image(repo.as_str())
-                    .tag(tag.as_str())
-                    .build();
-                let mut pull_stream = docker.create_image(Some(create_image_opts), None, None);
-                loop {
-                    match pull_stream.try_next().await {
-                        Ok(Some(_progress)) => continue,
-                        Ok(None) => break,
-                        Err(pe) => {
-                            let res = Response::builder()
-                                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                                .body(Full::new(Bytes::from(format!(
-                                    "{{\"error\":\"Image pull failed\",\"message\":\"{}\"}}",
-                                    pe
-                                ))))
-                                .unwrap();
-                            return Ok(res);
-                        }
-                    }
-                }
-                // retry create
-                match docker.create_container(Some(options), cfg).await {
-                    Ok(result) => {
-                        let serialized = serde_json::to_string(&result).unwrap();
-                        Ok(Response::new(Full::new(Bytes::from(serialized))))
-                    }
-                    Err(e2) => {
-                        let res = Response::builder()
-                            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(Full::new(Bytes::from(
-                                format!("{{\"error\": \"Docker create container failed\",\"message\":\"{}\"}}", e2),
-                            )))
-                            .unwrap();
-                        return Ok(res);
-                    }
-                }
-            } else {
-                let res = Response::builder()
-                    .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Full::new(Bytes::from(format!(
-                        "{{\"error\": \"Docker create container failed\",\"message\":\"{}\"}}",
-                        e
-                    ))))
-                    .unwrap();
-                return Ok(res);
+    }
+}
+
+fn pull_progress(info: CreateImageInfo) -> PullProgress {
+    let detail = info.progress_detail.unwrap_or_default();
+    PullProgress {
+        layer: info.id,
+        status: info.status.unwrap_or_default(),
+        current: detail.current,
+        total: detail.total,
+    }
+}
+
+/// Query of `GET /docker/containers/list`.
+#[derive(Deserialize, JsonSchema)]
+pub struct ListQuery {
+    /// Comma separated `key` or `key=value` labels, all have to match
+    label: Option<String>,
+    /// Comma separated states: created, restarting, running, removing, paused, exited or dead
+    status: Option<String>,
+    /// Part of the container name, or a regular expression like `^web-`
+    name: Option<String>,
+    /// Image the container was created from, or an image it is based on
+    ancestor: Option<String>,
+    /// Compose project name
+    project: Option<String>,
+    /// Return the short ContainerListItem instead of the Docker Engine API summary
+    #[serde(default)]
+    summary: bool,
+}
+
+const CONTAINER_STATES: &[&str] = &["created", "restarting", "running", "removing", "paused", "exited", "dead"];
+
+/// The short form of a listed container, what the manager shows in its grid.
+#[derive(Serialize, JsonSchema)]
+pub struct ContainerListItem {
+    id: String,
+    name: String,
+    image: String,
+    /// created, restarting, running, removing, paused, exited or dead
+    state: String,
+    /// starting, healthy or unhealthy; missing without a health check
+    health: Option<String>,
+    /// Published ports like `0.0.0.0:8080->80/tcp`, exposed ones like `443/tcp`
+    ports: Vec<String>,
+    /// Unix time of creation
+    created: i64,
+    /// How long a running container is up, as `docker ps` prints it, e.g. `3 hours`
+    uptime: Option<String>,
+    /// Compose project the container belongs to
+    project: Option<String>,
+}
+
+impl ContainerListItem {
+    fn from_summary(container: ContainerSummary) -> ContainerListItem {
+        let state = container.state.map(|s| s.to_string()).unwrap_or_default();
+        // The daemon only reports health and uptime inside the status text, e.g. `Up 3 hours (healthy)`
+        let status = container.status.unwrap_or_default();
+        let (uptime, health) = match status.rsplit_once(" (") {
+            Some((uptime, health @ ("healthy)" | "unhealthy)" | "health: starting)"))) => {
+                (uptime, Some(health.trim_start_matches("health: ").trim_end_matches(')').to_string()))
             }
+            _ => (status.as_str(), None),
+        };
+        let uptime = uptime.strip_prefix("Up ").filter(|_| state == "running").map(str::to_string);
+        let ports = container
+            .ports
+            .unwrap_or_default()
+            .iter()
+            .map(|port| {
+                let protocol = port.typ.map(|t| t.to_string()).filter(|t| !t.is_empty()).unwrap_or_else(|| "tcp".to_string());
+                match (port.ip.as_deref(), port.public_port) {
+                    (Some(ip), Some(public)) => format!("{}:{}->{}/{}", ip, public, port.private_port, protocol),
+                    _ => format!("{}/{}", port.private_port, protocol),
+                }
+            })
+            .collect();
+
+        ContainerListItem {
+            id: container.id.unwrap_or_default(),
+            name: container
+                .names
+                .and_then(|names| names.into_iter().next())
+                .unwrap_or_default()
+                .trim_start_matches('/')
+                .to_string(),
+            image: container.image.unwrap_or_default(),
+            state,
+            health,
+            ports,
+            created: container.created.unwrap_or(0),
+            uptime,
+            project: container.labels.and_then(|mut labels| labels.remove(COMPOSE_PROJECT_LABEL)),
         }
     }
 }
 
-pub async fn list_containers(
-    _request: Request<hyper::body::Incoming>,
-) -> Result<Response<Full<Bytes>>, Infallible> {
-    let options = ListContainersOptionsBuilder::default().all(true).build();
-    let docker = match Docker::connect_with_defaults() {
+/// Comma separated query values without the empty ones.
+fn split_list(value: &Option<String>) -> Vec<String> {
+    value
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+pub async fn list_containers(query: ListQuery, key: &ApiKey) -> Result<Response<Full<Bytes>>, Infallible> {
+    let mut filters: HashMap<&str, Vec<String>> = HashMap::new();
+    let mut labels = split_list(&query.label);
+    if let Some(project) = query.project.as_deref().filter(|p| !p.is_empty()) {
+        labels.push(format!("{}={}", COMPOSE_PROJECT_LABEL, project));
+    }
+    if !labels.is_empty() {
+        filters.insert("label", labels);
+    }
+    let status = split_list(&query.status);
+    if let Some(unknown) = status.iter().find(|s| !CONTAINER_STATES.contains(&s.as_str())) {
+        let message = format!("unknown status '{}', expected one of {}", unknown, CONTAINER_STATES.join(", "));
+        return Ok(ApiError::new(ErrorCode::InvalidQuery, message).response());
+    }
+    if !status.is_empty() {
+        filters.insert("status", status);
+    }
+    if let Some(name) = query.name.filter(|n| !n.is_empty()) {
+        filters.insert("name", vec![name]);
+    }
+    if let Some(ancestor) = query.ancestor.filter(|a| !a.is_empty()) {
+        filters.insert("ancestor", vec![ancestor]);
+    }
+
+    let options = ListContainersOptionsBuilder::default().all(true).filters(&filters).build();
+    let docker = match util::docker() {
         Ok(v) => v,
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Docker init failed\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
     };
 
-    let images = match docker.list_containers(Some(options)).await {
+    let mut containers = match docker.list_containers(Some(options)).await {
         Ok(v) => v,
-        Err(_) => {
-            return Ok(Response::new(Full::new(Bytes::from(
-                "{\"error\": \"Docker init failed\"}",
-            ))));
-        }
+        Err(e) => return Ok(ApiError::docker("container list", e).response()),
     };
 
-    let serialized = serde_json::to_string(&images).unwrap();
+    retain_allowed(&mut containers, key);
+
+    let serialized = if query.summary {
+        let items: Vec<ContainerListItem> = containers.into_iter().map(ContainerListItem::from_summary).collect();
+        serde_json::to_string(&items).unwrap()
+    } else {
+        serde_json::to_string(&containers).unwrap()
+    };
 
     Ok(Response::new(Full::new(Bytes::from(serialized))))
 }
 
+pub fn retain_allowed(containers: &mut Vec<ContainerSummary>, key: &ApiKey) {
+    if key.is_path_restricted() {
+        containers.retain(|c| {
+            c.labels
+                .as_ref()
+                .and_then(|labels| labels.get(COMPOSE_WORKING_DIR_LABEL))
+                .is_some_and(|dir| key.allows_path(dir))
+        });
+    }
+}
+
+/// Resolve the `{id}` of a container route, which may be a full ID, a container name or a unique
+/// ID prefix, to the full ID. Path restricted keys only find the containers they may touch.
+pub async fn resolve_container(key: &ApiKey, reference: &str) -> Result<String, ApiError> {
+    if reference.len() == 64 && reference.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(reference.to_string());
+    }
+    let docker = util::docker().map_err(|e| ApiError::docker_unavailable(&e))?;
+    let options = ListContainersOptionsBuilder::default().all(true).build();
+    let containers = docker
+        .list_containers(Some(options))
+        .await
+        .map_err(|e| ApiError::docker("container list", e))?;
+    match_container(containers, key, reference)
+}
+
+/// The container `reference` names or whose ID it is a unique prefix of, among the ones the key
+/// may touch.
+fn match_container(mut containers: Vec<ContainerSummary>, key: &ApiKey, reference: &str) -> Result<String, ApiError> {
+    retain_allowed(&mut containers, key);
+
+    // Names win over ID prefixes, like in the docker CLI
+    let name = format!("/{}", reference);
+    if let Some(id) = containers
+        .iter()
+        .find(|c| c.names.as_ref().is_some_and(|names| names.contains(&name)))
+        .and_then(|c| c.id.clone())
+    {
+        return Ok(id);
+    }
+    let matches: Vec<&str> = containers
+        .iter()
+        .filter_map(|c| c.id.as_deref())
+        .filter(|id| id.starts_with(reference))
+        .collect();
+    match matches.as_slice() {
+        [id] => Ok(id.to_string()),
+        [] => Err(ApiError::not_found(format!("no container named or with ID prefix {}", reference))),
+        _ => Err(ApiError::new(
+            ErrorCode::Conflict,
+            format!("{} matches {} containers, use a longer ID prefix or the name", reference, matches.len()),
+        )
+        .with_details(serde_json::json!({ "matches": matches }))),
+    }
+}
+
+/// Path restricted keys may only touch containers of compose projects below their prefixes.
+pub async fn container_allowed(key: &ApiKey, id: &str) -> bool {
+    if !key.is_path_restricted() {
+        return true;
+    }
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    let options = InspectContainerOptionsBuilder::default().build();
+    match docker.inspect_container(id, Some(options)).await {
+        Ok(inspect) => inspect
+            .config
+            .and_then(|c| c.labels)
+            .and_then(|labels| labels.get(COMPOSE_WORKING_DIR_LABEL).cloned())
+            .is_some_and(|dir| key.allows_path(&dir)),
+        Err(_) => false,
+    }
+}
+
 pub async fn container_inspect(id: &str) -> Result<Response<Full<Bytes>>, Infallible> {
     let options = InspectContainerOptionsBuilder::default().build();
-    let docker = match Docker::connect_with_defaults() {
+    let docker = match util::docker() {
         Ok(v) => v,
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Docker init failed\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
     };
 
-    let docker_container_inspect = match docker.inspect_container(&id, Some(options)).await {
+    let docker_container_inspect = match docker.inspect_container(id, Some(options)).await {
         Ok(v) => v,
-        Err(_) => {
-            return Ok(Response::new(Full::new(Bytes::from(
-                "{\"error\": \"Docker init failed\"}",
-            ))));
-        }
+        Err(e) => return Ok(ApiError::docker("container inspect", e).response()),
     };
 
     let serialized = serde_json::to_string(&docker_container_inspect).unwrap();
@@ -213,151 +473,529 @@ pub async fn container_inspect(id: &str) -> Result<Response<Full<Bytes>>, Infall
     Ok(Response::new(Full::new(Bytes::from(serialized))))
 }
 
+/// Query of the stop and restart actions.
+#[derive(Deserialize, JsonSchema)]
+pub struct StopQuery {
+    /// Seconds to wait for the container to stop before it is killed, defaults to the
+    /// container's stop timeout
+    timeout_secs: Option<u32>,
+}
+
+/// Query of `POST /docker/container/{id}/kill`.
+#[derive(Deserialize, JsonSchema)]
+pub struct KillQuery {
+    /// Signal name or number, e.g. `SIGHUP` or `9`. Defaults to SIGKILL
+    signal: Option<String>,
+}
+
+/// Query of `POST /docker/container/{id}/rename`.
+#[derive(Deserialize, JsonSchema)]
+pub struct RenameQuery {
+    /// New container name
+    name: String,
+}
+
+/// Query of `GET /docker/container/{id}/rm`.
+#[derive(Deserialize, JsonSchema)]
+pub struct RmQuery {
+    /// Kill and remove a running container
+    #[serde(default)]
+    force: bool,
+    /// Also remove the anonymous volumes of the container
+    #[serde(default)]
+    v: bool,
+}
+
+/// Limits and restart policy to change on a container without recreating it; fields that are
+/// left out stay as they are.
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateRequest {
+    /// Memory limit in bytes, 0 for none
+    memory: Option<i64>,
+    /// Memory plus swap in bytes, -1 for unlimited swap. Raising memory above the current swap
+    /// limit needs this as well
+    memory_swap: Option<i64>,
+    /// Number of CPUs, e.g. 1.5; 0 for no limit
+    cpus: Option<f64>,
+    /// Relative CPU weight against other containers
+    cpu_shares: Option<i64>,
+    restart_policy: Option<RestartPolicyRequest>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RestartPolicyRequest {
+    /// no, always, unless-stopped or on-failure
+    #[schemars(with = "String")]
+    name: RestartPolicyNameEnum,
+    /// Only for on-failure, 0 retries forever
+    maximum_retry_count: Option<i64>,
+}
+
 pub async fn container_start(id: &str) -> Result<Response<Full<Bytes>>, Infallible> {
     let options = StartContainerOptionsBuilder::default().build();
-    let docker = match Docker::connect_with_defaults() {
+    let docker = match util::docker() {
         Ok(v) => v,
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Docker init failed\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
     };
 
-    match docker.start_container(&id, Some(options)).await {
-        Ok(_) => {
-            return Ok(Response::new(Full::new(Bytes::from(
-                "{\"ok\": \"Docker container started\"}",
-            ))))
-        }
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Docker container start failed\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+    match docker.start_container(id, Some(options)).await {
+        Ok(_) => Ok(action_result(&docker, id, "Docker container started").await),
+        Err(e) => Ok(ApiError::docker("container start", e).response()),
+    }
+}
+
+pub async fn container_stop(id: &str, query: StopQuery) -> Result<Response<Full<Bytes>>, Infallible> {
+    let mut options = StopContainerOptionsBuilder::default();
+    if let Some(timeout_secs) = query.timeout_secs {
+        options = options.t(timeout_secs.min(i32::MAX as u32) as i32);
+    }
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
     };
+
+    match docker.stop_container(id, Some(options.build())).await {
+        Ok(_) => Ok(action_result(&docker, id, "Docker container stopped").await),
+        Err(e) => Ok(ApiError::docker("container stop", e).response()),
+    }
 }
 
-pub async fn container_stop(id: &str) -> Result<Response<Full<Bytes>>, Infallible> {
-    let options = StopContainerOptionsBuilder::default().build();
-    let docker = match Docker::connect_with_defaults() {
+pub async fn container_restart(id: &str, query: StopQuery) -> Result<Response<Full<Bytes>>, Infallible> {
+    let mut options = RestartContainerOptionsBuilder::default();
+    if let Some(timeout_secs) = query.timeout_secs {
+        options = options.t(timeout_secs.min(i32::MAX as u32) as i32);
+    }
+    let docker = match util::docker() {
         Ok(v) => v,
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Docker init failed\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
     };
 
-    match docker.stop_container(&id, Some(options)).await {
-        Ok(_) => {
-            return Ok(Response::new(Full::new(Bytes::from(
-                "{\"ok\": \"Docker container stopped\"}",
-            ))))
-        }
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Docker container stop failed\"}",
-                )))
-                .unwrap();
-            return Ok(res);
+    match docker.restart_container(id, Some(options.build())).await {
+        Ok(_) => Ok(action_result(&docker, id, "Docker container restarted").await),
+        Err(e) => Ok(ApiError::docker("container restart", e).response()),
+    }
+}
+
+pub async fn container_pause(id: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    match docker.pause_container(id).await {
+        Ok(_) => Ok(action_result(&docker, id, "Docker container paused").await),
+        Err(e) => Ok(ApiError::docker("container pause", e).response()),
+    }
+}
+
+pub async fn container_unpause(id: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    match docker.unpause_container(id).await {
+        Ok(_) => Ok(action_result(&docker, id, "Docker container unpaused").await),
+        Err(e) => Ok(ApiError::docker("container unpause", e).response()),
+    }
+}
+
+pub async fn container_kill(id: &str, query: KillQuery) -> Result<Response<Full<Bytes>>, Infallible> {
+    let mut options = KillContainerOptionsBuilder::default();
+    if let Some(signal) = &query.signal {
+        // Docker parses the signal, this only keeps garbage out of the query string
+        if signal.is_empty() || !signal.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-') {
+            return Ok(ApiError::new(ErrorCode::InvalidQuery, format!("invalid signal '{}'", signal)).response());
         }
+        options = options.signal(signal);
+    }
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    match docker.kill_container(id, Some(options.build())).await {
+        Ok(_) => Ok(action_result(&docker, id, "Docker container killed").await),
+        Err(e) => Ok(ApiError::docker("container kill", e).response()),
+    }
+}
+
+/// Whether `name` is a valid container name, `[a-zA-Z0-9][a-zA-Z0-9_.-]+` like the daemon wants.
+fn valid_container_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && name.len() > 1
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+pub async fn container_rename(id: &str, query: RenameQuery) -> Result<Response<Full<Bytes>>, Infallible> {
+    if !valid_container_name(&query.name) {
+        let message = format!("invalid container name '{}'", query.name);
+        return Ok(ApiError::new(ErrorCode::InvalidQuery, message).response());
+    }
+    let options = RenameContainerOptionsBuilder::default().name(&query.name).build();
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
     };
+
+    match docker.rename_container(id, options).await {
+        Ok(_) => Ok(action_result(&docker, id, "Docker container renamed").await),
+        Err(e) => Ok(ApiError::docker("container rename", e).response()),
+    }
 }
 
-pub async fn container_rm(id: &str) -> Result<Response<Full<Bytes>>, Infallible> {
-    let options = RemoveContainerOptionsBuilder::default().build();
-    let docker = match Docker::connect_with_defaults() {
+/// Change limits and restart policy of a container in place.
+pub async fn container_update(request: Request<Full<Bytes>>, id: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match request.into_body().collect().await {
         Ok(v) => v,
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Docker init failed\"}",
-                )))
-                .unwrap();
-            return Ok(res);
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    let body_bytes = body.to_bytes();
+    let update: UpdateRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    let nano_cpus = match update.cpus {
+        Some(cpus) if !cpus.is_finite() || cpus < 0.0 => {
+            return Ok(ApiError::new(ErrorCode::InvalidBody, "cpus must not be negative").response());
         }
+        Some(cpus) => Some((cpus * 1e9).round() as i64),
+        None => None,
+    };
+    let restart_policy = update.restart_policy.map(|policy| RestartPolicy {
+        name: Some(policy.name),
+        maximum_retry_count: policy.maximum_retry_count,
+    });
+    let body = ContainerUpdateBody {
+        memory: update.memory,
+        memory_swap: update.memory_swap,
+        nano_cpus,
+        cpu_shares: update.cpu_shares,
+        restart_policy,
+        ..Default::default()
+    };
+    if body == ContainerUpdateBody::default() {
+        return Ok(ApiError::new(ErrorCode::InvalidBody, "nothing to update").response());
+    }
+
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    match docker.update_container(id, body).await {
+        Ok(_) => Ok(action_result(&docker, id, "Docker container updated").await),
+        Err(e) => Ok(ApiError::docker("container update", e).response()),
+    }
+}
+
+pub async fn container_rm(id: &str, query: RmQuery) -> Result<Response<Full<Bytes>>, Infallible> {
+    let options = RemoveContainerOptionsBuilder::default().force(query.force).v(query.v).build();
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
     };
 
-    match docker.remove_container(&id, Some(options)).await {
+    match docker.remove_container(id, Some(options)).await {
         Ok(_) => {
-            return Ok(Response::new(Full::new(Bytes::from(
-                "{\"ok\": \"Docker container removed\"}",
-            ))))
-        }
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Docker container stop failed\"}",
-                )))
-                .unwrap();
-            return Ok(res);
+            let result = ActionResult {
+                ok: "Docker container removed".to_string(),
+                container: None,
+            };
+            let serialized = serde_json::to_string(&result).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
         }
+        Err(e) => Ok(ApiError::docker("container rm", e).response()),
+    }
+}
+
+fn unix_time_i32(time: i64) -> i32 {
+    time.clamp(0, i32::MAX as i64) as i32
+}
+
+/// Query of `GET /docker/container/{id}/logs`.
+#[derive(Deserialize, JsonSchema)]
+pub struct LogsQuery {
+    /// Number of lines from the end, or `all`. Defaults to 100
+    tail: Option<String>,
+    /// Only lines after this time (unix seconds)
+    since: Option<i64>,
+    /// Only lines before this time (unix seconds)
+    until: Option<i64>,
+    /// Prefix every line with its RFC 3339 timestamp
+    #[serde(default)]
+    timestamps: bool,
+    #[serde(default = "default_true")]
+    stdout: bool,
+    #[serde(default = "default_true")]
+    stderr: bool,
+    /// Keep the connection open and stream new lines as Server-Sent Events (`log` events)
+    #[serde(default)]
+    follow: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A followed log line, sent as the `log` event
+#[derive(Serialize, JsonSchema)]
+pub struct LogLine {
+    /// stdout, stderr, stdin or console
+    stream: &'static str,
+    line: String,
+}
+
+fn log_line(log: LogOutput) -> LogLine {
+    let (stream, message) = match log {
+        LogOutput::StdOut { message } => ("stdout", message),
+        LogOutput::StdErr { message } => ("stderr", message),
+        LogOutput::StdIn { message } => ("stdin", message),
+        LogOutput::Console { message } => ("console", message),
     };
+    LogLine {
+        stream,
+        line: String::from_utf8_lossy(&message).to_string(),
+    }
 }
 
-pub async fn container_logs(id: &str) -> Result<Response<Full<Bytes>>, Infallible> {
-    let options = LogsOptionsBuilder::default()
-        .stdout(true)
-        .stderr(true)
-        .build();
+pub async fn container_logs(id: &str, query: LogsQuery) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
+    let tail = query.tail.as_deref().unwrap_or("100");
+    if tail != "all" && tail.parse::<u64>().is_err() {
+        let message = format!("tail must be a number or all (got {})", tail);
+        return Ok(ApiError::new(ErrorCode::InvalidQuery, message).response().map(BodyExt::boxed));
+    }
+    if !query.stdout && !query.stderr {
+        let message = "at least one of stdout and stderr must be selected";
+        return Ok(ApiError::new(ErrorCode::InvalidQuery, message).response().map(BodyExt::boxed));
+    }
+    let mut options = LogsOptionsBuilder::default()
+        .stdout(query.stdout)
+        .stderr(query.stderr)
+        .timestamps(query.timestamps)
+        .follow(query.follow)
+        .tail(tail);
+    // bollard takes 32 bit timestamps, later times are clamped to January 2038
+    if let Some(since) = query.since {
+        options = options.since(unix_time_i32(since));
+    }
+    if let Some(until) = query.until {
+        options = options.until(unix_time_i32(until));
+    }
+    let options = options.build();
 
-    let docker = match Docker::connect_with_defaults() {
+    let docker = match util::docker() {
         Ok(v) => v,
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Docker init failed\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response().map(BodyExt::boxed)),
     };
 
-    let logs_stream = docker.logs(id, Some(options));
-
-    let lines = match logs_stream
-        .map_ok(|log| match log {
-            LogOutput::StdOut { message } | LogOutput::StdErr { message } => {
-                String::from_utf8_lossy(&message).to_string()
+    if query.follow {
+        // Fail before the stream starts if the container does not exist
+        if let Err(e) = docker.inspect_container(id, None::<InspectContainerOptions>).await {
+            return Ok(ApiError::docker("container inspect", e).response().map(BodyExt::boxed));
+        }
+        let id = id.to_string();
+        return Ok(sse::response(move |sender| async move {
+            let mut logs_stream = docker.logs(&id, Some(options));
+            loop {
+                match logs_stream.try_next().await {
+                    Ok(Some(log)) => {
+                        if !sender.send("log", &log_line(log)).await {
+                            return;
+                        }
+                    }
+                    // The container stopped
+                    Ok(None) => {
+                        sender.send("end", &serde_json::json!({})).await;
+                        return;
+                    }
+                    Err(e) => {
+                        sender.send("error", &ApiError::docker("container logs", e)).await;
+                        return;
+                    }
+                }
             }
-            _ => String::new(),
-        })
+        }));
+    }
+
+    let lines = match docker
+        .logs(id, Some(options))
+        .map_ok(|log| log_line(log).line)
         .try_collect::<Vec<String>>()
         .await
     {
         Ok(l) => l,
-        Err(e) => {
-            println!("{}", e);
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Docker read logs failed\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Ok(ApiError::docker("container logs", e).response().map(BodyExt::boxed)),
     };
-    let last_100_lines: &[String] = &lines[lines.len().saturating_sub(100)..];
 
-    let serialized = serde_json::to_string(&last_100_lines).unwrap();
+    let serialized = serde_json::to_string(&lines).unwrap();
 
-    Ok(Response::new(Full::new(Bytes::from(serialized))))
+    Ok(Response::new(Full::new(Bytes::from(serialized)).boxed()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bollard::models::{ContainerSummaryStateEnum, Port, PortTypeEnum};
+    use crate::auth::Scope;
+
+    fn key(path_prefixes: &[&str]) -> ApiKey {
+        ApiKey {
+            name: "ci".to_string(),
+            hash: String::new(),
+            scopes: vec![Scope::Deploy],
+            path_prefixes: path_prefixes.iter().map(Into::into).collect(),
+            previous_hash: None,
+            previous_expires_at: None,
+            signing_key: None,
+            previous_signing_key: None,
+            require_signature: false,
+        }
+    }
+
+    fn container(id: &str, name: &str, working_dir: Option<&str>) -> ContainerSummary {
+        ContainerSummary {
+            id: Some(id.to_string()),
+            names: Some(vec![format!("/{}", name)]),
+            labels: working_dir.map(|dir| HashMap::from([(COMPOSE_WORKING_DIR_LABEL.to_string(), dir.to_string())])),
+            ..Default::default()
+        }
+    }
+
+    fn containers() -> Vec<ContainerSummary> {
+        vec![
+            container("abc123", "web", Some("/home/node_agent/apps/shop")),
+            container("abd456", "abc", Some("/home/node_agent/apps/blog")),
+            container("ff0000", "db", None),
+        ]
+    }
+
+    fn summary_item(container: ContainerSummary) -> serde_json::Value {
+        serde_json::to_value(ContainerListItem::from_summary(container)).unwrap()
+    }
+
+    #[test]
+    fn summarizes_a_running_container() {
+        let port = |ip: Option<&str>, public_port: Option<u16>, private_port: u16, typ: Option<PortTypeEnum>| Port {
+            ip: ip.map(str::to_string),
+            private_port,
+            public_port,
+            typ,
+        };
+        let container = ContainerSummary {
+            id: Some("abc123".to_string()),
+            names: Some(vec!["/shop-web-1".to_string(), "/alias".to_string()]),
+            image: Some("nginx:1.27".to_string()),
+            state: Some(ContainerSummaryStateEnum::RUNNING),
+            status: Some("Up 3 hours (healthy)".to_string()),
+            ports: Some(vec![
+                port(Some("0.0.0.0"), Some(8080), 80, Some(PortTypeEnum::TCP)),
+                port(None, None, 443, Some(PortTypeEnum::TCP)),
+                port(Some("::"), Some(5353), 53, Some(PortTypeEnum::UDP)),
+                port(None, None, 9000, Some(PortTypeEnum::EMPTY)),
+            ]),
+            created: Some(1_700_000_000),
+            labels: Some(HashMap::from([
+                (COMPOSE_PROJECT_LABEL.to_string(), "shop".to_string()),
+                (COMPOSE_WORKING_DIR_LABEL.to_string(), "/home/node_agent/apps/shop".to_string()),
+            ])),
+            ..Default::default()
+        };
+        assert_eq!(
+            summary_item(container),
+            serde_json::json!({
+                "id": "abc123",
+                "name": "shop-web-1",
+                "image": "nginx:1.27",
+                "state": "running",
+                "health": "healthy",
+                "ports": ["0.0.0.0:8080->80/tcp", "443/tcp", ":::5353->53/udp", "9000/tcp"],
+                "created": 1_700_000_000,
+                "uptime": "3 hours",
+                "project": "shop",
+            })
+        );
+    }
+
+    #[test]
+    fn summarizes_health_states_and_stopped_containers() {
+        let with_status = |state, status: &str| ContainerSummary {
+            state: Some(state),
+            status: Some(status.to_string()),
+            ..Default::default()
+        };
+        let item = summary_item(with_status(ContainerSummaryStateEnum::RUNNING, "Up 2 minutes (health: starting)"));
+        assert_eq!((item["health"].as_str(), item["uptime"].as_str()), (Some("starting"), Some("2 minutes")));
+        let item = summary_item(with_status(ContainerSummaryStateEnum::RUNNING, "Up About a minute (unhealthy)"));
+        assert_eq!((item["health"].as_str(), item["uptime"].as_str()), (Some("unhealthy"), Some("About a minute")));
+        // Exited containers have no uptime, even though the status text has a duration
+        let item = summary_item(with_status(ContainerSummaryStateEnum::EXITED, "Exited (0) 5 minutes ago"));
+        assert_eq!(item["state"], "exited");
+        assert!(item["health"].is_null() && item["uptime"].is_null());
+    }
+
+    #[test]
+    fn summarizes_missing_fields() {
+        assert_eq!(
+            summary_item(ContainerSummary::default()),
+            serde_json::json!({
+                "id": "",
+                "name": "",
+                "image": "",
+                "state": "",
+                "health": null,
+                "ports": [],
+                "created": 0,
+                "uptime": null,
+                "project": null,
+            })
+        );
+    }
+
+    #[test]
+    fn logs_query_takes_64_bit_times() {
+        let query: LogsQuery = serde_urlencoded::from_str("since=4102444800&until=-5").unwrap();
+        assert_eq!((query.since, query.until), (Some(4_102_444_800), Some(-5)));
+        assert_eq!(unix_time_i32(4_102_444_800), i32::MAX);
+        assert_eq!(unix_time_i32(-5), 0);
+        assert_eq!(unix_time_i32(1_700_000_000), 1_700_000_000);
+    }
+
+    #[test]
+    fn names_beat_id_prefixes() {
+        // "abc" is the name of one container and the ID prefix of another
+        assert_eq!(match_container(containers(), &key(&[]), "abc").unwrap(), "abd456");
+        assert_eq!(match_container(containers(), &key(&[]), "web").unwrap(), "abc123");
+        assert_eq!(match_container(containers(), &key(&[]), "abc1").unwrap(), "abc123");
+        assert_eq!(match_container(containers(), &key(&[]), "ff").unwrap(), "ff0000");
+    }
+
+    #[test]
+    fn ambiguous_prefixes_conflict() {
+        let error = match_container(containers(), &key(&[]), "ab").unwrap_err();
+        assert_eq!(error.code, ErrorCode::Conflict);
+        assert_eq!(error.details, Some(serde_json::json!({ "matches": ["abc123", "abd456"] })));
+
+        let error = match_container(containers(), &key(&[]), "nope").unwrap_err();
+        assert_eq!(error.code, ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn path_restricted_keys_only_see_their_containers() {
+        let shop = key(&["/home/node_agent/apps/shop"]);
+        assert_eq!(match_container(containers(), &shop, "web").unwrap(), "abc123");
+        // The prefix is no longer ambiguous, the other container is invisible
+        assert_eq!(match_container(containers(), &shop, "ab").unwrap(), "abc123");
+        // Without the container named abc, abc is just an ID prefix
+        assert_eq!(match_container(containers(), &shop, "abc").unwrap(), "abc123");
+        for reference in ["abd", "db", "ff0000"] {
+            let error = match_container(containers(), &shop, reference).unwrap_err();
+            assert_eq!(error.code, ErrorCode::NotFound, "{}", reference);
+        }
+    }
 }
@@ -1,98 +1,132 @@
 use std::convert::Infallible;
 use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
-use serde::Deserialize;
-use serde_json;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{self, Value};
 
 use http_body_util::BodyExt;
 use http_body_util::Full;
 use hyper::body::Bytes;
 use hyper::{Request, Response};
 
+use crate::auth::ApiKey;
+use crate::config;
+use crate::error::ApiError;
+use crate::image_ref::PullPolicy;
+use crate::jobs::{JobKind, JobStore};
+use crate::registries::RegistryStore;
+use crate::services;
 use crate::util;
 
-#[derive(Deserialize)]
-struct DockerComposeRequest {
+#[derive(Deserialize, JsonSchema)]
+pub struct DockerComposeRequest {
     path: String,
+    /// Content of docker-compose.yml
+    compose: String,
+    #[serde(default)]
+    pull_policy: PullPolicy,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ComposeResult {
+    ok: bool,
+    /// Output of docker compose up
     compose: String,
 }
 
 pub async fn create_or_update_compose(
-    request: Request<hyper::body::Incoming>,
+    request: Request<Full<Bytes>>,
+    key: &ApiKey,
+    jobs: Arc<JobStore>,
+    registries: Arc<RegistryStore>,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
     let body = match request.into_body().collect().await {
         Ok(v) => v,
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::BAD_REQUEST)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Cant read request body\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
     };
 
     let body_bytes = body.to_bytes();
     // Parse JSON
     let setup: DockerComposeRequest = match serde_json::from_slice(&body_bytes) {
         Ok(v) => v,
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::BAD_REQUEST)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Cant read request body\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
     };
 
-    // Create dirs if needed
-    if let Err(e) = std::fs::create_dir_all(&setup.path) {
-        let res = Response::builder()
-            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Full::new(Bytes::from(format!(
-                "{{\"error\": \"mkdir failed: {}\"}}",
-                e
-            ))))
-            .unwrap();
-        return Ok(res);
+    if let Err(e) = util::check_deployment_path(&setup.path) {
+        return Ok(ApiError::path_not_allowed(e).response());
+    }
+    if !key.allows_path(&setup.path) {
+        return Ok(ApiError::path_not_allowed("key is restricted to other deployment paths").response());
     }
 
-    // Update compose file content
-    match fs::write(format!("{}/docker-compose.yml", &setup.path), setup.compose) {
-        Ok(v) => v,
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Cant read/write docker-compose.yml\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+    // Reserve the path before writing below it, a running deploy must not see its files change
+    let path = setup.path.clone();
+    let work = async move {
+        // Create dirs if needed
+        std::fs::create_dir_all(&setup.path).map_err(|e| ApiError::internal(format!("mkdir failed: {}", e)))?;
+
+        // Update compose file content
+        fs::write(format!("{}/docker-compose.yml", &setup.path), setup.compose)
+            .map_err(|e| ApiError::internal(format!("cannot write docker-compose.yml: {}", e)))?;
+
+        compose_up(setup.path, setup.pull_policy, registries).await
     };
+    match jobs.spawn_exclusive(JobKind::Compose, &path, key, work) {
+        Ok(job) => Ok(services::jobs::accepted(&job)),
+        Err(running) => Ok(services::jobs::busy(&path, &running)),
+    }
+}
 
-    // Update compose
-    let compose_output = util::command_output(
+async fn compose_up(path: String, pull_policy: PullPolicy, registries: Arc<RegistryStore>) -> Result<Value, ApiError> {
+    // Compose files join it as an external network, which compose does not create
+    let docker = util::docker().map_err(|e| ApiError::docker_unavailable(&e))?;
+    services::networks::ensure_traefik_network(&docker).await?;
+
+    // Lives until compose is done, then the credentials are deleted again
+    let docker_config = registries
+        .docker_config_dir()
+        .map_err(|e| ApiError::internal(format!("cannot prepare registry credentials: {}", e)))?;
+    let env: Vec<(&str, &Path)> = docker_config.iter().map(|dir| ("DOCKER_CONFIG", dir.path())).collect();
+    let compose_output = util::command_output_env(
         "docker",
-        Some(vec!["compose", "up", "-d", "--remove-orphans", "--build"]),
-        Some(&setup.path),
-    );
-
-    Ok(Response::new(Full::new(Bytes::from(format!(
-        "{{\"ok\": true, \"compose\": \"{}\"}}",
-        compose_output
-    )))))
+        Some(vec!["compose", "up", "-d", "--remove-orphans", "--build", "--pull", pull_policy.compose_flag()]),
+        Some(&path),
+        &env,
+        Duration::from_secs(config::get().timeouts.compose_secs),
+    )
+    .await
+    .map_err(|e| ApiError::command("docker compose", &e))?;
+
+    let result = ComposeResult {
+        ok: true,
+        compose: compose_output,
+    };
+    Ok(serde_json::to_value(&result).unwrap())
 }
 
-pub async fn logs(path: &str) -> Result<Response<Full<Bytes>>, Infallible> {
-    let compose_logs_output = util::command_output(
+pub async fn logs(path: &str, key: &ApiKey) -> Result<Response<Full<Bytes>>, Infallible> {
+    if let Err(e) = util::check_deployment_path(path) {
+        return Ok(ApiError::path_not_allowed(e).response());
+    }
+    if !key.allows_path(path) {
+        return Ok(ApiError::path_not_allowed("key is restricted to other deployment paths").response());
+    }
+
+    let compose_logs_output = match util::command_output(
         "docker",
         Some(vec!["compose", "logs"]),
         Some(path),
-    );
+        Duration::from_secs(config::get().timeouts.compose_secs),
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::command("docker compose", &e).response()),
+    };
     let lines: Vec<&str> = compose_logs_output.lines().collect();
     let last_100_lines = &lines[lines.len().saturating_sub(100)..];
 
@@ -1,121 +1,113 @@
 use std::convert::Infallible;
 use std::path::Path;
-use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
 
 use http_body_util::BodyExt;
 use http_body_util::Full;
 use hyper::body::Bytes;
 use hyper::{Request, Response};
 use regex::Regex;
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
+use crate::auth::ApiKey;
+use crate::config;
+use crate::error::{ApiError, ErrorCode};
+use crate::jobs::{self, JobKind, JobStore};
+use crate::services;
 use crate::util;
 
-#[derive(Deserialize)]
-struct SetupRequest {
+#[derive(Deserialize, JsonSchema)]
+pub struct SetupRequest {
+    /// Runner registration token
     token: String,
     path: String,
     git_url: String,
 }
 
+#[derive(Serialize, JsonSchema)]
+pub struct SetupResult {
+    ok: bool,
+    /// Result of svc.sh install and start
+    service: String,
+}
+
 pub async fn setup_new(
-    request: Request<hyper::body::Incoming>,
+    request: Request<Full<Bytes>>,
+    key: &ApiKey,
+    jobs: Arc<JobStore>,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
     let body = match request.into_body().collect().await {
         Ok(v) => v,
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::BAD_REQUEST)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Cant read request body\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
     };
 
     let body_bytes = body.to_bytes();
     // Parse JSON
     let setup: SetupRequest = match serde_json::from_slice(&body_bytes) {
         Ok(v) => v,
-        Err(_) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::BAD_REQUEST)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"Cant read request body\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
     };
 
+    if let Err(e) = util::check_deployment_path(&setup.path) {
+        return Ok(ApiError::path_not_allowed(e).response());
+    }
+    if !key.allows_path(&setup.path) {
+        return Ok(ApiError::path_not_allowed("key is restricted to other deployment paths").response());
+    }
+
     // ensure directory does not already exist
     if Path::new(&setup.path).exists() {
-        let res = Response::builder()
-            .status(hyper::StatusCode::BAD_REQUEST)
-            .body(Full::new(Bytes::from(
-                "{\"error\": \"path already exists\"}",
-            )))
-            .unwrap();
-        return Ok(res);
+        return Ok(ApiError::new(ErrorCode::Conflict, "path already exists").response());
     }
 
-    // create directory
-    if let Err(e) = std::fs::create_dir_all(&setup.path) {
-        let res = Response::builder()
-            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Full::new(Bytes::from(format!(
-                "{{\"error\": \"mkdir failed: {}\"}}",
-                e
-            ))))
-            .unwrap();
-        return Ok(res);
+    // Reserve the path before creating it, a concurrent setup of the same path gets a 409
+    let path = setup.path.clone();
+    let work = async move {
+        // create directory, it may have appeared since the check above
+        let mkdir = |e: std::io::Error| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => ApiError::new(ErrorCode::Conflict, "path already exists"),
+            _ => ApiError::internal(format!("mkdir failed: {}", e)),
+        };
+        if let Some(parent) = Path::new(&setup.path).parent() {
+            std::fs::create_dir_all(parent).map_err(mkdir)?;
+        }
+        std::fs::create_dir(&setup.path).map_err(mkdir)?;
+        install(setup).await
+    };
+    match jobs.spawn_exclusive(JobKind::RunnerSetup, &path, key, work) {
+        Ok(job) => Ok(services::jobs::accepted(&job)),
+        Err(running) => Ok(services::jobs::busy(&path, &running)),
     }
+}
+
+/// Download the latest runner release into the (new) deployment directory, configure and start it.
+async fn install(setup: SetupRequest) -> Result<Value, ApiError> {
+    let runner_config = &config::get().runner;
+    let setup_timeout = Duration::from_secs(config::get().timeouts.runner_setup_secs);
+    let download_timeout = Duration::from_secs(runner_config.download_timeout_secs);
 
     // create an HTTP client that does NOT follow redirects so we can read the Location header
     let client_no_redirect = match reqwest::Client::builder()
         .redirect(reqwest::redirect::Policy::none())
+        .timeout(download_timeout)
         .build()
     {
         Ok(c) => c,
-        Err(e) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(format!(
-                    "{{\"error\": \"client init failed: {}\"}}",
-                    e
-                ))))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Err(ApiError::internal(format!("client init failed: {}", e))),
     };
 
     // get latest release redirect
-    let latest_url = "https://github.com/actions/runner/releases/latest";
-    let resp = match client_no_redirect.get(latest_url).send().await {
+    let resp = match client_no_redirect.get(&runner_config.latest_release_url).send().await {
         Ok(r) => r,
-        Err(e) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::BAD_GATEWAY)
-                .body(Full::new(Bytes::from(format!(
-                    "{{\"error\": \"request failed: {}\"}}",
-                    e
-                ))))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Err(ApiError::upstream(format!("request failed: {}", e))),
     };
 
     let status = resp.status().as_u16();
     if !(status == 302 || status == 301) {
-        let res = Response::builder()
-            .status(hyper::StatusCode::BAD_GATEWAY)
-            .body(Full::new(Bytes::from(format!(
-                "{{\"error\": \"unexpected redirect status: {}\"}}",
-                status
-            ))))
-            .unwrap();
-        return Ok(res);
+        return Err(ApiError::upstream(format!("unexpected redirect status: {}", status)));
     }
 
     let location = match resp.headers().get(reqwest::header::LOCATION) {
@@ -127,13 +119,7 @@ pub async fn setup_new(
     };
 
     if location.is_empty() {
-        let res = Response::builder()
-            .status(hyper::StatusCode::BAD_GATEWAY)
-            .body(Full::new(Bytes::from(
-                "{\"error\": \"no location header\"}",
-            )))
-            .unwrap();
-        return Ok(res);
+        return Err(ApiError::upstream("no location header"));
     }
 
     // expect format .../tag/vX.Y.Z
@@ -144,182 +130,132 @@ pub async fn setup_new(
         .map(|m| m.as_str().to_string())
     {
         Some(t) => t,
-        None => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::BAD_GATEWAY)
-                .body(Full::new(Bytes::from(
-                    "{\"error\": \"could not extract version tag\"}",
-                )))
-                .unwrap();
-            return Ok(res);
-        }
+        None => return Err(ApiError::upstream("could not extract version tag")),
     };
 
     let file_version = tag.trim_start_matches('v');
 
-    let file_name = format!("actions-runner-linux-x64-{}.tar.gz", file_version);
+    let file_name = format!("actions-runner-{}-{}.tar.gz", runner_config.platform, file_version);
     let download_url = format!(
-        "https://github.com/actions/runner/releases/download/{}/{}",
-        tag, file_name
+        "{}/{}/{}",
+        runner_config.download_base_url.trim_end_matches('/'), tag, file_name
     );
 
     // download file
-    let download_resp = match reqwest::get(&download_url).await {
+    jobs::step(&format!("Downloading {}", download_url));
+    let client = match reqwest::Client::builder().timeout(download_timeout).build() {
+        Ok(c) => c,
+        Err(e) => return Err(ApiError::internal(format!("client init failed: {}", e))),
+    };
+    let download_resp = match client.get(&download_url).send().await {
         Ok(r) => r,
-        Err(e) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::BAD_GATEWAY)
-                .body(Full::new(Bytes::from(format!(
-                    "{{\"error\": \"download failed: {}\"}}",
-                    e
-                ))))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Err(ApiError::upstream(format!("download failed: {}", e))),
     };
 
     if !download_resp.status().is_success() {
-        let res = Response::builder()
-            .status(hyper::StatusCode::BAD_GATEWAY)
-            .body(Full::new(Bytes::from(format!(
-                "{{\"error\": \"download returned {}\"}}",
-                download_resp.status()
-            ))))
-            .unwrap();
-        return Ok(res);
+        return Err(ApiError::upstream(format!("download returned {}", download_resp.status())));
     }
 
     let bytes = match download_resp.bytes().await {
         Ok(b) => b,
-        Err(e) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::BAD_GATEWAY)
-                .body(Full::new(Bytes::from(format!(
-                    "{{\"error\": \"reading download failed: {}\"}}",
-                    e
-                ))))
-                .unwrap();
-            return Ok(res);
-        }
+        Err(e) => return Err(ApiError::upstream(format!("reading download failed: {}", e))),
     };
 
     let target_file_path = Path::new(&setup.path).join(&file_name);
     if let Err(e) = std::fs::write(&target_file_path, &bytes) {
-        let res = Response::builder()
-            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Full::new(Bytes::from(format!(
-                "{{\"error\": \"write file failed: {}\"}}",
-                e
-            ))))
-            .unwrap();
-        return Ok(res);
+        return Err(ApiError::internal(format!("write file failed: {}", e)));
     }
 
     // extract tar
-    let extract = Command::new("tar")
-        .arg("xzf")
-        .arg(target_file_path.file_name().unwrap())
-        .current_dir(&setup.path)
-        .status();
+    jobs::step(&format!("Extracting {}", file_name));
+    let extract = util::command_status(
+        "tar",
+        Some(vec!["xzf", &file_name]),
+        Some(&setup.path),
+        setup_timeout,
+    )
+    .await;
 
     match extract {
         Ok(s) if s.success() => {}
-        Ok(s) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(format!(
-                    "{{\"error\": \"tar exited with {}\"}}",
-                    s
-                ))))
-                .unwrap();
-            return Ok(res);
-        }
-        Err(e) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(format!(
-                    "{{\"error\": \"tar failed: {}\"}}",
-                    e
-                ))))
-                .unwrap();
-            return Ok(res);
-        }
+        Ok(s) => return Err(ApiError::command_exit("tar", s)),
+        Err(e) => return Err(ApiError::command("tar", &e)),
     }
 
     // run config script
-    let cfg = Command::new("./config.sh")
-        .arg("--url")
-        .arg(&setup.git_url)
-        .arg("--token")
-        .arg(&setup.token)
-        .arg("--unattended")
-        .current_dir(&setup.path)
-        .status();
+    jobs::step("Configuring runner");
+    let cfg = util::command_status(
+        "./config.sh",
+        Some(vec!["--url", &setup.git_url, "--token", &setup.token, "--unattended"]),
+        Some(&setup.path),
+        setup_timeout,
+    )
+    .await;
 
     match cfg {
         Ok(s) if s.success() => {}
-        Ok(s) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(format!(
-                    "{{\"error\": \"config exited with {}\"}}",
-                    s
-                ))))
-                .unwrap();
-            return Ok(res);
-        }
-        Err(e) => {
-            let res = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(format!(
-                    "{{\"error\": \"config failed: {}\"}}",
-                    e
-                ))))
-                .unwrap();
-            return Ok(res);
-        }
+        Ok(s) => return Err(ApiError::command_exit("./config.sh", s)),
+        Err(e) => return Err(ApiError::command("./config.sh", &e)),
     }
 
     // try to install and start service
+    jobs::step("Installing and starting the runner service");
     let mut svc_result = String::new();
-    let svc_install = Command::new("sudo")
-        .arg("-n")
-        .arg("./svc.sh")
-        .arg("install")
-        .current_dir(&setup.path)
-        .status();
+    let svc_install = util::command_status(
+        "sudo",
+        Some(vec!["-n", "./svc.sh", "install"]),
+        Some(&setup.path),
+        setup_timeout,
+    )
+    .await;
     match svc_install {
         Ok(s) if s.success() => svc_result.push_str("installed "),
         Ok(s) => svc_result.push_str(&format!("install-exit:{} ", s)),
         Err(e) => svc_result.push_str(&format!("install-err:{} ", e)),
     }
 
-    let svc_start = Command::new("sudo")
-        .arg("-n")
-        .arg("./svc.sh")
-        .arg("start")
-        .current_dir(&setup.path)
-        .status();
+    let svc_start = util::command_status(
+        "sudo",
+        Some(vec!["-n", "./svc.sh", "start"]),
+        Some(&setup.path),
+        setup_timeout,
+    )
+    .await;
     match svc_start {
         Ok(s) if s.success() => svc_result.push_str("started"),
         Ok(s) => svc_result.push_str(&format!("start-exit:{}", s)),
         Err(e) => svc_result.push_str(&format!("start-err:{}", e)),
     }
 
-    let res = Response::builder()
-        .status(hyper::StatusCode::OK)
-        .body(Full::new(Bytes::from(format!(
-            "{{\"ok\": true, \"service\": \"{}\"}}",
-            svc_result
-        ))))
-        .unwrap();
-    Ok(res)
+    let result = SetupResult {
+        ok: true,
+        service: svc_result,
+    };
+    Ok(serde_json::to_value(&result).unwrap())
 }
 
-pub fn get_status(
+pub async fn get_status(
     svc_path: &str,
+    key: &ApiKey,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
-    let service_status = util::command_output("sudo", Some(vec!["-n", "./svc.sh", "status"]), Some(&svc_path));
+    if let Err(e) = util::check_deployment_path(svc_path) {
+        return Ok(ApiError::path_not_allowed(e).response());
+    }
+    if !key.allows_path(svc_path) {
+        return Ok(ApiError::path_not_allowed("key is restricted to other deployment paths").response());
+    }
+
+    let service_status = match util::command_output(
+        "sudo",
+        Some(vec!["-n", "./svc.sh", "status"]),
+        Some(svc_path),
+        Duration::from_secs(config::get().timeouts.command_secs),
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::command("svc.sh status", &e).response()),
+    };
 
     Ok(Response::new(Full::new(Bytes::from(service_status))))
 }
@@ -1,5 +1,7 @@
 use std::convert::Infallible;
+use std::time::Duration;
 
+use schemars::JsonSchema;
 use serde::Serialize;
 use serde_json;
 
@@ -7,10 +9,12 @@ use http_body_util::Full;
 use hyper::body::Bytes;
 use hyper::{Request, Response};
 
+use crate::config;
+use crate::error::ApiError;
 use crate::util;
 
-#[derive(Serialize)]
-struct SystemStats {
+#[derive(Serialize, JsonSchema)]
+pub struct SystemStats {
     memory_total: i32,
     memory_available: i32,
     memory_swapped: i32,
@@ -22,14 +26,24 @@ struct SystemStats {
     cpu_usage: i32,
 }
 
-pub fn health(_: Request<hyper::body::Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
-    let vmstat = util::command_output("vmstat", None, None);
+pub async fn health(_: Request<Full<Bytes>>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let timeout = Duration::from_secs(config::get().timeouts.command_secs);
+    let outputs = (
+        util::command_output("vmstat", None, None, timeout).await,
+        util::command_output("awk", Some(vec!["/^MemTotal:/ {printf $2}", "/proc/meminfo"]), None, timeout).await,
+        util::command_output("awk", Some(vec!["/^MemAvailable:/ {printf $2}", "/proc/meminfo"]), None, timeout).await,
+    );
+    let (vmstat, total_mem, memory_available) = match outputs {
+        (Ok(vmstat), Ok(total_mem), Ok(memory_available)) => (vmstat, total_mem, memory_available),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            log::error!("Health command failed: {}", e);
+            return Ok(ApiError::command("reading system stats", &e).response());
+        }
+    };
     let vmstat_split: Vec<Vec<&str>> = vmstat
         .lines()
         .map(|line| line.split_whitespace().collect())
         .collect();
-    let total_mem = util::command_output("awk", Some(vec!["/^MemTotal:/ {printf $2}", "/proc/meminfo"]), None);
-    let memory_available = util::command_output("awk", Some(vec!["/^MemAvailable:/ {printf $2}", "/proc/meminfo"]), None);
 
     let mut result = SystemStats {
         memory_total: total_mem.parse::<i32>().unwrap(),
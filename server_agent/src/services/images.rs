@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, OnceLock};
+
+use bollard::auth::DockerCredentials;
+use bollard::models::{ImageDeleteResponseItem, ImageSummary};
+use bollard::query_parameters::ListContainersOptionsBuilder;
+use bollard::query_parameters::ListImagesOptionsBuilder;
+use bollard::query_parameters::PruneImagesOptionsBuilder;
+use bollard::query_parameters::RemoveImageOptionsBuilder;
+use bollard::query_parameters::TagImageOptionsBuilder;
+use bollard::Docker;
+
+use http_body_util::BodyExt;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, Response};
+
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{self, Value};
+
+use crate::auth::ApiKey;
+use crate::error::{ApiError, ErrorCode};
+use crate::image_ref::ImageRef;
+use crate::jobs::{self, JobKind, JobStore};
+use crate::registries::RegistryStore;
+use crate::services::{self, docker};
+use crate::util;
+
+/// Query of the routes that act on one image.
+#[derive(Deserialize, JsonSchema)]
+pub struct ImageQuery {
+    /// Image reference like `nginx:1.27`, or an image ID (prefix)
+    image: String,
+}
+
+/// Query of `DELETE /docker/images`.
+#[derive(Deserialize, JsonSchema)]
+pub struct RemoveQuery {
+    /// Image reference like `nginx:1.27`, or an image ID (prefix)
+    image: String,
+    /// Remove the image even if stopped containers use it or it has several tags
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct PullRequest {
+    /// Image reference like `ghcr.io/team/app:1.2`, without a tag `latest` is pulled
+    image: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct TagRequest {
+    /// Image reference or ID to tag
+    source: String,
+    /// New reference, `latest` if it has no tag
+    target: String,
+}
+
+#[derive(Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PruneRequest {
+    /// Also remove tagged images no container uses, not only dangling ones
+    #[serde(default)]
+    all: bool,
+    /// Only images created longer ago than this, as a duration like `24h` or `90m`
+    older_than: Option<String>,
+}
+
+/// A local image and the containers that use it.
+#[derive(Serialize, JsonSchema)]
+pub struct ImageInfo {
+    id: String,
+    tags: Vec<String>,
+    digests: Vec<String>,
+    /// Unix time of creation
+    created: i64,
+    /// Size including the layers shared with other images, in bytes
+    size: i64,
+    /// Size of the layers no other image uses, what removing the image frees, in bytes
+    unique_size: i64,
+    /// Names of the containers created from the image, stopped ones included
+    containers: Vec<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct PullResult {
+    image: String,
+    id: String,
+    size: i64,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TagResult {
+    id: String,
+    tags: Vec<String>,
+}
+
+/// What a remove or prune did.
+#[derive(Serialize, JsonSchema)]
+pub struct RemovalResult {
+    /// Tags that were removed
+    untagged: Vec<String>,
+    /// IDs of removed images and layers
+    deleted: Vec<String>,
+    /// Freed disk space in bytes
+    space_reclaimed: i64,
+}
+
+impl RemovalResult {
+    fn new(items: Vec<ImageDeleteResponseItem>, space_reclaimed: i64) -> RemovalResult {
+        let mut result = RemovalResult {
+            untagged: Vec::new(),
+            deleted: Vec::new(),
+            space_reclaimed,
+        };
+        for item in items {
+            result.untagged.extend(item.untagged);
+            result.deleted.extend(item.deleted);
+        }
+        result
+    }
+}
+
+/// `image` as the Docker API takes it, an ID (prefix) or a normalized reference.
+fn image_name(image: &str) -> Result<String, String> {
+    static ID: OnceLock<Regex> = OnceLock::new();
+    let id = ID.get_or_init(|| Regex::new("^(sha256:[0-9a-f]{64}|[0-9a-f]{12,64})$").unwrap());
+    if id.is_match(image) {
+        return Ok(image.to_string());
+    }
+    image.parse::<ImageRef>().map(|image| image.to_string()).map_err(|e| format!("invalid image: {}", e))
+}
+
+/// Whether `duration` is a Go duration like `24h` or `1h30m`, what the daemon's `until` filter takes.
+fn valid_duration(duration: &str) -> bool {
+    static DURATION: OnceLock<Regex> = OnceLock::new();
+    let pattern = DURATION.get_or_init(|| Regex::new(r"^([0-9]+(\.[0-9]+)?(h|m|s|ms))+$").unwrap());
+    pattern.is_match(duration)
+}
+
+async fn local_images(docker: &Docker) -> Result<Vec<ImageSummary>, ApiError> {
+    let options = ListImagesOptionsBuilder::default().shared_size(true).build();
+    docker.list_images(Some(options)).await.map_err(|e| ApiError::docker("image list", e))
+}
+
+fn unique_size(image: &ImageSummary) -> i64 {
+    // -1 when the daemon did not compute it
+    if image.shared_size >= 0 {
+        image.size - image.shared_size
+    } else {
+        image.size
+    }
+}
+
+pub async fn list(key: &ApiKey) -> Result<Response<Full<Bytes>>, Infallible> {
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let images = match local_images(&docker).await {
+        Ok(v) => v,
+        Err(e) => return Ok(e.response()),
+    };
+    let options = ListContainersOptionsBuilder::default().all(true).build();
+    let mut containers = match docker.list_containers(Some(options)).await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker("container list", e).response()),
+    };
+    docker::retain_allowed(&mut containers, key);
+
+    let mut users: HashMap<String, Vec<String>> = HashMap::new();
+    for container in containers {
+        let name = container.names.and_then(|names| names.into_iter().next()).unwrap_or_default();
+        if let Some(image_id) = container.image_id {
+            users.entry(image_id).or_default().push(name.trim_start_matches('/').to_string());
+        }
+    }
+
+    let images: Vec<ImageInfo> = images
+        .into_iter()
+        .map(|image| ImageInfo {
+            unique_size: unique_size(&image),
+            containers: users.remove(&image.id).unwrap_or_default(),
+            id: image.id,
+            tags: image.repo_tags,
+            digests: image.repo_digests,
+            created: image.created,
+            size: image.size,
+        })
+        .collect();
+
+    let serialized = serde_json::to_string(&images).unwrap();
+    Ok(Response::new(Full::new(Bytes::from(serialized))))
+}
+
+pub async fn inspect(query: ImageQuery) -> Result<Response<Full<Bytes>>, Infallible> {
+    let image = match image_name(&query.image) {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::new(ErrorCode::InvalidQuery, e).response()),
+    };
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    match docker.inspect_image(&image).await {
+        Ok(inspect) => {
+            let serialized = serde_json::to_string(&inspect).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Err(e) => Ok(ApiError::docker("image inspect", e).response()),
+    }
+}
+
+/// Pull an image as a job, the layers show up as its progress.
+pub async fn pull(
+    request: Request<Full<Bytes>>,
+    key: &ApiKey,
+    jobs: Arc<JobStore>,
+    registries: &RegistryStore,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match request.into_body().collect().await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    let body_bytes = body.to_bytes();
+    let pull: PullRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+    let image: ImageRef = match pull.image.parse() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::new(ErrorCode::InvalidBody, format!("invalid image: {}", e)).response()),
+    };
+
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let credentials = registries.credentials(&image);
+    let job = jobs.spawn(JobKind::ImagePull, &image.to_string(), None, key, pull_work(docker, image, credentials));
+    Ok(services::jobs::accepted(&job))
+}
+
+async fn pull_work(docker: Docker, image: ImageRef, credentials: Option<DockerCredentials>) -> Result<Value, ApiError> {
+    docker::pull_image(&docker, &image, credentials).await?;
+    // A digest pull leaves the image without a tag, look it up the way it was pulled
+    let reference = match &image.digest {
+        Some(digest) => format!("{}@{}", image.name(), digest),
+        None => format!("{}:{}", image.name(), image.pull_tag()),
+    };
+    let inspect = docker
+        .inspect_image(&reference)
+        .await
+        .map_err(|e| ApiError::docker("image inspect", e))?;
+    jobs::step(&format!("Pulled {}", reference));
+    let result = PullResult {
+        image: reference,
+        id: inspect.id.unwrap_or_default(),
+        size: inspect.size.unwrap_or(0),
+    };
+    Ok(serde_json::to_value(result).unwrap())
+}
+
+pub async fn tag(request: Request<Full<Bytes>>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match request.into_body().collect().await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    let body_bytes = body.to_bytes();
+    let tag: TagRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+    let source = match image_name(&tag.source) {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::new(ErrorCode::InvalidBody, e).response()),
+    };
+    let target: ImageRef = match tag.target.parse() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::new(ErrorCode::InvalidBody, format!("invalid target: {}", e)).response()),
+    };
+    if target.digest.is_some() {
+        return Ok(ApiError::new(ErrorCode::InvalidBody, "target cannot have a digest").response());
+    }
+
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let options = TagImageOptionsBuilder::default()
+        .repo(&target.name())
+        .tag(target.tag.as_deref().unwrap_or("latest"))
+        .build();
+    if let Err(e) = docker.tag_image(&source, Some(options)).await {
+        return Ok(ApiError::docker("image tag", e).response());
+    }
+    match docker.inspect_image(&source).await {
+        Ok(inspect) => {
+            let result = TagResult {
+                id: inspect.id.unwrap_or_default(),
+                tags: inspect.repo_tags.unwrap_or_default(),
+            };
+            let serialized = serde_json::to_string(&result).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Err(e) => Ok(ApiError::docker("image inspect", e).response()),
+    }
+}
+
+pub async fn remove(query: RemoveQuery) -> Result<Response<Full<Bytes>>, Infallible> {
+    let image = match image_name(&query.image) {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::new(ErrorCode::InvalidQuery, e).response()),
+    };
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    // The daemon does not report freed space for a single image, the unique size is what goes
+    let id = match docker.inspect_image(&image).await {
+        Ok(inspect) => inspect.id.unwrap_or_default(),
+        Err(e) => return Ok(ApiError::docker("image inspect", e).response()),
+    };
+    let size = match local_images(&docker).await {
+        Ok(images) => images.iter().find(|i| i.id == id).map_or(0, unique_size),
+        Err(e) => return Ok(e.response()),
+    };
+
+    let options = RemoveImageOptionsBuilder::default().force(query.force).build();
+    match docker.remove_image(&image, Some(options), None).await {
+        Ok(items) => {
+            let removed = items.iter().any(|item| item.deleted.as_deref() == Some(id.as_str()));
+            let result = RemovalResult::new(items, if removed { size } else { 0 });
+            log::info!("Removed image {}, reclaimed {} bytes", image, result.space_reclaimed);
+            let serialized = serde_json::to_string(&result).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Err(e) => Ok(ApiError::docker("image remove", e).response()),
+    }
+}
+
+/// Remove dangling images, or with `all` every image no container uses.
+pub async fn prune(request: Request<Full<Bytes>>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match request.into_body().collect().await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    let body_bytes = body.to_bytes();
+    let prune: PruneRequest = if body_bytes.is_empty() {
+        PruneRequest::default()
+    } else {
+        match serde_json::from_slice(&body_bytes) {
+            Ok(v) => v,
+            Err(e) => return Ok(ApiError::invalid_body(e).response()),
+        }
+    };
+
+    let mut filters: HashMap<&str, Vec<String>> = HashMap::new();
+    filters.insert("dangling", vec![(!prune.all).to_string()]);
+    if let Some(older_than) = prune.older_than {
+        if !valid_duration(&older_than) {
+            let message = format!("older_than must be a duration like 24h or 90m (got {})", older_than);
+            return Ok(ApiError::new(ErrorCode::InvalidBody, message).response());
+        }
+        filters.insert("until", vec![older_than]);
+    }
+
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let options = PruneImagesOptionsBuilder::default().filters(&filters).build();
+    match docker.prune_images(Some(options)).await {
+        Ok(pruned) => {
+            let result = RemovalResult::new(pruned.images_deleted.unwrap_or_default(), pruned.space_reclaimed.unwrap_or(0));
+            log::info!("Pruned {} images and layers, reclaimed {} bytes", result.deleted.len(), result.space_reclaimed);
+            let serialized = serde_json::to_string(&result).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Err(e) => Ok(ApiError::docker("image prune", e).response()),
+    }
+}
@@ -0,0 +1,125 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json;
+
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::Response;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::auth::ApiKey;
+use crate::error::{ApiError, ErrorCode};
+use crate::jobs::{CancelError, Job, JobEvent, JobQuery, JobStore};
+use crate::sse;
+
+/// How long a cancel request waits for the job to stop before answering.
+const CANCEL_WAIT: Duration = Duration::from_secs(10);
+
+/// 202 answer of the endpoints that start a job.
+pub fn accepted(job: &Job) -> Response<Full<Bytes>> {
+    let serialized = serde_json::to_string(job).unwrap();
+    Response::builder()
+        .status(hyper::StatusCode::ACCEPTED)
+        .header("Content-Type", "application/json")
+        .header("Location", format!("/jobs/{}", job.summary.id))
+        .body(Full::new(Bytes::from(serialized)))
+        .unwrap()
+}
+
+/// 409 answer when another job still works on the deployment path.
+pub fn busy(path: &str, running: &str) -> Response<Full<Bytes>> {
+    ApiError::new(ErrorCode::Conflict, format!("job {} is still running on {}", running, path))
+        .with_details(serde_json::json!({ "job": running }))
+        .response()
+}
+
+pub async fn get(id: &str, key: &ApiKey, jobs: Arc<JobStore>) -> Result<Response<Full<Bytes>>, Infallible> {
+    match jobs.get(id, key) {
+        Some(job) => {
+            let serialized = serde_json::to_string(&job).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        None => Ok(ApiError::not_found(format!("no job {}", id)).response()),
+    }
+}
+
+pub async fn list(query: JobQuery, key: &ApiKey, jobs: Arc<JobStore>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let serialized = serde_json::to_string(&jobs.list(&query, key)).unwrap();
+    Ok(Response::new(Full::new(Bytes::from(serialized))))
+}
+
+pub async fn cancel(id: &str, key: &ApiKey, jobs: Arc<JobStore>) -> Result<Response<Full<Bytes>>, Infallible> {
+    match jobs.cancel(id, key, CANCEL_WAIT).await {
+        Ok(job) => {
+            let serialized = serde_json::to_string(&job).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Err(CancelError::NotFound) => Ok(ApiError::not_found(format!("no job {}", id)).response()),
+        Err(CancelError::MissingScope(scope)) => Ok(ApiError::new(
+            ErrorCode::MissingScope,
+            format!("key lacks the {} scope", scope),
+        )
+        .with_details(serde_json::json!({ "required_scope": scope }))
+        .response()),
+        Err(CancelError::Finished(state)) => Ok(ApiError::new(
+            ErrorCode::Conflict,
+            format!("job {} is no longer running", id),
+        )
+        .with_details(serde_json::json!({ "state": state }))
+        .response()),
+        Err(CancelError::NotCancellable) => Ok(ApiError::new(
+            ErrorCode::Conflict,
+            format!("job {} is in a step that cannot be interrupted, retry once it is done", id),
+        )
+        .response()),
+    }
+}
+
+/// Server-Sent Events of a job: a `job` event with its summary, the output so far, then live
+/// `output`, `step` and `progress` events until the closing `finished` event.
+pub async fn events(id: &str, key: &ApiKey, jobs: Arc<JobStore>) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
+    let Some((job, events)) = jobs.subscribe(id, key) else {
+        return Ok(ApiError::not_found(format!("no job {}", id)).response().map(BodyExt::boxed));
+    };
+    Ok(sse::response(move |sender| async move {
+        if !sender.send("job", &job.summary).await {
+            return;
+        }
+        // Replay what happened before the client connected
+        for line in job.output.lines() {
+            if !sender.send("output", &serde_json::json!({ "line": line })).await {
+                return;
+            }
+        }
+        let Some(mut events) = events else {
+            let (name, data) = JobEvent::Finished {
+                state: job.summary.state,
+                result: job.result,
+                error: job.error,
+            }
+            .encode();
+            sender.send(name, &data).await;
+            return;
+        };
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let finished = matches!(event, JobEvent::Finished { .. });
+                    let (name, data) = event.encode();
+                    if !sender.send(name, &data).await || finished {
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    if !sender.send("lagged", &serde_json::json!({ "skipped": skipped })).await {
+                        return;
+                    }
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }))
+}
@@ -0,0 +1,79 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json;
+
+use http_body_util::BodyExt;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, Response};
+
+use crate::auth::{self, KeyStore};
+use crate::config;
+use crate::error::{ApiError, ErrorCode};
+
+#[derive(Deserialize, JsonSchema, Default)]
+pub struct RotateRequest {
+    /// How long the old key stays valid, defaults to auth.rotation_grace_secs, at most 30 days
+    grace_period_secs: Option<u64>,
+}
+
+pub async fn rotate(
+    request: Request<Full<Bytes>>,
+    name: &str,
+    keys: Arc<KeyStore>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match request.into_body().collect().await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    // The body is optional
+    let body_bytes = body.to_bytes();
+    let rotate_request: RotateRequest = if body_bytes.is_empty() {
+        RotateRequest::default()
+    } else {
+        match serde_json::from_slice(&body_bytes) {
+            Ok(v) => v,
+            Err(e) => return Ok(ApiError::invalid_body(e).response()),
+        }
+    };
+
+    let auth_config = &config::get().auth;
+    let grace_secs = rotate_request
+        .grace_period_secs
+        .unwrap_or(auth_config.rotation_grace_secs);
+    if grace_secs > auth::MAX_ROTATION_GRACE_SECS {
+        return Ok(ApiError::new(
+            ErrorCode::InvalidRequest,
+            format!("grace_period_secs must be at most {}", auth::MAX_ROTATION_GRACE_SECS),
+        )
+        .response());
+    }
+    let grace = Duration::from_secs(grace_secs);
+    let bcrypt_cost = auth_config.bcrypt_cost;
+    let key_name = name.to_string();
+
+    // bcrypt and the file write are blocking
+    let rotated = tokio::task::spawn_blocking(move || keys.rotate(&key_name, grace, bcrypt_cost)).await;
+
+    match rotated {
+        Ok(Ok(Some(rotated))) => {
+            log::info!("Rotated key {}", rotated.name);
+            let serialized = serde_json::to_string(&rotated).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Ok(Ok(None)) => Ok(ApiError::not_found(format!("no key named {}", name)).response()),
+        Ok(Err(e)) => {
+            log::error!("Key rotation failed: {}", e);
+            Ok(ApiError::internal(format!("key rotation failed: {}", e)).response())
+        }
+        Err(e) => {
+            log::error!("Key rotation task failed: {}", e);
+            Ok(ApiError::internal("key rotation failed").response())
+        }
+    }
+}
@@ -1,4 +1,15 @@
 pub mod health;
 pub mod docker;
+pub mod container_update;
+pub mod container_stats;
+pub mod container_exec;
+pub mod container_terminal;
+pub mod images;
+pub mod volumes;
+pub mod networks;
 pub mod docker_compose;
 pub mod github_runners;
+pub mod keys;
+pub mod registries;
+pub mod audit;
+pub mod jobs;
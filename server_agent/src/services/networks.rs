@@ -0,0 +1,411 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+
+use bollard::models::{
+    ContainerSummary, EndpointIpamConfig, EndpointSettings, Ipam, IpamConfig, Network, NetworkConnectRequest,
+    NetworkCreateRequest, NetworkDisconnectRequest,
+};
+use bollard::query_parameters::{InspectNetworkOptions, ListContainersOptionsBuilder, ListNetworksOptions};
+use bollard::Docker;
+
+use http_body_util::BodyExt;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, Response};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json;
+
+use crate::auth::ApiKey;
+use crate::config;
+use crate::error::{ApiError, ErrorCode};
+use crate::jobs;
+use crate::services::docker;
+use crate::util;
+
+/// Networks every daemon has, they cannot be removed.
+const PREDEFINED: &[&str] = &["bridge", "host", "none"];
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CreateNetworkRequest {
+    name: String,
+    /// Network driver, `bridge` if unset
+    driver: Option<String>,
+    /// Without a route to the outside
+    #[serde(default)]
+    internal: bool,
+    /// Standalone containers may join a swarm scoped network
+    #[serde(default)]
+    attachable: bool,
+    /// Subnet in CIDR notation like `172.30.0.0/16`, picked by the daemon if unset
+    subnet: Option<String>,
+    /// Gateway inside `subnet`
+    gateway: Option<String>,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectRequest {
+    /// Container name or ID
+    container: String,
+    /// Extra DNS names of the container in this network
+    #[serde(default)]
+    aliases: Vec<String>,
+    /// Fixed address, the network needs a user-defined subnet for it
+    ipv4_address: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DisconnectRequest {
+    /// Container name or ID
+    container: String,
+    /// Disconnect even if the container is not running
+    #[serde(default)]
+    force: bool,
+}
+
+/// A network and the containers attached to it.
+#[derive(Serialize, JsonSchema)]
+pub struct NetworkInfo {
+    id: String,
+    name: String,
+    driver: String,
+    scope: String,
+    internal: bool,
+    attachable: bool,
+    created: Option<String>,
+    /// Subnets in CIDR notation
+    subnets: Vec<String>,
+    labels: HashMap<String, String>,
+    /// Names of the attached containers, stopped ones included
+    containers: Vec<String>,
+}
+
+impl NetworkInfo {
+    fn new(network: Network, containers: Vec<String>) -> NetworkInfo {
+        let subnets = network
+            .ipam
+            .and_then(|ipam| ipam.config)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|config| config.subnet)
+            .collect();
+        NetworkInfo {
+            id: network.id.unwrap_or_default(),
+            name: network.name.unwrap_or_default(),
+            driver: network.driver.unwrap_or_default(),
+            scope: network.scope.unwrap_or_default(),
+            internal: network.internal.unwrap_or(false),
+            attachable: network.attachable.unwrap_or(false),
+            created: network.created,
+            subnets,
+            labels: network.labels.unwrap_or_default(),
+            containers,
+        }
+    }
+}
+
+/// The containers the key may see, stopped ones included.
+async fn visible_containers(docker: &Docker, key: &ApiKey) -> Result<Vec<ContainerSummary>, ApiError> {
+    let options = ListContainersOptionsBuilder::default().all(true).build();
+    let mut containers = docker
+        .list_containers(Some(options))
+        .await
+        .map_err(|e| ApiError::docker("container list", e))?;
+    docker::retain_allowed(&mut containers, key);
+    Ok(containers)
+}
+
+/// Container names per network name.
+fn attached(containers: Vec<ContainerSummary>) -> HashMap<String, Vec<String>> {
+    let mut attached: HashMap<String, Vec<String>> = HashMap::new();
+    for container in containers {
+        let name = container.names.and_then(|names| names.into_iter().next()).unwrap_or_default();
+        let name = name.trim_start_matches('/');
+        let networks = container.network_settings.and_then(|settings| settings.networks).unwrap_or_default();
+        for network in networks.into_keys() {
+            attached.entry(network).or_default().push(name.to_string());
+        }
+    }
+    attached
+}
+
+async fn network_info(docker: &Docker, name: &str, key: &ApiKey) -> Result<NetworkInfo, ApiError> {
+    let network = docker
+        .inspect_network(name, None::<InspectNetworkOptions>)
+        .await
+        .map_err(|e| ApiError::docker("network inspect", e))?;
+    let mut attached = attached(visible_containers(docker, key).await?);
+    let containers = network.name.as_ref().and_then(|name| attached.remove(name)).unwrap_or_default();
+    Ok(NetworkInfo::new(network, containers))
+}
+
+pub async fn list(key: &ApiKey) -> Result<Response<Full<Bytes>>, Infallible> {
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let networks = match docker.list_networks(None::<ListNetworksOptions>).await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker("network list", e).response()),
+    };
+    let mut attached = match visible_containers(&docker, key).await {
+        Ok(containers) => attached(containers),
+        Err(e) => return Ok(e.response()),
+    };
+
+    let networks: Vec<NetworkInfo> = networks
+        .into_iter()
+        .map(|network| {
+            let containers = network.name.as_ref().and_then(|name| attached.remove(name)).unwrap_or_default();
+            NetworkInfo::new(network, containers)
+        })
+        .collect();
+
+    let serialized = serde_json::to_string(&networks).unwrap();
+    Ok(Response::new(Full::new(Bytes::from(serialized))))
+}
+
+/// The daemon's view of a network. For path-restricted keys, only their own containers are listed.
+pub async fn inspect(name: &str, key: &ApiKey) -> Result<Response<Full<Bytes>>, Infallible> {
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let mut network = match docker.inspect_network(name, None::<InspectNetworkOptions>).await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker("network inspect", e).response()),
+    };
+    if key.is_path_restricted() {
+        let visible: HashSet<String> = match visible_containers(&docker, key).await {
+            Ok(containers) => containers.into_iter().filter_map(|c| c.id).collect(),
+            Err(e) => return Ok(e.response()),
+        };
+        if let Some(containers) = network.containers.as_mut() {
+            containers.retain(|id, _| visible.contains(id));
+        }
+    }
+
+    let serialized = serde_json::to_string(&network).unwrap();
+    Ok(Response::new(Full::new(Bytes::from(serialized))))
+}
+
+pub async fn create(request: Request<Full<Bytes>>, key: &ApiKey) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match request.into_body().collect().await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    let body_bytes = body.to_bytes();
+    let create: CreateNetworkRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+    if !valid_network_name(&create.name) {
+        let message = format!("invalid network name {}, use letters, digits, _, . and -", create.name);
+        return Ok(ApiError::new(ErrorCode::InvalidBody, message).response());
+    }
+    if create.gateway.is_some() && create.subnet.is_none() {
+        return Ok(ApiError::new(ErrorCode::InvalidBody, "gateway needs a subnet").response());
+    }
+
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let ipam = create.subnet.map(|subnet| Ipam {
+        config: Some(vec![IpamConfig {
+            subnet: Some(subnet),
+            gateway: create.gateway,
+            ..Default::default()
+        }]),
+        ..Default::default()
+    });
+    let options = NetworkCreateRequest {
+        name: create.name.clone(),
+        driver: Some(create.driver.unwrap_or_else(|| "bridge".to_string())),
+        internal: Some(create.internal),
+        attachable: Some(create.attachable),
+        ipam,
+        labels: Some(create.labels),
+        ..Default::default()
+    };
+    if let Err(e) = docker.create_network(options).await {
+        return Ok(ApiError::docker("network create", e).response());
+    }
+    log::info!("Created network {}", create.name);
+
+    match network_info(&docker, &create.name, key).await {
+        Ok(info) => {
+            let serialized = serde_json::to_string(&info).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Err(e) => Ok(e.response()),
+    }
+}
+
+/// Remove a network, which fails while containers are attached. Answers with what was removed.
+pub async fn remove(name: &str, key: &ApiKey) -> Result<Response<Full<Bytes>>, Infallible> {
+    if PREDEFINED.contains(&name) {
+        let message = format!("{} is a predefined network and cannot be removed", name);
+        return Ok(ApiError::new(ErrorCode::InvalidRequest, message).response());
+    }
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let info = match network_info(&docker, name, key).await {
+        Ok(v) => v,
+        Err(e) => return Ok(e.response()),
+    };
+    match docker.remove_network(name).await {
+        Ok(()) => {
+            log::info!("Removed network {}", info.name);
+            let serialized = serde_json::to_string(&info).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Err(e) => Ok(network_error("network remove", e).response()),
+    }
+}
+
+pub async fn connect(request: Request<Full<Bytes>>, name: &str, key: &ApiKey) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match request.into_body().collect().await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    let body_bytes = body.to_bytes();
+    let connect: ConnectRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+    let container = match docker::resolve_container(key, &connect.container).await {
+        Ok(v) => v,
+        Err(e) => return Ok(e.response()),
+    };
+
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let endpoint = EndpointSettings {
+        aliases: (!connect.aliases.is_empty()).then_some(connect.aliases),
+        ipam_config: connect.ipv4_address.map(|address| EndpointIpamConfig {
+            ipv4_address: Some(address),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let options = NetworkConnectRequest {
+        container: Some(container.clone()),
+        endpoint_config: Some(endpoint),
+    };
+    if let Err(e) = docker.connect_network(name, options).await {
+        return Ok(network_error("network connect", e).response());
+    }
+    log::info!("Connected container {} to network {}", connect.container, name);
+
+    match network_info(&docker, name, key).await {
+        Ok(info) => {
+            let serialized = serde_json::to_string(&info).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Err(e) => Ok(e.response()),
+    }
+}
+
+pub async fn disconnect(request: Request<Full<Bytes>>, name: &str, key: &ApiKey) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match request.into_body().collect().await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    let body_bytes = body.to_bytes();
+    let disconnect: DisconnectRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+    let container = match docker::resolve_container(key, &disconnect.container).await {
+        Ok(v) => v,
+        Err(e) => return Ok(e.response()),
+    };
+
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let options = NetworkDisconnectRequest {
+        container: Some(container),
+        force: Some(disconnect.force),
+    };
+    if let Err(e) = docker.disconnect_network(name, options).await {
+        return Ok(network_error("network disconnect", e).response());
+    }
+    log::info!("Disconnected container {} from network {}", disconnect.container, name);
+
+    match network_info(&docker, name, key).await {
+        Ok(info) => {
+            let serialized = serde_json::to_string(&info).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Err(e) => Ok(e.response()),
+    }
+}
+
+/// The daemon answers 403 when a container is already attached or a network still has endpoints,
+/// which is a conflict rather than a failure.
+fn network_error(action: &str, e: bollard::errors::Error) -> ApiError {
+    match e {
+        bollard::errors::Error::DockerResponseServerError { status_code: 403, message } => {
+            ApiError::new(ErrorCode::Conflict, format!("{} failed: {}", action, message))
+                .with_details(serde_json::json!({ "docker_status": 403 }))
+        }
+        e => ApiError::docker(action, e),
+    }
+}
+
+fn valid_network_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// Create the Traefik network from `install.sh` if it is missing, so deployments that join it
+/// do not fail on a fresh or cleaned up node.
+pub async fn ensure_traefik_network(docker: &Docker) -> Result<(), ApiError> {
+    let name = &config::get().docker.traefik_network;
+    if name.is_empty() {
+        return Ok(());
+    }
+    match docker.inspect_network(name, None::<InspectNetworkOptions>).await {
+        Ok(_) => return Ok(()),
+        Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => {}
+        Err(e) => return Err(ApiError::docker("network inspect", e)),
+    }
+    let options = NetworkCreateRequest {
+        name: name.clone(),
+        driver: Some("bridge".to_string()),
+        ..Default::default()
+    };
+    match docker.create_network(options).await {
+        Ok(_) => {
+            log::info!("Created missing Traefik network {}", name);
+            jobs::step(&format!("Created network {}", name));
+            Ok(())
+        }
+        // Another deployment created it in the meantime
+        Err(bollard::errors::Error::DockerResponseServerError { status_code: 409, .. }) => Ok(()),
+        Err(e) => Err(ApiError::docker("network create", e)),
+    }
+}
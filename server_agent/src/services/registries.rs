@@ -0,0 +1,83 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json;
+
+use http_body_util::BodyExt;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, Response};
+
+use crate::error::{ApiError, ErrorCode};
+use crate::registries::{self, RegistryStore};
+
+#[derive(Deserialize, JsonSchema)]
+pub struct RegistryRequest {
+    /// Registry host with optional port, e.g. `ghcr.io` or `localhost:5000`; `docker.io` for Docker Hub
+    host: String,
+    username: String,
+    /// Password or access token
+    password: String,
+}
+
+pub async fn list(registries: Arc<RegistryStore>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let serialized = serde_json::to_string(&registries.list()).unwrap();
+    Ok(Response::new(Full::new(Bytes::from(serialized))))
+}
+
+pub async fn add(
+    request: Request<Full<Bytes>>,
+    registries: Arc<RegistryStore>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match request.into_body().collect().await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    let body_bytes = body.to_bytes();
+    let registry: RegistryRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    let host = match registries::normalize_host(&registry.host) {
+        Ok(host) => host,
+        Err(e) => return Ok(ApiError::new(ErrorCode::InvalidBody, e).response()),
+    };
+    if registry.username.is_empty() || registry.password.is_empty() {
+        return Ok(ApiError::new(ErrorCode::InvalidBody, "username and password must not be empty").response());
+    }
+
+    match registries.add(&host, &registry.username, &registry.password) {
+        Ok(info) => {
+            log::info!("Stored credentials for registry {}", host);
+            let serialized = serde_json::to_string(&info).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Err(e) => {
+            log::error!("Storing registry credentials failed: {}", e);
+            Ok(ApiError::internal(format!("cannot store registry credentials: {}", e)).response())
+        }
+    }
+}
+
+pub async fn remove(host: &str, registries: Arc<RegistryStore>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let host = match registries::normalize_host(host) {
+        Ok(host) => host,
+        Err(e) => return Ok(ApiError::new(ErrorCode::InvalidRequest, e).response()),
+    };
+    match registries.remove(&host) {
+        Ok(Some(info)) => {
+            log::info!("Removed credentials for registry {}", host);
+            let serialized = serde_json::to_string(&info).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Ok(None) => Ok(ApiError::not_found(format!("no credentials for registry {}", host)).response()),
+        Err(e) => {
+            log::error!("Removing registry credentials failed: {}", e);
+            Ok(ApiError::internal(format!("cannot remove registry credentials: {}", e)).response())
+        }
+    }
+}
@@ -0,0 +1,915 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bollard::models::{ContainerCreateBody, HostConfig, Mount, MountTypeEnum, Volume, VolumeCreateOptions};
+use bollard::query_parameters::CreateContainerOptionsBuilder;
+use bollard::query_parameters::DownloadFromContainerOptionsBuilder;
+use bollard::query_parameters::ListContainersOptionsBuilder;
+use bollard::query_parameters::ListVolumesOptions;
+use bollard::query_parameters::PruneVolumesOptionsBuilder;
+use bollard::query_parameters::RemoveContainerOptionsBuilder;
+use bollard::query_parameters::RemoveVolumeOptions;
+use bollard::query_parameters::StartContainerOptions;
+use bollard::query_parameters::UploadToContainerOptionsBuilder;
+use bollard::query_parameters::WaitContainerOptions;
+use bollard::Docker;
+
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::{Request, Response};
+use tokio::sync::mpsc;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json;
+
+use crate::auth::ApiKey;
+use crate::config;
+use crate::error::{ApiError, ErrorCode};
+use crate::image_ref::{ImageRef, PullPolicy};
+use crate::registries::RegistryStore;
+use crate::services::docker;
+use crate::shutdown;
+use crate::util;
+
+/// Where helper containers mount the volume; archives hold its contents under `volume/`.
+const MOUNT_POINT: &str = "/volume";
+/// Restores are uploaded here first and copied into the volume once the archive is complete.
+const STAGING_DIR: &str = "/tmp";
+/// Marks helper containers, so the ones a restart left behind are removed on the next start.
+const HELPER_LABEL: &str = "server_agent.helper";
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CreateVolumeRequest {
+    name: String,
+    /// Volume driver, `local` if unset
+    driver: Option<String>,
+    #[serde(default)]
+    driver_opts: HashMap<String, String>,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+}
+
+#[derive(Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VolumePruneRequest {
+    /// Also remove named volumes no container uses, not only anonymous ones
+    #[serde(default)]
+    all: bool,
+}
+
+/// Query of `POST /docker/volumes/{name}/restore`.
+#[derive(Deserialize, JsonSchema)]
+pub struct RestoreQuery {
+    /// Delete the current contents first, otherwise the archive is extracted over them
+    #[serde(default)]
+    replace: bool,
+    /// Restore even while running containers use the volume
+    #[serde(default)]
+    force: bool,
+}
+
+/// A volume and the containers that use it.
+#[derive(Serialize, JsonSchema)]
+pub struct VolumeInfo {
+    name: String,
+    driver: String,
+    mountpoint: String,
+    created_at: Option<String>,
+    labels: HashMap<String, String>,
+    /// Disk usage in bytes, -1 if the driver does not report it
+    size: i64,
+    /// Names of the containers that mount the volume, stopped ones included
+    containers: Vec<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct RestoreResult {
+    volume: String,
+    /// Size of the extracted archive in bytes
+    archive_size: usize,
+    /// Whether the previous contents were deleted first
+    replaced: bool,
+}
+
+/// What a remove or prune did.
+#[derive(Serialize, JsonSchema)]
+pub struct VolumeRemoval {
+    /// Names of the removed volumes
+    deleted: Vec<String>,
+    /// Freed disk space in bytes
+    space_reclaimed: i64,
+}
+
+fn valid_volume_name(name: &str) -> bool {
+    // The daemon's own rule for volume names
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && name.len() >= 2
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// Disk usage per volume name, as far as the daemon computed it.
+async fn volume_sizes(docker: &Docker) -> Result<HashMap<String, i64>, ApiError> {
+    // bollard cannot encode the `type` filter, so this computes the image and container usage too
+    let usage = docker.df(None).await.map_err(|e| ApiError::docker("system df", e))?;
+    Ok(usage
+        .volumes
+        .unwrap_or_default()
+        .into_iter()
+        .map(|volume| (volume.name, volume.usage_data.map_or(-1, |usage| usage.size)))
+        .collect())
+}
+
+pub async fn list(key: &ApiKey) -> Result<Response<Full<Bytes>>, Infallible> {
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let volumes: Vec<Volume> = match docker.list_volumes(None::<ListVolumesOptions>).await {
+        Ok(list) => list.volumes.unwrap_or_default(),
+        Err(e) => return Ok(ApiError::docker("volume list", e).response()),
+    };
+    let mut sizes = match volume_sizes(&docker).await {
+        Ok(v) => v,
+        Err(e) => return Ok(e.response()),
+    };
+    let options = ListContainersOptionsBuilder::default().all(true).build();
+    let mut containers = match docker.list_containers(Some(options)).await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker("container list", e).response()),
+    };
+    docker::retain_allowed(&mut containers, key);
+
+    let mut users: HashMap<String, Vec<String>> = HashMap::new();
+    for container in containers {
+        let name = container.names.and_then(|names| names.into_iter().next()).unwrap_or_default();
+        let name = name.trim_start_matches('/');
+        for mount in container.mounts.unwrap_or_default() {
+            if let Some(volume) = mount.name {
+                users.entry(volume).or_default().push(name.to_string());
+            }
+        }
+    }
+
+    let volumes: Vec<VolumeInfo> = volumes
+        .into_iter()
+        .map(|volume| VolumeInfo {
+            size: sizes.remove(&volume.name).unwrap_or(-1),
+            containers: users.remove(&volume.name).unwrap_or_default(),
+            name: volume.name,
+            driver: volume.driver,
+            mountpoint: volume.mountpoint,
+            created_at: volume.created_at,
+            labels: volume.labels,
+        })
+        .collect();
+
+    let serialized = serde_json::to_string(&volumes).unwrap();
+    Ok(Response::new(Full::new(Bytes::from(serialized))))
+}
+
+pub async fn create(request: Request<Full<Bytes>>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match request.into_body().collect().await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    let body_bytes = body.to_bytes();
+    let create: CreateVolumeRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+    if !valid_volume_name(&create.name) {
+        let message = format!("invalid volume name {}, use letters, digits, _, . and -", create.name);
+        return Ok(ApiError::new(ErrorCode::InvalidBody, message).response());
+    }
+
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    // The daemon returns an existing volume of the same name instead of failing
+    match docker.inspect_volume(&create.name).await {
+        Ok(_) => {
+            let message = format!("volume {} already exists", create.name);
+            return Ok(ApiError::new(ErrorCode::Conflict, message).response());
+        }
+        Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => {}
+        Err(e) => return Ok(ApiError::docker("volume inspect", e).response()),
+    }
+
+    let options = VolumeCreateOptions {
+        name: Some(create.name),
+        driver: create.driver,
+        driver_opts: Some(create.driver_opts),
+        labels: Some(create.labels),
+        ..Default::default()
+    };
+    match docker.create_volume(options).await {
+        Ok(volume) => {
+            log::info!("Created volume {}", volume.name);
+            let serialized = serde_json::to_string(&volume).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Err(e) => Ok(ApiError::docker("volume create", e).response()),
+    }
+}
+
+/// Remove a volume, which fails while a container (running or not) uses it.
+pub async fn remove(name: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    // The daemon does not report freed space for a single volume
+    let size = match volume_sizes(&docker).await {
+        Ok(sizes) => sizes.get(name).copied().unwrap_or(0).max(0),
+        Err(e) => return Ok(e.response()),
+    };
+
+    match docker.remove_volume(name, None::<RemoveVolumeOptions>).await {
+        Ok(()) => {
+            let result = VolumeRemoval {
+                deleted: vec![name.to_string()],
+                space_reclaimed: size,
+            };
+            log::info!("Removed volume {}, reclaimed {} bytes", name, size);
+            let serialized = serde_json::to_string(&result).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Err(e) => Ok(ApiError::docker("volume remove", e).response()),
+    }
+}
+
+/// Remove anonymous volumes no container uses, or with `all` every unused volume.
+pub async fn prune(request: Request<Full<Bytes>>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match request.into_body().collect().await {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::invalid_body(e).response()),
+    };
+
+    let body_bytes = body.to_bytes();
+    let prune: VolumePruneRequest = if body_bytes.is_empty() {
+        VolumePruneRequest::default()
+    } else {
+        match serde_json::from_slice(&body_bytes) {
+            Ok(v) => v,
+            Err(e) => return Ok(ApiError::invalid_body(e).response()),
+        }
+    };
+
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    let mut filters: HashMap<&str, Vec<String>> = HashMap::new();
+    if prune.all {
+        filters.insert("all", vec!["true".to_string()]);
+    }
+    let options = PruneVolumesOptionsBuilder::default().filters(&filters).build();
+    match docker.prune_volumes(Some(options)).await {
+        Ok(pruned) => {
+            let result = VolumeRemoval {
+                deleted: pruned.volumes_deleted.unwrap_or_default(),
+                space_reclaimed: pruned.space_reclaimed.unwrap_or(0),
+            };
+            log::info!("Pruned {} volumes, reclaimed {} bytes", result.deleted.len(), result.space_reclaimed);
+            let serialized = serde_json::to_string(&result).unwrap();
+            Ok(Response::new(Full::new(Bytes::from(serialized))))
+        }
+        Err(e) => Ok(ApiError::docker("volume prune", e).response()),
+    }
+}
+
+/// Create a stopped container with the volume mounted at `/volume`. Archives can be copied in
+/// and out of it without access to the host filesystem; `cmd` only runs if it gets started.
+async fn create_helper(
+    docker: &Docker,
+    volume: &str,
+    read_only: bool,
+    cmd: Vec<String>,
+    registries: &RegistryStore,
+) -> Result<String, ApiError> {
+    // Fail with a 404 here rather than letting the mount create an empty volume
+    docker
+        .inspect_volume(volume)
+        .await
+        .map_err(|e| ApiError::docker("volume inspect", e))?;
+
+    let helper_image = &config::get().docker.helper_image;
+    let image: ImageRef = helper_image
+        .parse()
+        .map_err(|e| ApiError::internal(format!("invalid helper image {}: {}", helper_image, e)))?;
+    docker::ensure_image(docker, &image, PullPolicy::IfNotPresent, registries.credentials(&image)).await?;
+
+    let mut suffix = [0u8; 6];
+    getrandom::getrandom(&mut suffix).map_err(|e| ApiError::internal(format!("no random source: {}", e)))?;
+    let name = format!("server_agent-volume-{}", hex::encode(suffix));
+    let config = ContainerCreateBody {
+        image: Some(image.to_string()),
+        cmd: Some(cmd),
+        labels: Some(HashMap::from([(HELPER_LABEL.to_string(), volume.to_string())])),
+        network_disabled: Some(true),
+        host_config: Some(HostConfig {
+            network_mode: Some("none".to_string()),
+            mounts: Some(vec![Mount {
+                typ: Some(MountTypeEnum::VOLUME),
+                source: Some(volume.to_string()),
+                target: Some(MOUNT_POINT.to_string()),
+                read_only: Some(read_only),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let options = CreateContainerOptionsBuilder::default().name(&name).build();
+    let created = docker
+        .create_container(Some(options), config)
+        .await
+        .map_err(|e| ApiError::docker("container create", e))?;
+    Ok(created.id)
+}
+
+async fn remove_helper(docker: &Docker, id: &str) {
+    let options = RemoveContainerOptionsBuilder::default().force(true).build();
+    if let Err(e) = docker.remove_container(id, Some(options)).await {
+        log::warn!("Cannot remove volume helper container {}: {}", id, e);
+    }
+}
+
+/// Stream the contents of a volume as a tar archive, with the files under `volume/`. Databases
+/// should be stopped first, files that change while they are copied end up inconsistent.
+pub async fn backup(name: &str, registries: &RegistryStore) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response().map(BodyExt::boxed)),
+    };
+
+    let helper = match create_helper(&docker, name, true, vec!["true".to_string()], registries).await {
+        Ok(v) => v,
+        Err(e) => return Ok(e.response().map(BodyExt::boxed)),
+    };
+    let options = DownloadFromContainerOptionsBuilder::new().path(MOUNT_POINT).build();
+    let mut archive = docker.download_from_container(&helper, Some(options)).boxed();
+    // Errors before the first chunk still get a proper error response
+    let first = match archive.try_next().await {
+        Ok(chunk) => chunk,
+        Err(e) => {
+            remove_helper(&docker, &helper).await;
+            return Ok(ApiError::docker("volume backup", e).response().map(BodyExt::boxed));
+        }
+    };
+
+    log::info!("Backing up volume {}", name);
+    let (tx, mut rx) = mpsc::channel::<Bytes>(16);
+    let volume = name.to_string();
+    tokio::spawn(shutdown::track(format!("volume backup {}", volume), async move {
+        let forward = async {
+            let mut chunk = first;
+            while let Some(bytes) = chunk {
+                if tx.send(bytes).await.is_err() {
+                    log::warn!("Backup of volume {}: client went away", volume);
+                    return;
+                }
+                chunk = match archive.try_next().await {
+                    Ok(chunk) => chunk,
+                    // The client sees a truncated archive, tar notices the missing end
+                    Err(e) => {
+                        log::error!("Backup of volume {} failed: {}", volume, e);
+                        return;
+                    }
+                };
+            }
+            log::info!("Backup of volume {} finished", volume);
+        };
+        tokio::select! {
+            _ = forward => {}
+            _ = shutdown::aborted() => log::warn!("Backup of volume {} aborted", volume),
+        }
+        drop(tx);
+        remove_helper(&docker, &helper).await;
+    }));
+
+    let frames = stream::poll_fn(move |cx| rx.poll_recv(cx).map(|chunk| chunk.map(|c| Ok(Frame::data(c)))));
+    Ok(Response::builder()
+        .header("Content-Type", "application/x-tar")
+        .header("Content-Disposition", format!("attachment; filename=\"{}.tar\"", name))
+        .body(BodyExt::boxed(StreamBody::new(frames)))
+        .unwrap())
+}
+
+/// Extract an uploaded tar archive into a volume. The archive has to hold the files under
+/// `volume/`, the way backups do. It is streamed into the helper's `/tmp` while its entries are
+/// checked, and only copied into the volume once it arrived complete and valid.
+pub async fn restore<B>(
+    request: Request<B>,
+    name: &str,
+    query: RestoreQuery,
+    registries: &RegistryStore,
+) -> Result<Response<Full<Bytes>>, Infallible>
+where
+    B: hyper::body::Body<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: fmt::Display,
+{
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => return Ok(ApiError::docker_unavailable(&e).response()),
+    };
+
+    if !query.force {
+        let filters = HashMap::from([("volume", vec![name.to_string()]), ("status", vec!["running".to_string()])]);
+        let options = ListContainersOptionsBuilder::default().filters(&filters).build();
+        let running: Vec<String> = match docker.list_containers(Some(options)).await {
+            Ok(containers) => containers
+                .into_iter()
+                .filter_map(|c| c.names.and_then(|names| names.into_iter().next()))
+                .map(|name| name.trim_start_matches('/').to_string())
+                .collect(),
+            Err(e) => return Ok(ApiError::docker("container list", e).response()),
+        };
+        if !running.is_empty() {
+            let message = format!("volume {} is used by running containers, stop them or use force", name);
+            return Ok(ApiError::new(ErrorCode::Conflict, message)
+                .with_details(serde_json::json!({ "containers": running }))
+                .response());
+        }
+    }
+
+    let copy = format!("tar -C {} -cf - volume | tar -C / -xf -", STAGING_DIR);
+    let script = if query.replace {
+        format!("find {} -mindepth 1 -delete && {}", MOUNT_POINT, copy)
+    } else {
+        copy
+    };
+    let cmd = vec!["sh".to_string(), "-c".to_string(), script];
+    let helper = match create_helper(&docker, name, false, cmd, registries).await {
+        Ok(v) => v,
+        Err(e) => return Ok(e.response()),
+    };
+    let upload = Upload::new(request.into_body());
+    let failure = upload.failure.clone();
+    let restored = restore_into(&docker, &helper, upload).await;
+    remove_helper(&docker, &helper).await;
+    let archive_size = match restored {
+        Ok(size) => size,
+        // A rejected body explains the failed upload better than the daemon's error
+        Err(e) => return Ok(failure.lock().unwrap().take().unwrap_or(e).response()),
+    };
+
+    log::info!("Restored volume {}{}", name, if query.replace { ", replacing its contents" } else { "" });
+    let result = RestoreResult {
+        volume: name.to_string(),
+        archive_size,
+        replaced: query.replace,
+    };
+    let serialized = serde_json::to_string(&result).unwrap();
+    Ok(Response::new(Full::new(Bytes::from(serialized))))
+}
+
+/// Upload the archive into the helper, then start it to copy the files into the volume.
+/// Returns the size of the archive.
+async fn restore_into<B>(docker: &Docker, helper: &str, upload: Upload<B>) -> Result<usize, ApiError>
+where
+    B: hyper::body::Body<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: fmt::Display,
+{
+    let received = upload.received.clone();
+    let options = UploadToContainerOptionsBuilder::new().path(STAGING_DIR).build();
+    docker
+        .upload_to_container(helper, Some(options), bollard::body_try_stream(upload.into_stream()))
+        .await
+        .map_err(|e| ApiError::docker("volume restore", e))?;
+
+    docker
+        .start_container(helper, None::<StartContainerOptions>)
+        .await
+        .map_err(|e| ApiError::docker("container start", e))?;
+    // Fails with the exit code if the helper could not clear or fill the volume
+    docker
+        .wait_container(helper, None::<WaitContainerOptions>)
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| ApiError::docker("volume restore", e))?;
+    Ok(received.load(Ordering::Relaxed))
+}
+
+/// The body of a restore on its way to the daemon, checked chunk by chunk. The first problem
+/// ends the stream with an error and is kept in `failure` for the response.
+struct Upload<B> {
+    body: B,
+    checker: TarChecker,
+    received: Arc<AtomicUsize>,
+    failure: Arc<Mutex<Option<ApiError>>>,
+    done: bool,
+}
+
+impl<B> Upload<B>
+where
+    B: hyper::body::Body<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: fmt::Display,
+{
+    fn new(body: B) -> Upload<B> {
+        Upload {
+            body,
+            checker: TarChecker::default(),
+            received: Arc::new(AtomicUsize::new(0)),
+            failure: Arc::new(Mutex::new(None)),
+            done: false,
+        }
+    }
+
+    fn into_stream(self) -> impl Stream<Item = Result<Bytes, io::Error>> + Send + 'static {
+        stream::unfold(self, |mut upload| async move {
+            if upload.done {
+                return None;
+            }
+            match upload.next_chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), upload)),
+                Ok(None) => None,
+                Err(e) => {
+                    let message = e.message.clone();
+                    *upload.failure.lock().unwrap() = Some(e);
+                    upload.done = true;
+                    Some((Err(io::Error::other(message)), upload))
+                }
+            }
+        })
+    }
+
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, ApiError> {
+        let idle = Duration::from_secs(config::get().timeouts.request_body_secs);
+        let max_bytes = config::get().docker.volume_restore_max_bytes;
+        loop {
+            let frame = match tokio::time::timeout(idle, self.body.frame()).await {
+                Ok(Some(Ok(frame))) => frame,
+                Ok(Some(Err(e))) => return Err(ApiError::invalid_body(e)),
+                Ok(None) => {
+                    self.checker.finish().map_err(invalid_archive)?;
+                    return Ok(None);
+                }
+                Err(_) => {
+                    let message = format!("the archive upload stalled for {}s", idle.as_secs());
+                    return Err(ApiError::new(ErrorCode::RequestTimeout, message));
+                }
+            };
+            // Trailers carry nothing for the archive
+            let Ok(chunk) = frame.into_data() else { continue };
+            let received = self.received.fetch_add(chunk.len(), Ordering::Relaxed) + chunk.len();
+            if received as u64 > max_bytes {
+                let message = format!("the archive is larger than {} bytes", max_bytes);
+                return Err(ApiError::new(ErrorCode::PayloadTooLarge, message));
+            }
+            self.checker.feed(&chunk).map_err(invalid_archive)?;
+            return Ok(Some(chunk));
+        }
+    }
+}
+
+fn invalid_archive(e: String) -> ApiError {
+    ApiError::new(ErrorCode::InvalidBody, format!("invalid archive: {}", e))
+}
+
+/// Remove helper containers left behind by a restart in the middle of a backup or restore.
+pub async fn remove_orphaned_helpers() {
+    let docker = match util::docker() {
+        Ok(v) => v,
+        Err(e) => {
+            log::warn!("Cannot look for orphaned volume helpers: {}", e);
+            return;
+        }
+    };
+    let filters = HashMap::from([("label", vec![HELPER_LABEL.to_string()])]);
+    let options = ListContainersOptionsBuilder::default().all(true).filters(&filters).build();
+    let helpers = match docker.list_containers(Some(options)).await {
+        Ok(v) => v,
+        Err(e) => {
+            log::warn!("Cannot look for orphaned volume helpers: {}", e);
+            return;
+        }
+    };
+    for helper in helpers.into_iter().filter_map(|c| c.id) {
+        log::info!("Removing orphaned volume helper container {}", helper);
+        remove_helper(&docker, &helper).await;
+    }
+}
+
+/// Check a whole tar archive at once, see [`TarChecker`].
+#[cfg(test)]
+fn check_archive(archive: &[u8]) -> Result<(), String> {
+    let mut checker = TarChecker::default();
+    checker.feed(archive)?;
+    checker.finish()
+}
+
+const BLOCK: usize = 512;
+/// Long names and PAX headers are held in memory to check the path they carry.
+const MAX_EXTENDED_HEADER: usize = 64 * 1024;
+
+/// Checks that every entry of a tar archive lies below `volume/` while it streams past. Docker
+/// extracts the archive into the helper's staging directory, anything else would not end up in
+/// the volume.
+#[derive(Default)]
+struct TarChecker {
+    /// The header or extended header data read so far
+    pending: Vec<u8>,
+    state: TarState,
+    entries: usize,
+}
+
+#[derive(Default)]
+enum TarState {
+    #[default]
+    Header,
+    /// Data of a regular entry and its padding, not inspected
+    Skip(usize),
+    /// GNU long name (`L`) or PAX extended header (`x`) of `size` bytes, followed by `padding`
+    Extended { typeflag: u8, size: usize, padding: usize },
+    /// After the first zero block
+    End,
+}
+
+impl TarChecker {
+    fn feed(&mut self, mut data: &[u8]) -> Result<(), String> {
+        while !data.is_empty() {
+            match self.state {
+                TarState::Header => {
+                    let take = (BLOCK - self.pending.len()).min(data.len());
+                    self.pending.extend_from_slice(&data[..take]);
+                    data = &data[take..];
+                    if self.pending.len() == BLOCK {
+                        let header = std::mem::take(&mut self.pending);
+                        self.header(&header)?;
+                    }
+                }
+                TarState::Skip(remaining) => {
+                    let take = remaining.min(data.len());
+                    data = &data[take..];
+                    self.skip(remaining - take);
+                }
+                TarState::Extended { typeflag, size, padding } => {
+                    let take = (size - self.pending.len()).min(data.len());
+                    self.pending.extend_from_slice(&data[..take]);
+                    data = &data[take..];
+                    if self.pending.len() == size {
+                        let extended = std::mem::take(&mut self.pending);
+                        match typeflag {
+                            // Its path record replaces the name of the next entry
+                            b'x' => {
+                                if let Some(path) = pax_path(&extended) {
+                                    check_entry_path(&path)?;
+                                }
+                            }
+                            _ => check_entry_path(&c_string(&extended))?,
+                        }
+                        self.skip(padding);
+                    }
+                }
+                // Whatever follows the end marker is ignored, like tar does
+                TarState::End => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Called once the whole archive went through `feed`.
+    fn finish(&self) -> Result<(), String> {
+        match self.state {
+            TarState::Header if self.pending.is_empty() => {}
+            TarState::End => {}
+            _ => return Err("archive is truncated".to_string()),
+        }
+        if self.entries == 0 {
+            return Err("the archive has no entries".to_string());
+        }
+        Ok(())
+    }
+
+    fn header(&mut self, header: &[u8]) -> Result<(), String> {
+        // Two zero blocks end the archive
+        if header.iter().all(|b| *b == 0) {
+            self.state = TarState::End;
+            return Ok(());
+        }
+        let size = entry_size(&header[124..136]).ok_or("invalid entry size")?;
+        let padded = size.div_ceil(BLOCK).checked_mul(BLOCK).ok_or("invalid entry size")?;
+        match header[156] {
+            typeflag @ (b'x' | b'L') if size > 0 => {
+                if size > MAX_EXTENDED_HEADER {
+                    return Err(format!("extended header of {} bytes is too large", size));
+                }
+                self.state = TarState::Extended { typeflag, size, padding: padded - size };
+            }
+            b'x' | b'L' | b'g' => self.skip(padded),
+            _ => {
+                let name = c_string(&header[0..100]);
+                let path = if &header[257..262] == b"ustar" && header[345] != 0 {
+                    format!("{}/{}", c_string(&header[345..500]), name)
+                } else {
+                    name
+                };
+                check_entry_path(&path)?;
+                self.entries += 1;
+                self.skip(padded);
+            }
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, bytes: usize) {
+        self.state = if bytes == 0 { TarState::Header } else { TarState::Skip(bytes) };
+    }
+}
+
+fn check_entry_path(path: &str) -> Result<(), String> {
+    let relative = path.trim_start_matches("./");
+    let inside = relative == "volume" || relative.starts_with("volume/");
+    if !inside || relative.split('/').any(|part| part == "..") {
+        return Err(format!("entry {} is not below volume/", path));
+    }
+    Ok(())
+}
+
+/// Size field of a tar header, octal or base-256 for large entries.
+fn entry_size(field: &[u8]) -> Option<usize> {
+    if field[0] & 0x80 != 0 {
+        return field[1..].iter().try_fold(0usize, |size, b| size.checked_mul(256)?.checked_add(*b as usize));
+    }
+    let text = c_string(field);
+    let text = text.trim();
+    if text.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(text, 8).ok()
+}
+
+fn c_string(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// The `path` record of PAX extended header data, records look like `27 path=volume/data/x\n`.
+fn pax_path(data: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(data);
+    text.lines()
+        .filter_map(|record| record.split_once(' ').map(|(_, record)| record))
+        .find_map(|record| record.strip_prefix("path=").map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One ustar entry: header plus data padded to whole blocks.
+    fn entry(name: &str, typeflag: u8, data: &[u8]) -> Vec<u8> {
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        let mut entry = header.to_vec();
+        entry.extend_from_slice(data);
+        entry.resize(512 + data.len().div_ceil(512) * 512, 0);
+        entry
+    }
+
+    fn archive(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut archive = entries.concat();
+        archive.extend_from_slice(&[0u8; 1024]);
+        archive
+    }
+
+    #[test]
+    fn accepts_entries_below_volume() {
+        let archive = archive(&[
+            entry("volume/", b'5', b""),
+            entry("./volume/data/file.txt", b'0', b"hello"),
+            entry("volume/data/big.bin", b'0', &[1u8; 1500]),
+        ]);
+        assert_eq!(check_archive(&archive), Ok(()));
+    }
+
+    #[test]
+    fn rejects_paths_outside_volume() {
+        for name in ["etc/passwd", "/volume/file", "volume/../etc/passwd", "volumes/file", ".."] {
+            let archive = archive(&[entry("volume/ok", b'0', b""), entry(name, b'0', b"x")]);
+            assert!(check_archive(&archive).is_err(), "{} should be rejected", name);
+        }
+    }
+
+    #[test]
+    fn checks_ustar_prefix() {
+        let mut outside = entry("file", b'0', b"");
+        outside[345..350].copy_from_slice(b"other");
+        assert!(check_archive(&archive(&[outside])).is_err());
+
+        let mut inside = entry("file", b'0', b"");
+        inside[345..356].copy_from_slice(b"volume/data");
+        assert_eq!(check_archive(&archive(&[inside])), Ok(()));
+    }
+
+    #[test]
+    fn checks_long_names() {
+        let gnu = archive(&[entry("././@LongLink", b'L', b"../escape\0"), entry("volume/short", b'0', b"")]);
+        assert!(check_archive(&gnu).is_err());
+
+        let pax = archive(&[entry("PaxHeader", b'x', b"22 path=volume/../etc\n"), entry("volume/x", b'0', b"")]);
+        assert!(check_archive(&pax).is_err());
+
+        let pax = archive(&[entry("PaxHeader", b'x', b"20 path=volume/a/long\n"), entry("volume/x", b'0', b"")]);
+        assert_eq!(check_archive(&pax), Ok(()));
+    }
+
+    #[test]
+    fn rejects_empty_and_truncated_archives() {
+        assert!(check_archive(&archive(&[])).is_err());
+        assert!(check_archive(b"").is_err());
+
+        let mut truncated = entry("volume/file", b'0', &[0u8; 2048]);
+        truncated.truncate(1024);
+        assert!(check_archive(&truncated).is_err());
+    }
+
+    #[test]
+    fn checks_archives_split_into_chunks() {
+        let pax = entry("PaxHeader", b'x', b"20 path=volume/a/long\n");
+        let valid = archive(&[pax, entry("volume/data/big.bin", b'0', &[1u8; 1500]), entry("volume/x", b'0', b"")]);
+        for chunk_size in [1, 7, 511, 512, 513, 4096] {
+            let mut checker = TarChecker::default();
+            for chunk in valid.chunks(chunk_size) {
+                checker.feed(chunk).unwrap();
+            }
+            assert_eq!(checker.finish(), Ok(()), "chunks of {}", chunk_size);
+        }
+
+        let invalid = archive(&[entry("volume/ok", b'0', &[0u8; 700]), entry("etc/passwd", b'0', b"x")]);
+        let mut checker = TarChecker::default();
+        let rejected = invalid.chunks(100).map(|chunk| checker.feed(chunk)).find(Result::is_err);
+        assert_eq!(rejected, Some(Err("entry etc/passwd is not below volume/".to_string())));
+    }
+
+    #[test]
+    fn ignores_data_after_the_end() {
+        let mut data = archive(&[entry("volume/file", b'0', b"x")]);
+        data.extend_from_slice(b"../../garbage");
+        assert_eq!(check_archive(&data), Ok(()));
+
+        // A partial header is as truncated as partial data
+        let mut partial = entry("volume/file", b'0', b"x");
+        partial.extend_from_slice(&[1u8; 100]);
+        assert_eq!(check_archive(&partial), Err("archive is truncated".to_string()));
+    }
+
+    #[test]
+    fn rejects_huge_extended_headers() {
+        let mut header = entry("PaxHeader", b'x', b"");
+        header[124..136].copy_from_slice(format!("{:011o}\0", MAX_EXTENDED_HEADER + 1).as_bytes());
+        assert!(check_archive(&header).unwrap_err().contains("too large"));
+    }
+
+    #[tokio::test]
+    async fn upload_stops_at_the_first_invalid_entry() {
+        config::init_for_tests();
+        let body = archive(&[entry("volume/ok", b'0', b"x"), entry("etc/passwd", b'0', b"x")]);
+        let upload = Upload::new(Full::new(Bytes::from(body)));
+        let failure = upload.failure.clone();
+        let chunks: Vec<_> = upload.into_stream().collect().await;
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].is_err());
+        let error = failure.lock().unwrap().take().unwrap();
+        assert_eq!(error.code, ErrorCode::InvalidBody);
+        assert_eq!(error.message, "invalid archive: entry etc/passwd is not below volume/");
+
+        let body = Bytes::from(archive(&[entry("volume/ok", b'0', b"x")]));
+        let upload = Upload::new(Full::new(body.clone()));
+        let received = upload.received.clone();
+        let chunks: Vec<_> = upload.into_stream().try_collect().await.unwrap();
+        assert_eq!(chunks, vec![body.clone()]);
+        assert_eq!(received.load(Ordering::Relaxed), body.len());
+    }
+
+    #[test]
+    fn entry_sizes() {
+        assert_eq!(entry_size(b"00000000017\0"), Some(15));
+        assert_eq!(entry_size(b"           \0"), Some(0));
+        let mut base256 = [0u8; 12];
+        base256[0] = 0x80;
+        base256[11] = 2;
+        base256[10] = 1;
+        assert_eq!(entry_size(&base256), Some(258));
+        assert_eq!(entry_size(b"0000000009x\0"), None);
+    }
+}
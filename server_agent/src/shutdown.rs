@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use tokio::sync::watch;
+
+tokio::task_local! {
+    static CURRENT_OPERATION: u64;
+}
+
+/// Keeps track of in-flight operations (requests) and the child processes they started,
+/// so a shutdown can wait for them and report the ones it had to abort.
+struct Tracker {
+    next_id: AtomicU64,
+    operations: Mutex<HashMap<u64, Operation>>,
+    changed: watch::Sender<usize>,
+    /// Set once the agent stops accepting connections, long-lived streams end then
+    stopping: watch::Sender<bool>,
+    abort: watch::Sender<bool>,
+}
+
+struct Operation {
+    name: String,
+    started: Instant,
+    commands: Vec<String>,
+}
+
+fn tracker() -> &'static Tracker {
+    static TRACKER: OnceLock<Tracker> = OnceLock::new();
+    TRACKER.get_or_init(|| Tracker {
+        next_id: AtomicU64::new(1),
+        operations: Mutex::new(HashMap::new()),
+        changed: watch::channel(0).0,
+        stopping: watch::channel(false).0,
+        abort: watch::channel(false).0,
+    })
+}
+
+struct OperationGuard(u64);
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        let tracker = tracker();
+        let mut operations = tracker.operations.lock().unwrap();
+        operations.remove(&self.0);
+        tracker.changed.send_replace(operations.len());
+    }
+}
+
+/// Run `future` as a tracked operation named `name`.
+pub async fn track<F: Future>(name: String, future: F) -> F::Output {
+    let tracker = tracker();
+    let id = tracker.next_id.fetch_add(1, Ordering::Relaxed);
+    {
+        let mut operations = tracker.operations.lock().unwrap();
+        operations.insert(
+            id,
+            Operation {
+                name,
+                started: Instant::now(),
+                commands: Vec::new(),
+            },
+        );
+        tracker.changed.send_replace(operations.len());
+    }
+    let _guard = OperationGuard(id);
+    CURRENT_OPERATION.scope(id, future).await
+}
+
+pub struct CommandGuard(Option<(u64, String)>);
+
+impl Drop for CommandGuard {
+    fn drop(&mut self) {
+        if let Some((id, command)) = self.0.take() {
+            if let Some(operation) = tracker().operations.lock().unwrap().get_mut(&id) {
+                if let Some(pos) = operation.commands.iter().position(|c| *c == command) {
+                    operation.commands.remove(pos);
+                }
+            }
+        }
+    }
+}
+
+/// Record a child process under the current operation for the shutdown report.
+pub fn register_command(command: &str) -> CommandGuard {
+    let id = match CURRENT_OPERATION.try_with(|id| *id) {
+        Ok(id) => id,
+        Err(_) => return CommandGuard(None),
+    };
+    if let Some(operation) = tracker().operations.lock().unwrap().get_mut(&id) {
+        operation.commands.push(command.to_string());
+    }
+    CommandGuard(Some((id, command.to_string())))
+}
+
+/// Start the graceful shutdown: streams that would otherwise run until the client leaves end now,
+/// so the drain only waits for real work.
+pub fn begin() {
+    tracker().stopping.send_replace(true);
+}
+
+/// Resolves once the graceful shutdown started.
+pub async fn started() {
+    let mut stopping = tracker().stopping.subscribe();
+    let _ = stopping.wait_for(|stopping| *stopping).await;
+}
+
+/// Resolves once the shutdown drain timed out and running work has to be aborted.
+pub async fn aborted() {
+    let mut abort = tracker().abort.subscribe();
+    // The sender lives in a static, so wait_for only fails if that is dropped
+    let _ = abort.wait_for(|aborted| *aborted).await;
+}
+
+/// Resolves once no tracked operation is running.
+pub async fn idle() {
+    let mut changed = tracker().changed.subscribe();
+    let _ = changed.wait_for(|active| *active == 0).await;
+}
+
+/// Log every operation still running, then tell them to abort (which kills their child processes).
+pub fn abort_all() {
+    let tracker = tracker();
+    for operation in tracker.operations.lock().unwrap().values() {
+        if operation.commands.is_empty() {
+            log::warn!(
+                "Aborting {} after {}s",
+                operation.name,
+                operation.started.elapsed().as_secs()
+            );
+        } else {
+            log::warn!(
+                "Aborting {} after {}s, killing: {}",
+                operation.name,
+                operation.started.elapsed().as_secs(),
+                operation.commands.join(", ")
+            );
+        }
+    }
+    tracker.abort.send_replace(true);
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::auth::unix_now;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const KEY_NAME_HEADER: &str = "X-Key-Name";
+pub const TIMESTAMP_HEADER: &str = "X-Timestamp";
+pub const NONCE_HEADER: &str = "X-Nonce";
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// A fresh HMAC secret: 32 random bytes, hex encoded. It is independent of the API key, so a
+/// captured `X-Api-Key` header is not enough to sign requests.
+pub fn generate_signing_key() -> Result<String, String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(|e| format!("cannot generate signing key: {}", e))?;
+    Ok(hex::encode(bytes))
+}
+
+/// The string that gets signed, one field per line:
+/// method, path, query (empty if none), hex SHA-256 of the body, timestamp, nonce.
+pub fn canonical_request(method: &str, path: &str, query: &str, body: &[u8], timestamp: u64, nonce: &str) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        query,
+        hex::encode(Sha256::digest(body)),
+        timestamp,
+        nonce
+    )
+}
+
+/// Constant time check of a hex signature against the given hex signing key.
+pub fn verify(signing_key: &str, canonical: &str, signature: &str) -> bool {
+    let (Ok(secret), Ok(signature)) = (hex::decode(signing_key), hex::decode(signature)) else {
+        return false;
+    };
+    let mut mac = match HmacSha256::new_from_slice(&secret) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(canonical.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[derive(Debug)]
+pub enum SignatureError {
+    MissingHeader(&'static str),
+    InvalidTimestamp,
+    Stale,
+    InvalidNonce,
+    Invalid,
+    Replayed,
+}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::MissingHeader(name) => write!(f, "missing {} header", name),
+            SignatureError::InvalidTimestamp => write!(f, "invalid timestamp"),
+            SignatureError::Stale => write!(f, "timestamp outside the allowed clock skew"),
+            SignatureError::InvalidNonce => write!(f, "nonce must be 16 to 128 characters"),
+            SignatureError::Invalid => write!(f, "invalid signature"),
+            SignatureError::Replayed => write!(f, "nonce already used"),
+        }
+    }
+}
+
+/// Parse the timestamp header and check it is within `max_skew` seconds of now.
+pub fn check_timestamp(value: &str, max_skew: u64) -> Result<u64, SignatureError> {
+    let timestamp: u64 = value.parse().map_err(|_| SignatureError::InvalidTimestamp)?;
+    if unix_now().abs_diff(timestamp) > max_skew {
+        return Err(SignatureError::Stale);
+    }
+    Ok(timestamp)
+}
+
+pub fn check_nonce(nonce: &str) -> Result<(), SignatureError> {
+    if (16..=128).contains(&nonce.len()) && nonce.chars().all(|c| c.is_ascii_graphic()) {
+        Ok(())
+    } else {
+        Err(SignatureError::InvalidNonce)
+    }
+}
+
+/// Nonces seen per key. An entry only has to outlive the timestamp window, after that the
+/// request is rejected as stale anyway.
+pub struct NonceCache {
+    seen: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl NonceCache {
+    pub fn new() -> NonceCache {
+        NonceCache {
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remember the nonce until `expires_at`. Returns false if it was already used.
+    pub fn insert(&self, key_name: &str, nonce: &str, expires_at: u64) -> bool {
+        let now = unix_now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, expires| *expires > now);
+        let entry = (key_name.to_string(), nonce.to_string());
+        if seen.contains_key(&entry) {
+            return false;
+        }
+        seen.insert(entry, expires_at);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// HMAC-SHA256 test case 2 of RFC 4231, key "Jefe".
+    const RFC_KEY: &str = "4a656665";
+    const RFC_MAC: &str = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+
+    #[test]
+    fn canonical_request_layout() {
+        let canonical = canonical_request("POST", "/docker/container", "pull=always", b"{}", 1700000000, "nonce-0123456789");
+        assert_eq!(
+            canonical,
+            "POST\n/docker/container\npull=always\n\
+             44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a\n1700000000\nnonce-0123456789"
+        );
+
+        // An empty query and body still take their lines
+        let canonical = canonical_request("GET", "/health", "", b"", 1, "n");
+        assert_eq!(
+            canonical,
+            "GET\n/health\n\ne3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\n1\nn"
+        );
+    }
+
+    #[test]
+    fn verify_signature() {
+        let message = "what do ya want for nothing?";
+        assert!(verify(RFC_KEY, message, RFC_MAC));
+        assert!(verify(RFC_KEY, message, &RFC_MAC.to_uppercase()));
+        assert!(!verify(RFC_KEY, "what do ya want for nothing!", RFC_MAC));
+        assert!(!verify("4a656666", message, RFC_MAC));
+        assert!(!verify(RFC_KEY, message, &RFC_MAC[..62]));
+        assert!(!verify(RFC_KEY, message, "not hex"));
+        assert!(!verify("not hex", message, RFC_MAC));
+    }
+
+    #[test]
+    fn generated_signing_keys_are_random_hex() {
+        let first = generate_signing_key().unwrap();
+        let second = generate_signing_key().unwrap();
+        assert_eq!(first.len(), 64);
+        assert!(first.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn timestamps() {
+        let now = unix_now();
+        assert_eq!(check_timestamp(&now.to_string(), 300).unwrap(), now);
+        assert!(check_timestamp(&(now - 100).to_string(), 300).is_ok());
+        assert!(check_timestamp(&(now + 100).to_string(), 300).is_ok());
+        assert!(matches!(check_timestamp(&(now - 1000).to_string(), 300), Err(SignatureError::Stale)));
+        assert!(matches!(check_timestamp(&(now + 1000).to_string(), 300), Err(SignatureError::Stale)));
+        for value in ["", "abc", "-5", "1.5"] {
+            assert!(matches!(check_timestamp(value, 300), Err(SignatureError::InvalidTimestamp)));
+        }
+    }
+
+    #[test]
+    fn nonces() {
+        assert!(check_nonce("0123456789abcdef").is_ok());
+        assert!(check_nonce(&"n".repeat(128)).is_ok());
+        assert!(check_nonce("0123456789abcde").is_err());
+        assert!(check_nonce(&"n".repeat(129)).is_err());
+        assert!(check_nonce("0123456789 abcdef").is_err());
+        assert!(check_nonce("0123456789abcdé!").is_err());
+    }
+
+    #[test]
+    fn nonce_cache_rejects_replays() {
+        let cache = NonceCache::new();
+        let expires_at = unix_now() + 600;
+        assert!(cache.insert("manager", "nonce-0123456789", expires_at));
+        assert!(!cache.insert("manager", "nonce-0123456789", expires_at));
+        // Nonces are per key
+        assert!(cache.insert("dashboard", "nonce-0123456789", expires_at));
+    }
+
+    #[test]
+    fn nonce_cache_forgets_expired_entries() {
+        let cache = NonceCache::new();
+        assert!(cache.insert("manager", "nonce-0123456789", unix_now() - 1));
+        assert!(cache.insert("manager", "nonce-0123456789", unix_now() + 600));
+    }
+}
@@ -0,0 +1,78 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::time::Duration;
+
+use futures_util::stream;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::Response;
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::shutdown;
+
+/// Comment lines keep proxies like Traefik from closing an idle stream.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Sends events to the client of a Server-Sent Events response.
+#[derive(Clone)]
+pub struct EventSender(mpsc::Sender<Bytes>);
+
+impl EventSender {
+    /// Send `data` as JSON under the event name. Returns false once the client is gone.
+    pub async fn send<T: Serialize>(&self, event: &str, data: &T) -> bool {
+        let data = serde_json::to_string(data).unwrap();
+        self.0
+            .send(Bytes::from(format!("event: {}\ndata: {}\n\n", event, data)))
+            .await
+            .is_ok()
+    }
+
+    /// Send without waiting, a client that stopped reading does not hold up the shutdown.
+    fn try_send<T: Serialize>(&self, event: &str, data: &T) {
+        let data = serde_json::to_string(data).unwrap();
+        let _ = self.0.try_send(Bytes::from(format!("event: {}\ndata: {}\n\n", event, data)));
+    }
+
+    async fn comment(&self, text: &str) -> bool {
+        self.0.send(Bytes::from(format!(": {}\n\n", text))).await.is_ok()
+    }
+}
+
+/// A `text/event-stream` response fed by `produce`, which runs in its own task. The stream ends
+/// when `produce` returns or the client disconnects. When the agent shuts down, the client gets
+/// an `end` event with `"reason": "shutdown"` and the stream closes right away.
+pub fn response<F, Fut>(produce: F) -> Response<BoxBody<Bytes, Infallible>>
+where
+    F: FnOnce(EventSender) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel::<Bytes>(64);
+    let sender = EventSender(tx);
+    let producer = produce(sender.clone());
+    tokio::spawn(async move {
+        let keepalive = async {
+            let mut interval = tokio::time::interval(KEEPALIVE);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if !sender.comment("keepalive").await {
+                    break;
+                }
+            }
+        };
+        tokio::select! {
+            _ = producer => {}
+            _ = keepalive => {}
+            _ = shutdown::started() => sender.try_send("end", &serde_json::json!({ "reason": "shutdown" })),
+        }
+    });
+
+    let frames = stream::poll_fn(move |cx| rx.poll_recv(cx).map(|chunk| chunk.map(|c| Ok(Frame::data(c)))));
+    Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(StreamBody::new(frames).boxed())
+        .unwrap()
+}
@@ -0,0 +1,18 @@
+use std::sync::Arc;
+
+use crate::audit::AuditLog;
+use crate::auth::KeyStore;
+use crate::jobs::JobStore;
+use crate::lockout::Lockout;
+use crate::registries::RegistryStore;
+use crate::signature::NonceCache;
+
+/// Shared state handed to every request.
+pub struct AppState {
+    pub keys: Arc<KeyStore>,
+    pub nonces: NonceCache,
+    pub lockout: Lockout,
+    pub audit: Option<Arc<AuditLog>>,
+    pub jobs: Arc<JobStore>,
+    pub registries: Arc<RegistryStore>,
+}
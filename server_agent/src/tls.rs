@@ -0,0 +1,264 @@
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use base64::Engine;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use serde::Deserialize;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+
+/// Where the certificate comes from: plain PEM files or Traefik's ACME store.
+#[derive(Clone)]
+enum CertSource {
+    Files { cert: PathBuf, key: PathBuf },
+    Traefik { acme_file: PathBuf, resolver: String, domain: String },
+}
+
+impl CertSource {
+    fn from_config(config: &TlsConfig) -> CertSource {
+        match (&config.traefik_acme_file, &config.cert_file, &config.key_file) {
+            (Some(acme_file), _, _) => CertSource::Traefik {
+                acme_file: acme_file.clone(),
+                resolver: config.traefik_resolver.clone(),
+                domain: config.traefik_domain.clone().unwrap_or_default(),
+            },
+            (None, Some(cert), Some(key)) => CertSource::Files {
+                cert: cert.clone(),
+                key: key.clone(),
+            },
+            _ => unreachable!("tls config is validated at startup"),
+        }
+    }
+
+    fn watched_files(&self) -> Vec<&Path> {
+        match self {
+            CertSource::Files { cert, key } => vec![cert, key],
+            CertSource::Traefik { acme_file, .. } => vec![acme_file],
+        }
+    }
+
+    fn load(&self, provider: &CryptoProvider) -> Result<CertifiedKey, String> {
+        let (cert_pem, key_pem) = match self {
+            CertSource::Files { cert, key } => (
+                fs::read(cert).map_err(|e| format!("cannot read {}: {}", cert.display(), e))?,
+                fs::read(key).map_err(|e| format!("cannot read {}: {}", key.display(), e))?,
+            ),
+            CertSource::Traefik { acme_file, resolver, domain } => traefik_certificate(acme_file, resolver, domain)?,
+        };
+
+        let certs = rustls_pemfile::certs(&mut BufReader::new(cert_pem.as_slice()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid certificate PEM: {}", e))?;
+        if certs.is_empty() {
+            return Err("no certificate found in PEM".to_string());
+        }
+        let key = rustls_pemfile::private_key(&mut BufReader::new(key_pem.as_slice()))
+            .map_err(|e| format!("invalid key PEM: {}", e))?
+            .ok_or_else(|| "no private key found in PEM".to_string())?;
+
+        CertifiedKey::from_der(certs, key, provider).map_err(|e| format!("certificate and key do not match: {}", e))
+    }
+}
+
+#[derive(Deserialize)]
+struct AcmeResolver {
+    #[serde(rename = "Certificates", default)]
+    certificates: Option<Vec<AcmeCertificate>>,
+}
+
+#[derive(Deserialize)]
+struct AcmeCertificate {
+    domain: AcmeDomain,
+    certificate: String,
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct AcmeDomain {
+    main: String,
+    #[serde(default)]
+    sans: Option<Vec<String>>,
+}
+
+impl AcmeDomain {
+    fn names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.main.as_str()).chain(self.sans.iter().flatten().map(String::as_str))
+    }
+}
+
+/// Read the base64 encoded PEM certificate and key for `domain` from Traefik's acme.json.
+fn traefik_certificate(acme_file: &Path, resolver: &str, domain: &str) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let content = fs::read(acme_file).map_err(|e| format!("cannot read {}: {}", acme_file.display(), e))?;
+    let mut resolvers: std::collections::HashMap<String, AcmeResolver> =
+        serde_json::from_slice(&content).map_err(|e| format!("invalid acme file {}: {}", acme_file.display(), e))?;
+    let mut certificates = resolvers
+        .remove(resolver)
+        .and_then(|r| r.certificates)
+        .ok_or_else(|| format!("resolver {} has no certificates in {}", resolver, acme_file.display()))?;
+    // A certificate naming the domain itself wins over a wildcard one
+    let exact = certificates.iter().position(|c| c.domain.names().any(|name| name.eq_ignore_ascii_case(domain)));
+    let wildcard = || certificates.iter().position(|c| c.domain.names().any(|name| wildcard_matches(name, domain)));
+    let index = exact
+        .or_else(wildcard)
+        .ok_or_else(|| format!("no certificate for {} in {}", domain, acme_file.display()))?;
+    let certificate = certificates.swap_remove(index);
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    let cert = engine
+        .decode(certificate.certificate)
+        .map_err(|e| format!("certificate for {} is not valid base64: {}", domain, e))?;
+    let key = engine
+        .decode(certificate.key)
+        .map_err(|e| format!("key for {} is not valid base64: {}", domain, e))?;
+    Ok((cert, key))
+}
+
+/// Whether a wildcard name like `*.example.com` covers `domain`, which matches one label only.
+fn wildcard_matches(name: &str, domain: &str) -> bool {
+    let Some(suffix) = name.strip_prefix("*.") else {
+        return false;
+    };
+    match domain.split_once('.') {
+        Some((label, rest)) => !label.is_empty() && rest.eq_ignore_ascii_case(suffix),
+        None => false,
+    }
+}
+
+/// Serves whatever certificate was loaded last, so reloads apply to new handshakes immediately.
+#[derive(Debug)]
+struct ReloadingResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn modified_times(source: &CertSource) -> Vec<Option<SystemTime>> {
+    source
+        .watched_files()
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Build the TLS acceptor from the config and start watching the certificate files for changes.
+/// Fails if the initial certificate cannot be loaded.
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let provider = Arc::new(ring::default_provider());
+    let source = CertSource::from_config(config);
+    let certified_key = source.load(&provider)?;
+
+    let resolver = Arc::new(ReloadingResolver {
+        current: RwLock::new(Arc::new(certified_key)),
+    });
+
+    let mut server_config = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("tls setup failed: {}", e))?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    let interval = Duration::from_secs(config.reload_interval_secs);
+    tokio::spawn(async move {
+        let mut last_modified = modified_times(&source);
+        loop {
+            tokio::time::sleep(interval).await;
+            let modified = modified_times(&source);
+            if modified == last_modified {
+                continue;
+            }
+            match source.load(&provider) {
+                Ok(certified_key) => {
+                    *resolver.current.write().unwrap() = Arc::new(certified_key);
+                    last_modified = modified;
+                    log::info!("TLS certificate reloaded");
+                }
+                // Keep serving the old certificate; retry on the next tick in case we read a partial write
+                Err(e) => log::warn!("TLS certificate reload failed: {}", e),
+            }
+        }
+    });
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(text: &str) -> String {
+        base64::engine::general_purpose::STANDARD.encode(text)
+    }
+
+    fn acme_file(name: &str) -> PathBuf {
+        let certificate = |main: &str, sans: &[&str], label: &str| {
+            serde_json::json!({
+                "domain": { "main": main, "sans": sans },
+                "certificate": encode(&format!("{} cert", label)),
+                "key": encode(&format!("{} key", label)),
+                "Store": "default",
+            })
+        };
+        let acme = serde_json::json!({
+            "letsencrypt": {
+                "Account": { "Email": "ops@example.com" },
+                "Certificates": [
+                    certificate("*.example.com", &[], "wildcard"),
+                    certificate("example.com", &["www.example.com", "API.example.com"], "main"),
+                    { "domain": { "main": "broken.example.org" }, "certificate": "not base64!", "key": encode("k") },
+                ],
+            },
+            "staging": { "Account": {}, "Certificates": null },
+        });
+        let path = std::env::temp_dir().join(format!("server_agent-acme-{}-{}.json", std::process::id(), name));
+        fs::write(&path, acme.to_string()).unwrap();
+        path
+    }
+
+    fn load(name: &str, resolver: &str, domain: &str) -> Result<(String, String), String> {
+        let path = acme_file(name);
+        let result = traefik_certificate(&path, resolver, domain);
+        fs::remove_file(&path).unwrap();
+        result.map(|(cert, key)| (String::from_utf8(cert).unwrap(), String::from_utf8(key).unwrap()))
+    }
+
+    #[test]
+    fn finds_main_domain_and_sans() {
+        let main = Ok(("main cert".to_string(), "main key".to_string()));
+        assert_eq!(load("main", "letsencrypt", "example.com"), main);
+        assert_eq!(load("san", "letsencrypt", "www.example.com"), main);
+        // Exact names win over the wildcard, case does not matter
+        assert_eq!(load("case", "letsencrypt", "api.example.com"), main);
+    }
+
+    #[test]
+    fn wildcards_cover_one_label() {
+        let wildcard = Ok(("wildcard cert".to_string(), "wildcard key".to_string()));
+        assert_eq!(load("wildcard", "letsencrypt", "agent.example.com"), wildcard);
+        assert!(load("deep", "letsencrypt", "a.agent.example.com").unwrap_err().contains("no certificate"));
+        assert!(load("other", "letsencrypt", "agent.example.org").unwrap_err().contains("no certificate"));
+
+        assert!(wildcard_matches("*.example.com", "Agent.Example.com"));
+        assert!(!wildcard_matches("*.example.com", "example.com"));
+        assert!(!wildcard_matches("*.example.com", ".example.com"));
+        assert!(!wildcard_matches("agent.example.com", "agent.example.com"));
+    }
+
+    #[test]
+    fn reports_missing_resolvers_and_bad_base64() {
+        assert!(load("unknown", "other", "example.com").unwrap_err().contains("resolver other has no certificates"));
+        assert!(load("empty", "staging", "example.com").unwrap_err().contains("resolver staging has no certificates"));
+        assert!(load("base64", "letsencrypt", "broken.example.org").unwrap_err().contains("not valid base64"));
+    }
+}
@@ -1,22 +1,335 @@
-use std::process::Command;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
 
-pub fn command_output(command_str: &str, args: Option<Vec<&str>>, current_dir: Option<&str>) -> String {
+use bollard::{Docker, API_DEFAULT_VERSION};
+use hyper::HeaderMap;
+use ipnet::IpNet;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+
+use crate::config;
+use crate::jobs;
+use crate::shutdown;
+
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+pub fn init_logger(level: log::LevelFilter) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Connect to the Docker endpoint from the config (or `DOCKER_HOST` / the local socket if unset).
+pub fn docker() -> Result<Docker, bollard::errors::Error> {
+    let docker_config = &config::get().docker;
+    match &docker_config.host {
+        Some(host) if host.starts_with("unix://") => {
+            Docker::connect_with_unix(host, docker_config.timeout_secs, API_DEFAULT_VERSION)
+        }
+        Some(host) => Docker::connect_with_http(host, docker_config.timeout_secs, API_DEFAULT_VERSION),
+        None => Docker::connect_with_defaults().map(|d| d.with_timeout(Duration::from_secs(docker_config.timeout_secs))),
+    }
+}
+
+fn build_command(
+    command_str: &str,
+    args: Option<Vec<&str>>,
+    current_dir: Option<&str>,
+    env: &[(&str, &Path)],
+) -> Command {
     let mut command = Command::new(command_str);
-    if args.is_some() {
-        for arg in args.unwrap() {
-            command.arg(arg);
+    if let Some(args) = args {
+        command.args(args);
+    }
+    if let Some(current_dir) = current_dir {
+        command.current_dir(current_dir);
+    }
+    // `docker compose` should talk to the same daemon as bollard
+    if let Some(host) = &config::get().docker.host {
+        command.env("DOCKER_HOST", host);
+    }
+    command.envs(env.iter().copied());
+    // Own process group, so children of the command (e.g. the compose plugin) can be killed too
+    command.process_group(0);
+    command.kill_on_drop(true);
+    command
+}
+
+fn kill_process_group(pid: Option<u32>) {
+    if let Some(pid) = pid {
+        // SAFETY: plain kill(2) call; the negative pid addresses the group created in build_command
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+    }
+}
+
+fn aborted(command_str: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Interrupted,
+        format!("{} was aborted by agent shutdown", command_str),
+    )
+}
+
+/// Only the program and directory are recorded; arguments may carry secrets like runner tokens.
+fn command_label(command_str: &str, current_dir: Option<&str>) -> String {
+    match current_dir {
+        Some(dir) => format!("{} (in {})", command_str, dir),
+        None => command_str.to_string(),
+    }
+}
+
+fn timed_out(command_str: &str, timeout: Duration) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("{} did not finish within {}s", command_str, timeout.as_secs()),
+    )
+}
+
+/// Kills the process group of a command that did not finish: on timeout, on shutdown abort and
+/// when a cancelled job drops the running future.
+struct ProcessGroup(Option<u32>);
+
+impl ProcessGroup {
+    fn finished(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        kill_process_group(self.0.take());
+    }
+}
+
+/// Read a pipe line by line, copying every line into the output of the current job.
+async fn read_lines<R: AsyncRead + Unpin>(pipe: Option<R>) -> String {
+    let mut collected = String::new();
+    let Some(pipe) = pipe else { return collected };
+    let mut reader = BufReader::new(pipe);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buf);
+                let line = line.trim_end_matches(['\n', '\r']);
+                jobs::output(line);
+                collected.push_str(line);
+                collected.push('\n');
+            }
+        }
+    }
+    collected
+}
+
+async fn run_command(
+    command_str: &str,
+    args: Option<Vec<&str>>,
+    current_dir: Option<&str>,
+    env: &[(&str, &Path)],
+    timeout: Duration,
+) -> io::Result<(ExitStatus, String)> {
+    let _command_guard = shutdown::register_command(&command_label(command_str, current_dir));
+    let mut command = build_command(command_str, args, current_dir, env);
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = command.spawn()?;
+    let group = ProcessGroup(child.id());
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let run = async {
+        let (stdout, stderr, status) = tokio::join!(read_lines(stdout), read_lines(stderr), child.wait());
+        status.map(|status| (status, stdout, stderr))
+    };
+    let (status, stdout_str, stderr_str) = tokio::select! {
+        output = tokio::time::timeout(timeout, run) => match output {
+            Ok(output) => output?,
+            Err(_) => return Err(timed_out(command_str, timeout)),
+        },
+        _ = shutdown::aborted() => return Err(aborted(command_str)),
+    };
+    group.finished();
+
+    log::debug!("{}", command_str);
+    log::debug!("{}", stdout_str);
+    log::debug!("{}", stderr_str);
+
+    Ok((status, stdout_str))
+}
+
+pub async fn command_output(
+    command_str: &str,
+    args: Option<Vec<&str>>,
+    current_dir: Option<&str>,
+    timeout: Duration,
+) -> io::Result<String> {
+    run_command(command_str, args, current_dir, &[], timeout).await.map(|(_, stdout)| stdout)
+}
+
+/// Like `command_output`, with extra environment variables that point to files, e.g. `DOCKER_CONFIG`.
+pub async fn command_output_env(
+    command_str: &str,
+    args: Option<Vec<&str>>,
+    current_dir: Option<&str>,
+    env: &[(&str, &Path)],
+    timeout: Duration,
+) -> io::Result<String> {
+    run_command(command_str, args, current_dir, env, timeout).await.map(|(_, stdout)| stdout)
+}
+
+pub async fn command_status(
+    command_str: &str,
+    args: Option<Vec<&str>>,
+    current_dir: Option<&str>,
+    timeout: Duration,
+) -> io::Result<ExitStatus> {
+    run_command(command_str, args, current_dir, &[], timeout).await.map(|(status, _)| status)
+}
+
+/// Write a file atomically (temp file + rename) with owner-only permissions.
+pub fn write_private(path: &Path, content: &[u8]) -> Result<(), String> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .map_err(|e| format!("cannot write {}: {}", tmp_path.display(), e))?;
+    file.write_all(content)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("cannot write {}: {}", tmp_path.display(), e))?;
+    fs::rename(&tmp_path, path).map_err(|e| format!("cannot replace {}: {}", path.display(), e))
+}
+
+/// Check that a deployment path is absolute, free of `..` and inside one of the allowed base dirs.
+pub fn check_deployment_path(path: &str) -> Result<PathBuf, String> {
+    check_path_in(path, &config::get().allowed_base_dirs)
+}
+
+fn check_path_in(path: &str, allowed: &[PathBuf]) -> Result<PathBuf, String> {
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        return Err(format!("path {} is not absolute", path.display()));
+    }
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(format!("path {} must not contain ..", path.display()));
+    }
+    if !allowed.iter().any(|base| path.starts_with(base)) {
+        return Err(format!("path {} is outside the allowed base directories", path.display()));
+    }
+    Ok(path.to_path_buf())
+}
+
+/// The address of the client. Behind a trusted proxy this is the last X-Forwarded-For entry
+/// that is not itself a trusted proxy.
+pub fn client_ip(remote: IpAddr, headers: &HeaderMap) -> IpAddr {
+    forwarded_client_ip(remote, headers, &config::get().auth.trusted_proxies)
+}
+
+fn forwarded_client_ip(remote: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    if !trusted(&remote) {
+        return remote;
+    }
+    headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|v| v.trim().parse::<IpAddr>().ok())
+        .rev()
+        .find(|ip| !trusted(ip))
+        .unwrap_or(remote)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarded(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("X-Forwarded-For", value.parse().unwrap());
         }
+        headers
     }
-    if current_dir.is_some() {
-        command.current_dir(current_dir.unwrap());
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn deployment_paths() {
+        let allowed = [PathBuf::from("/home/node_agent"), PathBuf::from("/srv/apps")];
+        assert_eq!(check_path_in("/home/node_agent/web", &allowed), Ok(PathBuf::from("/home/node_agent/web")));
+        assert!(check_path_in("/srv/apps/team/api/", &allowed).is_ok());
+        assert!(check_path_in("/home/node_agent", &allowed).is_ok());
+
+        for path in [
+            "home/node_agent/web",
+            "",
+            "/home/node_agent/../etc",
+            "/home/node_agent/web/../../root",
+            "/etc/systemd",
+            // A shared prefix is not enough, the base has to be a whole path component
+            "/home/node_agent2/web",
+            "/srv/appsx",
+        ] {
+            assert!(check_path_in(path, &allowed).is_err(), "{} should be rejected", path);
+        }
+        assert!(check_path_in("/home/node_agent/web", &[]).is_err());
     }
-    let output = command.output().expect("Failed to execute command");
-    let stdout_str = String::from_utf8_lossy(&output.stdout).to_string();
-    let stderr_str = String::from_utf8_lossy(&output.stderr).to_string();
 
-    println!("{}", command_str);
-    println!("{}", stdout_str);
-    println!("{}", stderr_str);
-    
-    stdout_str
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let trusted: Vec<IpNet> = vec!["172.18.0.2/32".parse().unwrap()];
+        let headers = forwarded(&["198.51.100.7"]);
+        assert_eq!(forwarded_client_ip(ip("203.0.113.9"), &headers, &trusted), ip("203.0.113.9"));
+        // Without trusted proxies the header is never used
+        assert_eq!(forwarded_client_ip(ip("172.18.0.2"), &headers, &[]), ip("172.18.0.2"));
+    }
+
+    #[test]
+    fn trusted_proxy_forwards_the_client() {
+        let trusted: Vec<IpNet> = vec!["172.18.0.2/32".parse().unwrap(), "10.0.0.0/8".parse().unwrap()];
+        let proxy = ip("172.18.0.2");
+        assert_eq!(forwarded_client_ip(proxy, &forwarded(&["198.51.100.7"]), &trusted), ip("198.51.100.7"));
+        // The last entry that is not a trusted proxy wins, earlier ones are up to the client
+        let headers = forwarded(&["192.0.2.1, 198.51.100.7", "10.1.2.3"]);
+        assert_eq!(forwarded_client_ip(proxy, &headers, &trusted), ip("198.51.100.7"));
+        let headers = forwarded(&[" 2001:db8::1 , 10.1.2.3"]);
+        assert_eq!(forwarded_client_ip(proxy, &headers, &trusted), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn trusted_proxy_without_usable_header() {
+        let trusted: Vec<IpNet> = vec!["172.18.0.2/32".parse().unwrap()];
+        let proxy = ip("172.18.0.2");
+        assert_eq!(forwarded_client_ip(proxy, &HeaderMap::new(), &trusted), proxy);
+        assert_eq!(forwarded_client_ip(proxy, &forwarded(&["unknown, not-an-ip"]), &trusted), proxy);
+        assert_eq!(forwarded_client_ip(proxy, &forwarded(&["172.18.0.2"]), &trusted), proxy);
+    }
 }